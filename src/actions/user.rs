@@ -8,7 +8,7 @@ const MIN_NAME_LENGTH: usize = 3;
 const MAX_NAME_LENGTH: usize = 32;
 const INITIAL_CONSCIOUSNESS_LEVEL: u32 = 1;
 
-pub async fn create_anima(owner: Principal, name: &str) -> Result<AnimaState> {
+pub async fn create_anima(id: String, owner: Principal, name: &str) -> Result<AnimaState> {
     validate_name(name)?;
     
     let timestamp = time();
@@ -16,7 +16,7 @@ pub async fn create_anima(owner: Principal, name: &str) -> Result<AnimaState> {
     quantum_state.update_quantum_metrics(1.0); // Initial strong interaction
 
     Ok(AnimaState {
-        id: format!("anima_{}", timestamp),
+        id,
         owner,
         name: name.to_string(),
         quantum_state,
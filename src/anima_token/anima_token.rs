@@ -4,52 +4,219 @@ use ic_cdk::api::{time, trap};
 use ic_cdk::export::candid;
 use ic_cdk::export::Principal;
 use ic_cdk_macros::*;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+
+mod icrc3;
+mod state;
+mod vesting;
+
+use icrc3::{ArchiveInfo, DataCertificate, GetArchivesArgs, GetBlocksArgs, GetBlocksResult, Hash, SupportedBlockType, Value};
+use state::State;
+use vesting::{Bucket, VestingBucket, VestingSchedule, VestingStatus};
 
 // Constants
-const TOKEN_NAME: &str = "ANIMA Token";
-const TOKEN_SYMBOL: &str = "ANIMA";
-const DECIMALS: u8 = 8;
-const TRANSFER_FEE: u128 = 10_000;
-const GENESIS_AMOUNT: u128 = 1_000_000_000 * 10u128.pow(DECIMALS as u32);
+const MAX_SUPPLY_TOKENS: u128 = 1_000_000_000;  // Whole tokens, scaled by the decimals
+const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours
+const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;  // 2 minutes
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State::default());
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static STATE: RefCell<State> = RefCell::new(State::init(memory));
 }
 
-#[derive(candid::CandidType, Clone, Default)]
-struct State {
-    balances: HashMap<Principal, u128>,
-    allowances: HashMap<(Principal, Principal), Allowance>,
-    total_supply: u128,
-    minting_account: Option<Account>,
-    transactions: Vec<Transaction>,
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
 }
 
-#[derive(candid::CandidType, Clone)]
+type Subaccount = [u8; 32];
+type TxHash = [u8; 32];
+
+#[derive(candid::CandidType, candid::Deserialize, Clone)]
+struct RecentTransaction {
+    block_index: u128,
+    created_at_time: u64,
+}
+
+#[derive(candid::CandidType, candid::Deserialize, Clone)]
 struct Account {
     owner: Principal,
     subaccount: Option<Vec<u8>>,
 }
 
-#[derive(candid::CandidType, Clone)]
+/// An account with its subaccount normalized to 32 bytes, so that `None`
+/// and the all-zero default subaccount name the same balance.
+#[derive(candid::CandidType, candid::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct AccountKey {
+    owner: Principal,
+    subaccount: Subaccount,
+}
+
+impl Account {
+    fn key(&self) -> Result<AccountKey, String> {
+        let subaccount = match &self.subaccount {
+            None => DEFAULT_SUBACCOUNT,
+            Some(bytes) => bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| format!("Subaccount must be 32 bytes, got {}", bytes.len()))?,
+        };
+        Ok(AccountKey { owner: self.owner, subaccount })
+    }
+}
+
+impl AccountKey {
+    fn of(owner: Principal) -> Self {
+        Self { owner, subaccount: DEFAULT_SUBACCOUNT }
+    }
+
+    fn from_subaccount(owner: Principal, subaccount: &Option<Vec<u8>>) -> Result<Self, String> {
+        Account { owner, subaccount: subaccount.clone() }.key()
+    }
+
+    fn account(&self) -> Account {
+        Account {
+            owner: self.owner,
+            subaccount: (self.subaccount != DEFAULT_SUBACCOUNT).then(|| self.subaccount.to_vec()),
+        }
+    }
+}
+
+#[derive(candid::CandidType, candid::Deserialize, Clone)]
 struct Allowance {
-    amount: u128,
+    allowance: u128,
     expires_at: Option<u64>,
 }
 
-#[derive(candid::CandidType, Clone)]
+#[derive(Clone)]
+enum TransactionKind {
+    /// `from` is the minting account
+    Mint,
+    /// `to` is the minting account; `spender` is set for burns through an
+    /// allowance
+    Burn { spender: Option<Account> },
+    Transfer,
+    /// `to` is the spender and `amount` the new allowance
+    Approve { expected_allowance: Option<u128>, expires_at: Option<u64> },
+    TransferFrom { spender: Account },
+}
+
+#[derive(Clone)]
 struct Transaction {
+    kind: TransactionKind,
     from: Account,
     to: Account,
     amount: u128,
     fee: u128,
     memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
     timestamp: u64,
 }
 
-#[derive(candid::CandidType)]
+impl Transaction {
+    /// The ICRC-3 block for this transaction, linked to `parent`.
+    fn to_block(&self, parent: Option<Hash>, fee_collector: Option<&Account>) -> Value {
+        let mut tx = vec![entry("amt", Value::nat(self.amount))];
+        let btype = match &self.kind {
+            TransactionKind::Mint => {
+                tx.push(entry("to", account_value(&self.to)));
+                "1mint"
+            }
+            TransactionKind::Burn { spender } => {
+                tx.push(entry("from", account_value(&self.from)));
+                if let Some(spender) = spender {
+                    tx.push(entry("spender", account_value(spender)));
+                }
+                "1burn"
+            }
+            TransactionKind::Transfer => {
+                tx.push(entry("from", account_value(&self.from)));
+                tx.push(entry("to", account_value(&self.to)));
+                "1xfer"
+            }
+            TransactionKind::Approve { expected_allowance, expires_at } => {
+                tx.push(entry("from", account_value(&self.from)));
+                tx.push(entry("spender", account_value(&self.to)));
+                if let Some(expected_allowance) = expected_allowance {
+                    tx.push(entry("expected_allowance", Value::nat(*expected_allowance)));
+                }
+                if let Some(expires_at) = expires_at {
+                    tx.push(entry("expires_at", Value::nat(*expires_at)));
+                }
+                "2approve"
+            }
+            TransactionKind::TransferFrom { spender } => {
+                tx.push(entry("from", account_value(&self.from)));
+                tx.push(entry("to", account_value(&self.to)));
+                tx.push(entry("spender", account_value(spender)));
+                "2xfer"
+            }
+        };
+        if let Some(memo) = &self.memo {
+            tx.push(entry("memo", Value::Blob(memo.clone())));
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            tx.push(entry("ts", Value::nat(created_at_time)));
+        }
+
+        let mut block = vec![
+            entry("btype", Value::text(btype)),
+            entry("ts", Value::nat(self.timestamp)),
+            entry("tx", Value::Map(tx)),
+        ];
+        if self.fee > 0 {
+            block.push(entry("fee", Value::nat(self.fee)));
+        }
+        if let Some(fee_collector) = fee_collector {
+            block.push(entry("fee_col", account_value(fee_collector)));
+        }
+        if let Some(parent) = parent {
+            block.push(entry("phash", Value::Blob(parent.to_vec())));
+        }
+        Value::Map(block)
+    }
+}
+
+fn entry(key: &str, value: Value) -> (String, Value) {
+    (key.to_string(), value)
+}
+
+/// Accounts are encoded as `[owner]` or `[owner, subaccount]`.
+fn account_value(account: &Account) -> Value {
+    let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = &account.subaccount {
+        parts.push(Value::Blob(subaccount.clone()));
+    }
+    Value::Array(parts)
+}
+
+/// Block recording a transfer fee change. This is an ANIMA-specific block
+/// type, not one defined by ICRC-3.
+fn fee_change_block(parent: Option<Hash>, fee: u128, changed_by: Principal, timestamp: u64) -> Value {
+    let tx = vec![
+        entry("fee", Value::nat(fee)),
+        entry("caller", Value::Blob(changed_by.as_slice().to_vec())),
+    ];
+    let mut block = vec![
+        entry("btype", Value::text("anima_fee")),
+        entry("ts", Value::nat(timestamp)),
+        entry("tx", Value::Map(tx)),
+    ];
+    if let Some(parent) = parent {
+        block.push(entry("phash", Value::Blob(parent.to_vec())));
+    }
+    Value::Map(block)
+}
+
+#[derive(candid::CandidType, Debug)]
 enum TransferError {
     BadFee { expected_fee: u128 },
     BadBurn { min_burn_amount: u128 },
@@ -63,160 +230,559 @@ enum TransferError {
 
 type TransferResult = Result<u128, TransferError>;
 
-// Initialize the token
+#[derive(candid::CandidType)]
+enum ApproveError {
+    BadFee { expected_fee: u128 },
+    InsufficientFunds { balance: u128 },
+    AllowanceChanged { current_allowance: u128 },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+type ApproveResult = Result<u128, ApproveError>;
+
+#[derive(candid::CandidType)]
+enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+type TransferFromResult = Result<u128, TransferFromError>;
+
+impl From<TransferError> for ApproveError {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TransferError::InsufficientFunds { balance } => ApproveError::InsufficientFunds { balance },
+            TransferError::TooOld => ApproveError::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            TransferError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            TransferError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            TransferError::GenericError { error_code, message } => ApproveError::GenericError { error_code, message },
+            TransferError::BadBurn { .. } => ApproveError::GenericError {
+                error_code: 3,
+                message: "Approvals cannot burn".to_string(),
+            },
+        }
+    }
+}
+
+impl From<TransferError> for TransferFromError {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+            TransferError::BadBurn { min_burn_amount } => TransferFromError::BadBurn { min_burn_amount },
+            TransferError::InsufficientFunds { balance } => TransferFromError::InsufficientFunds { balance },
+            TransferError::TooOld => TransferFromError::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => TransferFromError::CreatedInFuture { ledger_time },
+            TransferError::Duplicate { duplicate_of } => TransferFromError::Duplicate { duplicate_of },
+            TransferError::TemporarilyUnavailable => TransferFromError::TemporarilyUnavailable,
+            TransferError::GenericError { error_code, message } => TransferFromError::GenericError { error_code, message },
+        }
+    }
+}
+
+fn invalid_account(message: String) -> TransferError {
+    TransferError::GenericError { error_code: 1, message }
+}
+
+fn require_controller(action: &str) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(format!("Only controllers can {}", action));
+    }
+    Ok(())
+}
+
+// Initialize the token. The installer becomes the minting account and
+// mints supply, up to the maximum, by transferring from it.
 #[init]
-fn init() {
+fn init(args: InitArgs) {
+    if args.name.is_empty() || args.symbol.is_empty() {
+        trap("Token name and symbol must not be empty");
+    }
     let caller = ic_cdk::caller();
+    STATE.with(|state| {
+        state.borrow_mut().update_metadata(|metadata| {
+            metadata.name = args.name;
+            metadata.symbol = args.symbol;
+            metadata.decimals = args.decimals;
+            metadata.fee = args.fee;
+            metadata.logo = args.logo;
+            metadata.minting_account = Some(AccountKey::of(caller));
+        });
+    });
+}
+
+// Balances, allowances and the block log all live in stable memory, so
+// nothing needs saving before an upgrade; the certified tip does not survive
+// one and is set again here.
+#[post_upgrade]
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    icrc3::certify_tip();
+
+    let Some(args) = args else { return };
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        state.minting_account = Some(Account {
-            owner: caller,
-            subaccount: None,
+        state.update_metadata(|metadata| {
+            if let Some(name) = args.name.filter(|name| !name.is_empty()) {
+                metadata.name = name;
+            }
+            if let Some(symbol) = args.symbol.filter(|symbol| !symbol.is_empty()) {
+                metadata.symbol = symbol;
+            }
+            if let Some(logo) = args.logo {
+                metadata.logo = Some(logo).filter(|logo| !logo.is_empty());
+            }
         });
-        state.balances.insert(caller, GENESIS_AMOUNT);
-        state.total_supply = GENESIS_AMOUNT;
+        if let Some(fee) = args.fee.filter(|fee| *fee != state.fee()) {
+            state.set_fee(fee, ic_cdk::caller(), time());
+        }
     });
 }
 
 // Token Metadata
 #[query]
 fn icrc1_name() -> String {
-    TOKEN_NAME.to_string()
+    STATE.with(|state| state.borrow().metadata().name)
 }
 
 #[query]
 fn icrc1_symbol() -> String {
-    TOKEN_SYMBOL.to_string()
+    STATE.with(|state| state.borrow().metadata().symbol)
 }
 
 #[query]
 fn icrc1_decimals() -> u8 {
-    DECIMALS
+    STATE.with(|state| state.borrow().metadata().decimals)
 }
 
 #[query]
 fn icrc1_fee() -> u128 {
-    TRANSFER_FEE
+    STATE.with(|state| state.borrow().fee())
+}
+
+#[query]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    let metadata = STATE.with(|state| state.borrow().metadata());
+    let mut entries = vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(metadata.name)),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(metadata.symbol)),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(metadata.decimals.into())),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(metadata.fee.into())),
+    ];
+    if let Some(logo) = metadata.logo {
+        entries.push(("icrc1:logo".to_string(), MetadataValue::Text(logo)));
+    }
+    entries
+}
+
+#[query]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    [
+        ("ICRC-1", "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1"),
+        ("ICRC-2", "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2"),
+        ("ICRC-3", "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3"),
+    ]
+    .into_iter()
+    .map(|(name, url)| StandardRecord { name: name.to_string(), url: url.to_string() })
+    .collect()
+}
+
+/// Changes the transfer fee and returns the index of the block recording it.
+#[update]
+fn set_transfer_fee(fee: u128) -> Result<u128, String> {
+    require_controller("set the transfer fee")?;
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if fee == state.fee() {
+            return Err(format!("The transfer fee is already {}", fee));
+        }
+        Ok(state.set_fee(fee, ic_cdk::caller(), time()))
+    })
 }
 
 #[query]
 fn icrc1_total_supply() -> u128 {
-    STATE.with(|state| state.borrow().total_supply)
+    STATE.with(|state| state.borrow().total_supply())
 }
 
 #[query]
 fn icrc1_minting_account() -> Option<Account> {
-    STATE.with(|state| state.borrow().minting_account.clone())
+    STATE.with(|state| state.borrow().minting_account().map(|account| account.account()))
 }
 
-// Balance operations
 #[query]
-fn icrc1_balance_of(account: Account) -> u128 {
+fn get_fee_collector() -> Option<Account> {
+    STATE.with(|state| state.borrow().fee_collector().map(|account| account.account()))
+}
+
+#[update]
+fn set_fee_collector(fee_collector: Option<Account>) -> Result<(), String> {
+    require_controller("set the fee collector")?;
+    let fee_collector = fee_collector.map(|account| account.key()).transpose()?;
+
     STATE.with(|state| {
-        state.borrow().balances.get(&account.owner).copied().unwrap_or(0)
+        let mut state = state.borrow_mut();
+        if fee_collector.is_some() && fee_collector == state.minting_account() {
+            return Err("The minting account cannot collect fees".to_string());
+        }
+        state.set_fee_collector(fee_collector);
+        Ok(())
     })
 }
 
+// Balance operations
+#[query]
+fn icrc1_balance_of(account: Account) -> u128 {
+    let key = account.key().unwrap_or_else(|e| trap(&e));
+    STATE.with(|state| state.borrow().balance(&key))
+}
+
 // Transfer operation
 #[update]
 fn icrc1_transfer(args: TransferArgs) -> TransferResult {
     let caller = ic_cdk::caller();
+    let from = AccountKey::from_subaccount(caller, &args.from_subaccount).map_err(invalid_account)?;
+    let to = args.to.key().map_err(invalid_account)?;
     
     if args.amount == 0 {
         return Ok(0);
     }
 
+    // Mints and burns are free
+    let (kind, transfer_fee) = STATE.with(|state| {
+        let state = state.borrow();
+        state.transfer_kind(&from, &to).map(|kind| (kind, state.fee()))
+    })?;
+    let fee = match kind {
+        TransactionKind::Transfer => transfer_fee,
+        _ => 0,
+    };
+
     // Verify fee
-    if let Some(fee) = args.fee {
-        if fee != TRANSFER_FEE {
+    if let Some(requested_fee) = args.fee {
+        if requested_fee != fee {
             return Err(TransferError::BadFee {
-                expected_fee: TRANSFER_FEE,
+                expected_fee: fee,
             });
         }
     }
 
-    // Check timing
+    // Check timing; only calls with a created_at_time are deduplicated
     let now = time();
-    if let Some(created_at_time) = args.created_at_time {
-        if created_at_time > now {
-            return Err(TransferError::CreatedInFuture { ledger_time: now });
-        }
-        if now - created_at_time > 24 * 60 * 60 * 1_000_000_000 {
-            return Err(TransferError::TooOld);
-        }
-    }
+    let dedup = deduplication_key(&from, &args, args.created_at_time, now)?;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        // Check balance
-        let from_balance = state.balances.get(&args.from.owner).copied().unwrap_or(0);
-        let total_debit = args.amount + TRANSFER_FEE;
-        
-        if from_balance < total_debit {
-            return Err(TransferError::InsufficientFunds {
-                balance: from_balance,
-            });
+        state.check_duplicate(dedup.as_ref().map(|(hash, _)| hash), now)?;
+        match kind {
+            TransactionKind::Mint => state.mint(to, args.amount)?,
+            TransactionKind::Burn { .. } => state.burn(from, args.amount)?,
+            _ => state.transfer(from, to, args.amount, fee)?,
         }
 
-        // Update balances
-        *state.balances.entry(args.from.owner).or_insert(0) -= total_debit;
-        *state.balances.entry(args.to.owner).or_insert(0) += args.amount;
-
         // Record transaction
         let tx = Transaction {
-            from: args.from,
-            to: args.to,
+            kind,
+            from: from.account(),
+            to: to.account(),
             amount: args.amount,
-            fee: TRANSFER_FEE,
+            fee,
             memo: args.memo,
+            created_at_time: args.created_at_time,
             timestamp: now,
         };
-        state.transactions.push(tx);
-
-        Ok(state.transactions.len() as u128 - 1)
+        Ok(state.record(tx, dedup))
     })
 }
 
+/// Rejects timestamps outside the deduplication window.
+fn check_created_at_time(created_at_time: u64, now: u64) -> Result<(), TransferError> {
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err(TransferError::CreatedInFuture { ledger_time: now });
+    }
+    if created_at_time < now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) {
+        return Err(TransferError::TooOld);
+    }
+    Ok(())
+}
+
+/// Validates `created_at_time` and returns the hash to deduplicate the call
+/// under, if it has one.
+fn deduplication_key<T: candid::CandidType>(
+    caller: &AccountKey,
+    args: &T,
+    created_at_time: Option<u64>,
+    now: u64,
+) -> Result<Option<(TxHash, u64)>, TransferError> {
+    match created_at_time {
+        Some(created_at_time) => {
+            check_created_at_time(created_at_time, now)?;
+            Ok(Some((transaction_hash(caller, args), created_at_time)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Identifies a call by its caller account and arguments, so an identical
+/// retry within the window is recognized as a duplicate.
+fn transaction_hash<T: candid::CandidType>(caller: &AccountKey, args: &T) -> TxHash {
+    let bytes = Encode!(caller, args).unwrap_or_else(|e| trap(&format!("Failed to encode arguments: {}", e)));
+    Sha256::digest(bytes).into()
+}
+
+fn overflow() -> TransferError {
+    TransferError::GenericError {
+        error_code: 2,
+        message: "Arithmetic overflow".to_string(),
+    }
+}
+
 // Approvals
 #[query]
 fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
-    STATE.with(|state| {
-        state
-            .borrow()
-            .allowances
-            .get(&(args.account.owner, args.spender.owner))
-            .cloned()
-            .unwrap_or(Allowance {
-                amount: 0,
-                expires_at: None,
-            })
-    })
+    let owner = args.account.key().unwrap_or_else(|e| trap(&e));
+    let spender = args.spender.key().unwrap_or_else(|e| trap(&e));
+    STATE.with(|state| state.borrow().allowance(&owner, &spender, time()))
 }
 
 #[update]
-fn icrc2_approve(args: ApproveArgs) -> TransferResult {
+fn icrc2_approve(args: ApproveArgs) -> ApproveResult {
     let caller = ic_cdk::caller();
+    let owner = AccountKey::from_subaccount(caller, &args.from_subaccount).map_err(invalid_account)?;
+    let spender = args.spender.key().map_err(invalid_account)?;
+
+    if spender.owner == caller {
+        return Err(ApproveError::GenericError {
+            error_code: 3,
+            message: "Cannot approve an account owned by the caller".to_string(),
+        });
+    }
+
+    let fee = STATE.with(|state| state.borrow().fee());
+    if let Some(requested_fee) = args.fee {
+        if requested_fee != fee {
+            return Err(ApproveError::BadFee { expected_fee: fee });
+        }
+    }
+
+    let now = time();
+    if let Some(expires_at) = args.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+    let dedup = deduplication_key(&owner, &args, args.created_at_time, now)?;
     
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        
-        state.allowances.insert(
-            (caller, args.spender.owner),
+        state.prune_expired_allowances(now);
+        state.check_duplicate(dedup.as_ref().map(|(hash, _)| hash), now)?;
+
+        if let Some(expected_allowance) = args.expected_allowance {
+            let current_allowance = state.allowance(&owner, &spender, now).allowance;
+            if current_allowance != expected_allowance {
+                return Err(ApproveError::AllowanceChanged { current_allowance });
+            }
+        }
+
+        // The approval fee is charged to the owner's account
+        state.charge_fee(owner, fee)?;
+
+        state.set_allowance(
+            owner,
+            spender,
             Allowance {
-                amount: args.amount,
+                allowance: args.amount,
+                expires_at: args.expires_at,
+            },
+        );
+
+        let tx = Transaction {
+            kind: TransactionKind::Approve {
+                expected_allowance: args.expected_allowance,
                 expires_at: args.expires_at,
             },
+            from: owner.account(),
+            to: spender.account(),
+            amount: args.amount,
+            fee,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            timestamp: now,
+        };
+        Ok(state.record(tx, dedup))
+    })
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> TransferFromResult {
+    let caller = ic_cdk::caller();
+    let spender = AccountKey::from_subaccount(caller, &args.spender_subaccount).map_err(invalid_account)?;
+    let from = args.from.key().map_err(invalid_account)?;
+    let to = args.to.key().map_err(invalid_account)?;
+
+    // Allowances can burn but never mint
+    let (kind, transfer_fee) = STATE.with(|state| {
+        let state = state.borrow();
+        state.transfer_kind(&from, &to).map(|kind| (kind, state.fee()))
+    })?;
+    let kind = match kind {
+        TransactionKind::Mint => {
+            return Err(TransferFromError::GenericError {
+                error_code: 3,
+                message: "Cannot mint through an allowance".to_string(),
+            })
+        }
+        TransactionKind::Burn { .. } => TransactionKind::Burn { spender: Some(spender.account()) },
+        _ => TransactionKind::TransferFrom { spender: spender.account() },
+    };
+    let fee = match kind {
+        TransactionKind::TransferFrom { .. } => transfer_fee,
+        _ => 0,
+    };
+
+    if let Some(requested_fee) = args.fee {
+        if requested_fee != fee {
+            return Err(TransferFromError::BadFee { expected_fee: fee });
+        }
+    }
+
+    let now = time();
+    let dedup = deduplication_key(&spender, &args, args.created_at_time, now)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.prune_expired_allowances(now);
+        state.check_duplicate(dedup.as_ref().map(|(hash, _)| hash), now)?;
+
+        // The spender's allowance covers both the amount and the fee
+        let allowance = state.allowance(&from, &spender, now);
+        let total = args.amount.checked_add(fee).ok_or_else(overflow)?;
+        let remaining = allowance
+            .allowance
+            .checked_sub(total)
+            .ok_or(TransferFromError::InsufficientAllowance { allowance: allowance.allowance })?;
+
+        match kind {
+            TransactionKind::Burn { .. } => state.burn(from, args.amount)?,
+            _ => state.transfer(from, to, args.amount, fee)?,
+        }
+        state.set_allowance(
+            from,
+            spender,
+            Allowance {
+                allowance: remaining,
+                expires_at: allowance.expires_at,
+            },
         );
-        
-        Ok(0)
+
+        let tx = Transaction {
+            kind,
+            from: from.account(),
+            to: to.account(),
+            amount: args.amount,
+            fee,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+            timestamp: now,
+        };
+        Ok(state.record(tx, dedup))
+    })
+}
+
+// Vesting
+#[query]
+fn get_vesting_buckets() -> Vec<(Bucket, VestingBucket)> {
+    STATE.with(|state| state.borrow().vesting_buckets())
+}
+
+#[update]
+fn set_vesting_bucket(bucket: Bucket, allocation: u128, schedule: VestingSchedule) -> Result<(), String> {
+    require_controller("configure vesting")?;
+    STATE.with(|state| state.borrow_mut().set_vesting_bucket(bucket, allocation, schedule))
+}
+
+/// Mints a locked grant to the beneficiary and returns the mint's block
+/// index.
+#[update]
+fn create_vesting_grant(args: GrantArgs) -> Result<u128, String> {
+    require_controller("create vesting grants")?;
+    let beneficiary = args.beneficiary.key()?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let minting_account = state.minting_account().ok_or("The token has no minting account")?;
+        state.grant(args.bucket, beneficiary, args.amount)?;
+
+        let tx = Transaction {
+            kind: TransactionKind::Mint,
+            from: minting_account.account(),
+            to: beneficiary.account(),
+            amount: args.amount,
+            fee: 0,
+            memo: None,
+            created_at_time: None,
+            timestamp: time(),
+        };
+        Ok(state.record(tx, None))
     })
 }
 
+/// Unlocks whatever has vested in the caller's grants and returns the
+/// amount released.
+#[update]
+fn release(from_subaccount: Option<Vec<u8>>) -> Result<u128, String> {
+    let beneficiary = AccountKey::from_subaccount(ic_cdk::caller(), &from_subaccount)?;
+    match STATE.with(|state| state.borrow_mut().release(beneficiary, time())) {
+        0 => Err("Nothing has vested since the last release".to_string()),
+        released => Ok(released),
+    }
+}
+
+#[query]
+fn get_vesting_status(account: Account) -> VestingStatus {
+    let beneficiary = account.key().unwrap_or_else(|e| trap(&e));
+    STATE.with(|state| state.borrow().vesting_status(beneficiary, time()))
+}
+
+// Transaction log
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    icrc3::get_blocks(args)
+}
+
+#[query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    icrc3::supported_block_types()
+}
+
+/// A certificate for the hash tree labelling the tip's index and hash.
+#[query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    icrc3::tip_certificate()
+}
+
+#[query]
+fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    icrc3::archives(args)
+}
+
 // Types for args
-#[derive(candid::CandidType)]
+#[derive(candid::CandidType, candid::Deserialize)]
 struct TransferArgs {
-    from: Account,
+    from_subaccount: Option<Vec<u8>>,  // The caller owns the source account
     to: Account,
     amount: u128,
     fee: Option<u128>,
@@ -224,22 +790,128 @@ struct TransferArgs {
     created_at_time: Option<u64>,
 }
 
-#[derive(candid::CandidType)]
+#[derive(candid::CandidType, candid::Deserialize)]
 struct AllowanceArgs {
     account: Account,
     spender: Account,
 }
 
-#[derive(candid::CandidType)]
+#[derive(candid::CandidType, candid::Deserialize)]
 struct ApproveArgs {
     from_subaccount: Option<Vec<u8>>,
     spender: Account,
     amount: u128,
+    expected_allowance: Option<u128>,
     expires_at: Option<u64>,
     fee: Option<u128>,
     memo: Option<Vec<u8>>,
     created_at_time: Option<u64>,
 }
 
+#[derive(candid::CandidType, candid::Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+struct GrantArgs {
+    bucket: Bucket,
+    beneficiary: Account,
+    amount: u128,
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+struct InitArgs {
+    name: String,
+    symbol: String,
+    decimals: u8,
+    fee: u128,
+    logo: Option<String>,  // URL, possibly a data URL
+}
+
+/// Fields left unset keep their current value; an empty logo removes it.
+#[derive(candid::CandidType, candid::Deserialize)]
+struct UpgradeArgs {
+    name: Option<String>,
+    symbol: Option<String>,
+    fee: Option<u128>,
+    logo: Option<String>,
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+enum MetadataValue {
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(candid::CandidType, candid::Deserialize)]
+struct StandardRecord {
+    name: String,
+    url: String,
+}
+
 // Generate Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_subaccount_matches_none() {
+        let owner = Principal::anonymous();
+        let none = Account { owner, subaccount: None };
+        let zero = Account { owner, subaccount: Some(vec![0; 32]) };
+        assert_eq!(none.key().unwrap(), zero.key().unwrap());
+
+        let mut bytes = [0u8; 32];
+        bytes[31] = 1;
+        let other = Account { owner, subaccount: Some(bytes.to_vec()) };
+        assert_ne!(other.key().unwrap(), none.key().unwrap());
+        assert!(Account { owner, subaccount: Some(vec![1; 31]) }.key().is_err());
+    }
+
+    #[test]
+    fn test_created_at_time_window() {
+        let now = 10 * TX_WINDOW_NANOS;
+        assert!(check_created_at_time(now, now).is_ok());
+        assert!(check_created_at_time(now + PERMITTED_DRIFT_NANOS, now).is_ok());
+        assert!(matches!(
+            check_created_at_time(now + PERMITTED_DRIFT_NANOS + 1, now),
+            Err(TransferError::CreatedInFuture { .. })
+        ));
+        assert!(matches!(
+            check_created_at_time(now - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS - 1, now),
+            Err(TransferError::TooOld)
+        ));
+    }
+
+    #[test]
+    fn test_blocks_link_to_their_parent() {
+        let tx = Transaction {
+            kind: TransactionKind::Transfer,
+            from: AccountKey::of(Principal::anonymous()).account(),
+            to: AccountKey::of(Principal::management_canister()).account(),
+            amount: 1_000,
+            fee: 10_000,
+            memo: None,
+            created_at_time: None,
+            timestamp: 1,
+        };
+        let genesis = tx.to_block(None, None);
+        let next = tx.to_block(Some(genesis.hash()), None);
+
+        let Value::Map(fields) = &next else { panic!("blocks are maps") };
+        assert!(fields.contains(&entry("btype", Value::text("1xfer"))));
+        assert!(fields.contains(&entry("phash", Value::Blob(genesis.hash().to_vec()))));
+        assert_ne!(genesis.hash(), next.hash());
+    }
+}
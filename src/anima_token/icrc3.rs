@@ -0,0 +1,377 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Int, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Log, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::{memory, Memory};
+
+pub type Hash = [u8; 32];
+
+const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+/// The ICRC-3 generic value that blocks are made of.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn nat(n: impl Into<Nat>) -> Self {
+        Value::Nat(n.into())
+    }
+
+    pub fn text(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+
+    /// The representation-independent hash from the ICRC-3 standard.
+    pub fn hash(&self) -> Hash {
+        match self {
+            Value::Blob(bytes) => sha256(bytes),
+            Value::Text(text) => sha256(text.as_bytes()),
+            Value::Nat(nat) => {
+                let mut leb128 = Vec::new();
+                nat.encode(&mut leb128).expect("writing to a vec cannot fail");
+                sha256(&leb128)
+            }
+            Value::Int(int) => {
+                let mut sleb128 = Vec::new();
+                int.encode(&mut sleb128).expect("writing to a vec cannot fail");
+                sha256(&sleb128)
+            }
+            Value::Array(values) => {
+                let mut hasher = Sha256::new();
+                for value in values {
+                    hasher.update(value.hash());
+                }
+                hasher.finalize().into()
+            }
+            Value::Map(entries) => {
+                let mut pairs: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|(key, value)| [sha256(key.as_bytes()), value.hash()].concat())
+                    .collect();
+                pairs.sort();
+
+                let mut hasher = Sha256::new();
+                for pair in pairs {
+                    hasher.update(pair);
+                }
+                hasher.finalize().into()
+            }
+        }
+    }
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+fn leb128(n: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    Nat::from(n).encode(&mut bytes).expect("writing to a vec cannot fail");
+    bytes
+}
+
+/// The hash tree ICRC-3 certifies for the tip:
+///
+/// fork(labeled("last_block_hash", leaf(hash)),
+///      labeled("last_block_index", leaf(leb128(index))))
+///
+/// Returns the root hash, which is the certified data, and the tree's CBOR
+/// encoding, which clients check against the certificate.
+fn tip_tree(index: u64, hash: &Hash) -> (Hash, Vec<u8>) {
+    let index = leb128(index);
+    let root = tree_hash(
+        b"ic-hashtree-fork",
+        &[
+            &tree_hash(b"ic-hashtree-labeled", &[b"last_block_hash", &tree_hash(b"ic-hashtree-leaf", &[hash])]),
+            &tree_hash(b"ic-hashtree-labeled", &[b"last_block_index", &tree_hash(b"ic-hashtree-leaf", &[&index])]),
+        ],
+    );
+
+    // Self-describing CBOR tag, then [1, left, right] for the fork,
+    // [2, label, subtree] for each label and [3, value] for each leaf
+    let mut cbor = vec![0xd9, 0xd9, 0xf7, 0x83, 0x01];
+    for (label, value) in [(&b"last_block_hash"[..], &hash[..]), (b"last_block_index", &index)] {
+        cbor.extend_from_slice(&[0x83, 0x02]);
+        cbor_bytes(&mut cbor, label);
+        cbor.extend_from_slice(&[0x82, 0x03]);
+        cbor_bytes(&mut cbor, value);
+    }
+    (root, cbor)
+}
+
+/// Hash of a hash tree node: its domain separator, then its parts.
+fn tree_hash(domain: &[u8], parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Appends a CBOR byte string; tree values are always shorter than 256 bytes.
+fn cbor_bytes(cbor: &mut Vec<u8>, bytes: &[u8]) {
+    match bytes.len() {
+        len if len < 24 => cbor.push(0x40 | len as u8),
+        len => cbor.extend_from_slice(&[0x58, len as u8]),
+    }
+    cbor.extend_from_slice(bytes);
+}
+
+// Blocks are stored candid-encoded
+struct EncodedBlock(Vec<u8>);
+
+impl Storable for EncodedBlock {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl From<&Value> for EncodedBlock {
+    fn from(block: &Value) -> Self {
+        Self(Encode!(block).expect("failed to encode block"))
+    }
+}
+
+impl From<EncodedBlock> for Value {
+    fn from(block: EncodedBlock) -> Self {
+        Decode!(&block.0, Value).expect("failed to decode block")
+    }
+}
+
+thread_local! {
+    static BLOCKS: RefCell<Log<EncodedBlock, Memory, Memory>> = RefCell::new(
+        Log::init(memory(BLOCKS_INDEX_MEMORY_ID), memory(BLOCKS_DATA_MEMORY_ID))
+            .expect("failed to initialize the block log")
+    );
+}
+
+pub fn log_length() -> u64 {
+    BLOCKS.with(|blocks| blocks.borrow().len())
+}
+
+pub fn block(index: u64) -> Option<Value> {
+    BLOCKS.with(|blocks| blocks.borrow().get(index)).map(Value::from)
+}
+
+/// Hash of the newest block, which every new block links to.
+pub fn tip() -> Option<(u64, Hash)> {
+    let length = log_length();
+    let last = length.checked_sub(1)?;
+    block(last).map(|block| (last, block.hash()))
+}
+
+/// Appends the block built by `build` from the parent hash, certifies the
+/// new tip and returns its index.
+pub fn append(build: impl FnOnce(Option<Hash>) -> Value) -> u64 {
+    let block = build(tip().map(|(_, hash)| hash));
+    let hash = block.hash();
+    let index = BLOCKS.with(|blocks| {
+        blocks
+            .borrow_mut()
+            .append(&EncodedBlock::from(&block))
+            .expect("failed to append block")
+    });
+    ic_cdk::api::set_certified_data(&tip_tree(index, &hash).0);
+    index
+}
+
+/// Certifies the tip again, e.g. after an upgrade.
+pub fn certify_tip() {
+    if let Some((index, hash)) = tip() {
+        ic_cdk::api::set_certified_data(&tip_tree(index, &hash).0);
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksFn,
+}
+
+candid::define_function!(pub GetBlocksFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,  // Always empty; nothing is archived yet
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+/// The certificate for the tip and the hash tree whose root it certifies.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,  // CBOR-encoded
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+/// Serves the requested ranges in order, returning at most
+/// `MAX_BLOCKS_PER_RESPONSE` blocks in total.
+pub fn get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = log_length();
+    let mut budget = MAX_BLOCKS_PER_RESPONSE;
+    let mut blocks = Vec::new();
+
+    for range in args {
+        let (Some(start), Some(length)) = (to_u64(&range.start), to_u64(&range.length)) else {
+            continue;
+        };
+        let end = start.saturating_add(length.min(budget)).min(log_length);
+        for id in start..end {
+            if let Some(block) = block(id) {
+                blocks.push(BlockWithId { id: Nat::from(id), block });
+            }
+        }
+        budget -= end.saturating_sub(start);
+        if budget == 0 {
+            break;
+        }
+    }
+
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: Vec::new(),
+    }
+}
+
+pub fn supported_block_types() -> Vec<SupportedBlockType> {
+    const ICRC1: &str = "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-1/README.md";
+    const ICRC2: &str = "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-2/README.md";
+    // Documented alongside the token source
+    const ANIMA: &str = "src/anima_token/README.md#anima_fee-block";
+    [
+        ("1burn", ICRC1),
+        ("1mint", ICRC1),
+        ("1xfer", ICRC1),
+        ("2approve", ICRC2),
+        ("2xfer", ICRC2),
+        ("anima_fee", ANIMA),
+    ]
+        .into_iter()
+        .map(|(block_type, url)| SupportedBlockType { block_type: block_type.to_string(), url: url.to_string() })
+        .collect()
+}
+
+pub fn tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let (index, hash) = tip()?;
+    Some(DataCertificate { certificate, hash_tree: tip_tree(index, &hash).1 })
+}
+
+/// Nothing is archived yet; every block is served by this canister.
+pub fn archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    Vec::new()
+}
+
+fn to_u64(nat: &Nat) -> Option<u64> {
+    u64::try_from(nat.0.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes_match_the_standard_examples() {
+        // Examples from the ICRC-3 specification
+        assert_eq!(
+            hex::encode(Value::nat(42u64).hash()),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+        );
+        assert_eq!(
+            hex::encode(Value::text("Hello, World!").hash()),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+        assert_eq!(
+            hex::encode(Value::Blob(vec![0x01, 0x02, 0x03, 0x04]).hash()),
+            "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+        );
+    }
+
+    #[test]
+    fn test_map_hash_ignores_entry_order() {
+        let a = Value::Map(vec![("a".to_string(), Value::nat(1u64)), ("b".to_string(), Value::text("x"))]);
+        let b = Value::Map(vec![("b".to_string(), Value::text("x")), ("a".to_string(), Value::nat(1u64))]);
+        assert_eq!(a.hash(), b.hash());
+    }
+
+    #[test]
+    fn test_every_recorded_block_type_is_advertised() {
+        let advertised: Vec<String> = supported_block_types().into_iter().map(|t| t.block_type).collect();
+        for block_type in ["1burn", "1mint", "1xfer", "2approve", "2xfer", "anima_fee"] {
+            assert!(advertised.iter().any(|t| t == block_type), "{} is not advertised", block_type);
+        }
+    }
+
+    #[test]
+    fn test_tip_tree_is_labelled_and_encoded() {
+        let hash = [7u8; 32];
+        let (root, cbor) = tip_tree(624_485, &hash);
+
+        assert_eq!(leb128(624_485), vec![0xe5, 0x8e, 0x26]);
+        let expected = tree_hash(
+            b"ic-hashtree-fork",
+            &[
+                &tree_hash(b"ic-hashtree-labeled", &[b"last_block_hash", &tree_hash(b"ic-hashtree-leaf", &[&hash])]),
+                &tree_hash(b"ic-hashtree-labeled", &[b"last_block_index", &tree_hash(b"ic-hashtree-leaf", &[&[0xe5, 0x8e, 0x26]])]),
+            ],
+        );
+        assert_eq!(root, expected);
+        assert_ne!(root, hash);
+
+        assert_eq!(&cbor[..5], &[0xd9, 0xd9, 0xf7, 0x83, 0x01]);
+        // The hash leaf is a 32-byte string: 0x58 0x20
+        let leaf = [&[0x82, 0x03, 0x58, 0x20][..], &hash].concat();
+        assert!(cbor.windows(leaf.len()).any(|window| window == leaf.as_slice()));
+        assert!(cbor.ends_with(&[0x82, 0x03, 0x43, 0xe5, 0x8e, 0x26]));
+    }
+}
@@ -33,6 +33,20 @@ pub struct RewardPool {
     distribution_interval: u64
 }
 
+/// A payout whose outcome is unknown because the ledger call failed.
+/// Retrying sends it again with the same `created_at_time`, so the ledger
+/// deduplicates it instead of paying twice.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+struct PendingPayout {
+    amount: u128,
+    created_at_time: u64,
+}
+
+enum TransferFailure {
+    Rejected(String),  // The ledger refused the transfer
+    Unknown(String),   // The call failed; the transfer may have happened
+}
+
 thread_local! {
     static REWARD_METRICS: RefCell<HashMap<Principal, RewardMetrics>> = RefCell::new(HashMap::new());
     static REWARD_CONFIG: RefCell<RewardConfig> = RefCell::new(RewardConfig {
@@ -43,16 +57,52 @@ thread_local! {
         staking_bonus_rate: 0.1,
         network_bonus_rate: 0.3
     });
+    // Account that funds rewards; it must approve this canister as a spender
+    static REWARD_TREASURY: RefCell<Option<Principal>> = RefCell::new(None);
     static REWARD_POOL: RefCell<RewardPool> = RefCell::new(RewardPool {
         total_rewards: 0,
         distributed_rewards: 0,
         last_distribution: 0,
         distribution_interval: 24 * 60 * 60 * 1_000_000_000 // 24 hours
     });
+    static PENDING_PAYOUTS: RefCell<HashMap<Principal, PendingPayout>> = RefCell::new(HashMap::new());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = (
+        REWARD_METRICS.with(|metrics| metrics.borrow().clone()),
+        REWARD_CONFIG.with(|config| config.borrow().clone()),
+        REWARD_TREASURY.with(|treasury| *treasury.borrow()),
+        REWARD_POOL.with(|pool| pool.borrow().clone()),
+        PENDING_PAYOUTS.with(|pending| pending.borrow().clone()),
+    );
+    ic_cdk::storage::stable_save(state).expect("failed to save reward state");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (metrics, config, treasury, pool, pending): (
+        HashMap<Principal, RewardMetrics>,
+        RewardConfig,
+        Option<Principal>,
+        RewardPool,
+        HashMap<Principal, PendingPayout>,
+    ) = ic_cdk::storage::stable_restore().expect("failed to restore reward state");
+    REWARD_METRICS.with(|current| *current.borrow_mut() = metrics);
+    REWARD_CONFIG.with(|current| *current.borrow_mut() = config);
+    REWARD_TREASURY.with(|current| *current.borrow_mut() = treasury);
+    REWARD_POOL.with(|current| *current.borrow_mut() = pool);
+    PENDING_PAYOUTS.with(|current| *current.borrow_mut() = pending);
 }
 
 #[update]
 async fn distribute_rewards(principal: Principal) -> Result<u128, String> {
+    // Finish a payout whose outcome is unknown before computing a new one
+    if let Some(pending) = PENDING_PAYOUTS.with(|pending| pending.borrow().get(&principal).cloned()) {
+        return pay_out(principal, pending).await;
+    }
+
     let current_time = time();
     let metrics = REWARD_METRICS.with(|metrics| {
         metrics.borrow().get(&principal).cloned()
@@ -82,10 +132,34 @@ async fn distribute_rewards(principal: Principal) -> Result<u128, String> {
     pool.last_distribution = current_time;
 
     // Transfer rewards
-    match transfer_rewards(principal, reward).await {
-        Ok(_) => Ok(reward),
-        Err(e) => Err(format!("Reward transfer failed: {}", e))
+    let pending = PendingPayout { amount: reward, created_at_time: current_time };
+    PENDING_PAYOUTS.with(|payouts| payouts.borrow_mut().insert(principal, pending.clone()));
+    pay_out(principal, pending).await
+}
+
+/// Sends a payout, keeping it pending if the call fails so the next
+/// `distribute_rewards` resends it unchanged.
+async fn pay_out(to: Principal, payout: PendingPayout) -> Result<u128, String> {
+    match transfer_rewards(to, &payout).await {
+        Ok(()) => {
+            PENDING_PAYOUTS.with(|pending| pending.borrow_mut().remove(&to));
+            Ok(payout.amount)
+        }
+        Err(TransferFailure::Rejected(e)) => {
+            PENDING_PAYOUTS.with(|pending| pending.borrow_mut().remove(&to));
+            Err(format!("Reward transfer failed: {}", e))
+        }
+        Err(TransferFailure::Unknown(e)) => Err(format!("Reward transfer failed; retry to resend it: {}", e)),
+    }
+}
+
+#[update]
+fn set_reward_treasury(treasury: Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("Only controllers can set the reward treasury".to_string());
     }
+    REWARD_TREASURY.with(|current| *current.borrow_mut() = Some(treasury));
+    Ok(())
 }
 
 #[update]
@@ -119,21 +193,29 @@ fn calculate_potential_rewards(metrics: RewardMetrics) -> u128 {
     (base_reward as f64 * total_multiplier) as u128
 }
 
-async fn transfer_rewards(to: Principal, amount: u128) -> Result<(), String> {
+/// Pays a reward out of the treasury's allowance to this canister.
+async fn transfer_rewards(to: Principal, payout: &PendingPayout) -> Result<(), TransferFailure> {
     let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
-    let args = TransferArgs {
-        from: Account { owner: ic_cdk::id(), subaccount: None },
+    let treasury = REWARD_TREASURY
+        .with(|treasury| *treasury.borrow())
+        .ok_or_else(|| TransferFailure::Rejected("Reward treasury is not set".to_string()))?;
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: treasury, subaccount: None },
         to: Account { owner: to, subaccount: None },
-        amount,
+        amount: payout.amount,
         fee: None,
         memo: None,
-        created_at_time: None,
+        created_at_time: Some(payout.created_at_time),
     };
 
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg))
+    let result: CallResult<(Result<u128, TransferFromError>,)> =
+        ic_cdk::call(token_canister, "icrc2_transfer_from", (args,)).await;
+    match result {
+        // A duplicate is the earlier attempt of this same payout
+        Ok((Ok(_),)) | Ok((Err(TransferFromError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(e),)) => Err(TransferFailure::Rejected(format!("Transfer error: {:?}", e))),
+        Err((code, msg)) => Err(TransferFailure::Unknown(format!("RPC error: {:?} - {}", code, msg)))
     }
 }
 
@@ -144,7 +226,8 @@ struct Account {
 }
 
 #[derive(CandidType)]
-struct TransferArgs {
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
     from: Account,
     to: Account,
     amount: u128,
@@ -153,4 +236,17 @@ struct TransferArgs {
     created_at_time: Option<u64>,
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
 ic_cdk::export_candid!();
\ No newline at end of file
@@ -25,8 +25,24 @@ pub struct PoolMetrics {
     pub network_stability: f64,
 }
 
+/// A stake pull or payout whose outcome is unknown because the ledger call
+/// failed. Retrying sends it again with the same `created_at_time`, so the
+/// ledger deduplicates it instead of moving the tokens twice.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+struct PendingTransfer {
+    amount: u128,
+    created_at_time: u64,
+}
+
+enum TransferFailure {
+    Rejected(String),  // The ledger refused the transfer
+    Unknown(String),   // The call failed; the transfer may have happened
+}
+
 thread_local! {
     static STAKES: RefCell<HashMap<Principal, StakeInfo>> = RefCell::new(HashMap::new());
+    static PENDING_STAKES: RefCell<HashMap<Principal, PendingTransfer>> = RefCell::new(HashMap::new());
+    static PENDING_PAYOUTS: RefCell<HashMap<Principal, PendingTransfer>> = RefCell::new(HashMap::new());
     static POOL_METRICS: RefCell<PoolMetrics> = RefCell::new(PoolMetrics {
         total_staked: 0,
         total_rewards_distributed: 0,
@@ -41,6 +57,29 @@ const COHERENCE_MULTIPLIER: f64 = 2.0; // Up to 2x rewards for perfect coherence
 const MIN_STAKE_DURATION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days in nanoseconds
 const REWARD_CALCULATION_PERIOD: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours in nanoseconds
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = (
+        STAKES.with(|stakes| stakes.borrow().clone()),
+        PENDING_STAKES.with(|pending| pending.borrow().clone()),
+        PENDING_PAYOUTS.with(|pending| pending.borrow().clone()),
+    );
+    ic_cdk::storage::stable_save(state).expect("failed to save staking state");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (stakes, pending_stakes, pending_payouts): (
+        HashMap<Principal, StakeInfo>,
+        HashMap<Principal, PendingTransfer>,
+        HashMap<Principal, PendingTransfer>,
+    ) = ic_cdk::storage::stable_restore().expect("failed to restore staking state");
+    STAKES.with(|current| *current.borrow_mut() = stakes);
+    PENDING_STAKES.with(|current| *current.borrow_mut() = pending_stakes);
+    PENDING_PAYOUTS.with(|current| *current.borrow_mut() = pending_payouts);
+    update_pool_metrics();
+}
+
 #[update]
 async fn stake(amount: u128, lock_period: u64, quantum_coherence: f64) -> Result<(), String> {
     let caller = ic_cdk::caller();
@@ -57,10 +96,27 @@ async fn stake(amount: u128, lock_period: u64, quantum_coherence: f64) -> Result
         return Err("Quantum coherence too low for staking".to_string());
     }
 
-    // Transfer ANIMA tokens to staking contract
-    match transfer_tokens_to_contract(caller, amount).await {
-        Ok(_) => (),
-        Err(e) => return Err(format!("Token transfer failed: {}", e)),
+    // Transfer ANIMA tokens to staking contract. A retry after a failed
+    // call resends the same pull.
+    let pending = match PENDING_STAKES.with(|pending| pending.borrow().get(&caller).cloned()) {
+        Some(pending) if pending.amount != amount => {
+            return Err(format!("A stake of {} is still pending; retry it first", pending.amount));
+        }
+        Some(pending) => pending,
+        None => PendingTransfer { amount, created_at_time: time() },
+    };
+    PENDING_STAKES.with(|stakes| stakes.borrow_mut().insert(caller, pending.clone()));
+    match transfer_tokens_to_contract(caller, &pending).await {
+        Ok(()) => {
+            PENDING_STAKES.with(|stakes| stakes.borrow_mut().remove(&caller));
+        }
+        Err(TransferFailure::Rejected(e)) => {
+            PENDING_STAKES.with(|stakes| stakes.borrow_mut().remove(&caller));
+            return Err(format!("Token transfer failed: {}", e));
+        }
+        Err(TransferFailure::Unknown(e)) => {
+            return Err(format!("Token transfer failed; retry to resend it: {}", e));
+        }
     }
 
     STAKES.with(|stakes| {
@@ -92,6 +148,9 @@ async fn stake(amount: u128, lock_period: u64, quantum_coherence: f64) -> Result
 #[update]
 async fn unstake() -> Result<u128, String> {
     let caller = ic_cdk::caller();
+    if let Some(pending) = PENDING_PAYOUTS.with(|pending| pending.borrow().get(&caller).cloned()) {
+        return pay_out(caller, pending).await;
+    }
     let current_time = time();
     
     let (stake_info, rewards) = STAKES.with(|stakes| {
@@ -113,13 +172,8 @@ async fn unstake() -> Result<u128, String> {
     })?;
 
     // Transfer tokens back to user
-    match transfer_tokens_to_user(caller, stake_info.amount + rewards).await {
-        Ok(_) => {
-            update_pool_metrics();
-            Ok(stake_info.amount + rewards)
-        },
-        Err(e) => Err(format!("Token transfer failed: {}", e)),
-    }
+    update_pool_metrics();
+    pay_out(caller, PendingTransfer { amount: stake_info.amount + rewards, created_at_time: current_time }).await
 }
 
 #[query]
@@ -139,6 +193,9 @@ fn get_pool_metrics() -> PoolMetrics {
 #[update]
 async fn claim_rewards() -> Result<u128, String> {
     let caller = ic_cdk::caller();
+    if let Some(pending) = PENDING_PAYOUTS.with(|pending| pending.borrow().get(&caller).cloned()) {
+        return pay_out(caller, pending).await;
+    }
     let current_time = time();
     
     let rewards = STAKES.with(|stakes| {
@@ -158,15 +215,28 @@ async fn claim_rewards() -> Result<u128, String> {
     })?;
 
     // Transfer rewards to user
-    match transfer_tokens_to_user(caller, rewards).await {
-        Ok(_) => {
-            POOL_METRICS.with(|metrics| {
-                let mut metrics = metrics.borrow_mut();
-                metrics.total_rewards_distributed += rewards;
-            });
-            Ok(rewards)
-        },
-        Err(e) => Err(format!("Reward transfer failed: {}", e)),
+    let rewards = pay_out(caller, PendingTransfer { amount: rewards, created_at_time: current_time }).await?;
+    POOL_METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        metrics.total_rewards_distributed += rewards;
+    });
+    Ok(rewards)
+}
+
+/// Sends a payout, keeping it pending if the call fails so the next
+/// `unstake` or `claim_rewards` resends it unchanged.
+async fn pay_out(to: Principal, payout: PendingTransfer) -> Result<u128, String> {
+    PENDING_PAYOUTS.with(|pending| pending.borrow_mut().insert(to, payout.clone()));
+    match transfer_tokens_to_user(to, &payout).await {
+        Ok(()) => {
+            PENDING_PAYOUTS.with(|pending| pending.borrow_mut().remove(&to));
+            Ok(payout.amount)
+        }
+        Err(TransferFailure::Rejected(e)) => {
+            PENDING_PAYOUTS.with(|pending| pending.borrow_mut().remove(&to));
+            Err(format!("Token transfer failed: {}", e))
+        }
+        Err(TransferFailure::Unknown(e)) => Err(format!("Token transfer failed; retry to resend it: {}", e)),
     }
 }
 
@@ -226,39 +296,48 @@ fn calculate_network_stability(average_coherence: f64) -> f64 {
     })
 }
 
-async fn transfer_tokens_to_contract(from: Principal, amount: u128) -> Result<(), String> {
+/// Pulls the stake from the staker's account. The staker must first
+/// `icrc2_approve` this canister for `amount` plus the transfer fee.
+async fn transfer_tokens_to_contract(from: Principal, stake: &PendingTransfer) -> Result<(), TransferFailure> {
     let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
-    let args = TransferArgs {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
         from: Account { owner: from, subaccount: None },
         to: Account { owner: ic_cdk::id(), subaccount: None },
-        amount,
+        amount: stake.amount,
         fee: None,
         memo: None,
-        created_at_time: None,
+        created_at_time: Some(stake.created_at_time),
     };
 
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg)),
+    let result: CallResult<(Result<u128, TransferFromError>,)> =
+        ic_cdk::call(token_canister, "icrc2_transfer_from", (args,)).await;
+    match result {
+        // A duplicate is the earlier attempt of this same pull
+        Ok((Ok(_),)) | Ok((Err(TransferFromError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(e),)) => Err(TransferFailure::Rejected(format!("Transfer error: {:?}", e))),
+        Err((code, msg)) => Err(TransferFailure::Unknown(format!("RPC error: {:?} - {}", code, msg))),
     }
 }
 
-async fn transfer_tokens_to_user(to: Principal, amount: u128) -> Result<(), String> {
+async fn transfer_tokens_to_user(to: Principal, payout: &PendingTransfer) -> Result<(), TransferFailure> {
     let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
     let args = TransferArgs {
-        from: Account { owner: ic_cdk::id(), subaccount: None },
+        from_subaccount: None,
         to: Account { owner: to, subaccount: None },
-        amount,
+        amount: payout.amount,
         fee: None,
         memo: None,
-        created_at_time: None,
+        created_at_time: Some(payout.created_at_time),
     };
 
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg)),
+    let result: CallResult<(Result<u128, TransferError>,)> =
+        ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await;
+    match result {
+        // A duplicate is the earlier attempt of this same payout
+        Ok((Ok(_),)) | Ok((Err(TransferError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(e),)) => Err(TransferFailure::Rejected(format!("Transfer error: {:?}", e))),
+        Err((code, msg)) => Err(TransferFailure::Unknown(format!("RPC error: {:?} - {}", code, msg))),
     }
 }
 
@@ -270,6 +349,17 @@ struct Account {
 
 #[derive(CandidType)]
 struct TransferArgs {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
     from: Account,
     to: Account,
     amount: u128,
@@ -278,5 +368,30 @@ struct TransferArgs {
     created_at_time: Option<u64>,
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
 // Candid interface generation
 ic_cdk::export_candid!();
\ No newline at end of file
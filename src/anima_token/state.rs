@@ -0,0 +1,803 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+
+use crate::{
+    fee_change_block, icrc3, overflow, AccountKey, Allowance, Memory, RecentTransaction, Transaction, TransactionKind,
+    TransferError, TxHash, MAX_SUPPLY_TOKENS, PERMITTED_DRIFT_NANOS, TX_WINDOW_NANOS,
+};
+use crate::vesting::{Bucket, Grant, GrantKey, VestingBucket, VestingSchedule, VestingStatus};
+
+// Memory IDs 0 and 1 hold the ICRC-3 block log
+const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(2);
+const ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const ALLOWANCE_EXPIRATIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const RECENT_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const RECENT_TRANSACTIONS_BY_TIME_MEMORY_ID: MemoryId = MemoryId::new(6);
+const METADATA_MEMORY_ID: MemoryId = MemoryId::new(7);
+const VESTING_BUCKETS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const VESTING_GRANTS_MEMORY_ID: MemoryId = MemoryId::new(9);
+
+pub const ACCOUNT_KEY_SIZE: usize = 1 + 29 + 32;  // Owner length, padded owner, subaccount
+
+/// Token state that isn't derived from the block log. Everything lives in
+/// stable memory, so an upgrade only has to reopen it.
+pub struct State {
+    balances: StableBTreeMap<AccountKey, Tokens, Memory>,
+    allowances: StableBTreeMap<AllowanceKey, Allowance, Memory>,
+    allowance_expirations: StableBTreeMap<ExpirationKey, (), Memory>,
+    recent_transactions: StableBTreeMap<TxKey, RecentTransaction, Memory>,  // Deduplication window
+    recent_transactions_by_time: StableBTreeMap<RecentKey, (), Memory>,
+    metadata: StableCell<StoredMetadata, Memory>,
+    vesting_buckets: StableBTreeMap<Bucket, VestingBucket, Memory>,
+    vesting_grants: StableBTreeMap<GrantKey, Grant, Memory>,
+}
+
+/// Scalar token state, versioned so later releases can migrate it.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Metadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u128,
+    pub logo: Option<String>,  // URL, possibly a data URL
+    pub total_supply: u128,
+    pub minting_account: Option<AccountKey>,
+    pub fee_collector: Option<AccountKey>,  // Fees are burned when unset
+}
+
+/// Metadata before the token description became configurable.
+#[derive(CandidType, Deserialize, Clone)]
+struct MetadataV1 {
+    total_supply: u128,
+    minting_account: Option<AccountKey>,
+    fee_collector: Option<AccountKey>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum StoredMetadata {
+    V1(MetadataV1),
+    V2(Metadata),
+}
+
+impl StoredMetadata {
+    fn into_current(self) -> Metadata {
+        match self {
+            // V1 tokens were built with these values as constants
+            StoredMetadata::V1(v1) => Metadata {
+                name: "ANIMA Token".to_string(),
+                symbol: "ANIMA".to_string(),
+                decimals: 8,
+                fee: 10_000,
+                logo: None,
+                total_supply: v1.total_supply,
+                minting_account: v1.minting_account,
+                fee_collector: v1.fee_collector,
+            },
+            StoredMetadata::V2(metadata) => metadata,
+        }
+    }
+}
+
+impl State {
+    /// Opens the state in the memories handed out by `memory`, picking up
+    /// whatever a previous installation left there.
+    pub fn init(memory: impl Fn(MemoryId) -> Memory) -> Self {
+        Self {
+            balances: StableBTreeMap::init(memory(BALANCES_MEMORY_ID)),
+            allowances: StableBTreeMap::init(memory(ALLOWANCES_MEMORY_ID)),
+            allowance_expirations: StableBTreeMap::init(memory(ALLOWANCE_EXPIRATIONS_MEMORY_ID)),
+            recent_transactions: StableBTreeMap::init(memory(RECENT_TRANSACTIONS_MEMORY_ID)),
+            recent_transactions_by_time: StableBTreeMap::init(memory(RECENT_TRANSACTIONS_BY_TIME_MEMORY_ID)),
+            metadata: StableCell::init(memory(METADATA_MEMORY_ID), StoredMetadata::V2(Metadata::default()))
+                .expect("failed to initialize token metadata"),
+            vesting_buckets: StableBTreeMap::init(memory(VESTING_BUCKETS_MEMORY_ID)),
+            vesting_grants: StableBTreeMap::init(memory(VESTING_GRANTS_MEMORY_ID)),
+        }
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.metadata.get().clone().into_current()
+    }
+
+    /// Applies `f` to the metadata and stores the result as the current
+    /// version.
+    pub fn update_metadata(&mut self, f: impl FnOnce(&mut Metadata)) {
+        let mut metadata = self.metadata();
+        f(&mut metadata);
+        self.metadata
+            .set(StoredMetadata::V2(metadata))
+            .expect("failed to store token metadata");
+    }
+
+    pub fn fee(&self) -> u128 {
+        self.metadata().fee
+    }
+
+    /// `MAX_SUPPLY_TOKENS` whole tokens in base units.
+    pub fn max_supply(&self) -> u128 {
+        MAX_SUPPLY_TOKENS.saturating_mul(10u128.saturating_pow(self.metadata().decimals as u32))
+    }
+
+    /// Burns must be at least the transfer fee.
+    pub fn min_burn_amount(&self) -> u128 {
+        self.fee()
+    }
+
+    pub fn total_supply(&self) -> u128 {
+        self.metadata().total_supply
+    }
+
+    pub fn minting_account(&self) -> Option<AccountKey> {
+        self.metadata().minting_account
+    }
+
+    pub fn set_minting_account(&mut self, minting_account: Option<AccountKey>) {
+        self.update_metadata(|metadata| metadata.minting_account = minting_account);
+    }
+
+    pub fn fee_collector(&self) -> Option<AccountKey> {
+        self.metadata().fee_collector
+    }
+
+    pub fn set_fee_collector(&mut self, fee_collector: Option<AccountKey>) {
+        self.update_metadata(|metadata| metadata.fee_collector = fee_collector);
+    }
+
+    pub fn balance(&self, account: &AccountKey) -> u128 {
+        self.balances.get(account).map_or(0, |tokens| tokens.0)
+    }
+
+    /// The part of `account`'s balance not locked by vesting grants.
+    pub fn spendable_balance(&self, account: &AccountKey) -> u128 {
+        self.balance(account).saturating_sub(self.locked(account))
+    }
+
+    pub fn set_balance(&mut self, account: AccountKey, balance: u128) {
+        if balance == 0 {
+            self.balances.remove(&account);
+        } else {
+            self.balances.insert(account, Tokens(balance));
+        }
+    }
+
+    /// Takes `amount` out of `account`'s spendable balance and returns what
+    /// its balance becomes, without writing it.
+    fn debit(&self, account: &AccountKey, amount: u128) -> Result<u128, TransferError> {
+        let spendable = self.spendable_balance(account);
+        if amount > spendable {
+            return Err(TransferError::InsufficientFunds { balance: spendable });
+        }
+        Ok(self.balance(account) - amount)
+    }
+
+    /// Moves `amount` from `from` to `to` and takes `fee` from `from`. Both
+    /// balances are computed before either is written.
+    pub fn transfer(&mut self, from: AccountKey, to: AccountKey, amount: u128, fee: u128) -> Result<(), TransferError> {
+        let total_debit = amount.checked_add(fee).ok_or_else(overflow)?;
+        let new_from_balance = self.debit(&from, total_debit)?;
+        let to_balance = if to == from { new_from_balance } else { self.balance(&to) };
+        let new_to_balance = to_balance.checked_add(amount).ok_or_else(overflow)?;
+
+        self.set_balance(from, new_from_balance);
+        self.set_balance(to, new_to_balance);
+        self.collect_fee(fee);
+        Ok(())
+    }
+
+    /// Credits new tokens to `to`, up to the maximum supply.
+    pub fn mint(&mut self, to: AccountKey, amount: u128) -> Result<(), TransferError> {
+        let max_supply = self.max_supply();
+        let total_supply = self
+            .total_supply()
+            .checked_add(amount)
+            .filter(|supply| *supply <= max_supply)
+            .ok_or_else(|| TransferError::GenericError {
+                error_code: 4,
+                message: "Minting would exceed the maximum supply".to_string(),
+            })?;
+        let balance = self.balance(&to).checked_add(amount).ok_or_else(overflow)?;
+
+        self.set_balance(to, balance);
+        self.update_metadata(|metadata| metadata.total_supply = total_supply);
+        Ok(())
+    }
+
+    /// Destroys `amount` from `from`'s balance.
+    pub fn burn(&mut self, from: AccountKey, amount: u128) -> Result<(), TransferError> {
+        let min_burn_amount = self.min_burn_amount();
+        if amount < min_burn_amount {
+            return Err(TransferError::BadBurn { min_burn_amount });
+        }
+        let remaining = self.debit(&from, amount)?;
+        let total_supply = self.total_supply().checked_sub(amount).ok_or_else(overflow)?;
+
+        self.set_balance(from, remaining);
+        self.update_metadata(|metadata| metadata.total_supply = total_supply);
+        Ok(())
+    }
+
+    /// Sends a fee that was already debited to the fee collector, or burns
+    /// it. Fees come out of balances counted in the supply, so neither side
+    /// can overflow.
+    pub fn collect_fee(&mut self, fee: u128) {
+        match self.fee_collector() {
+            Some(collector) => {
+                let balance = self.balance(&collector).saturating_add(fee);
+                self.set_balance(collector, balance);
+            }
+            None => self.update_metadata(|metadata| metadata.total_supply = metadata.total_supply.saturating_sub(fee)),
+        }
+    }
+
+    /// Whether a transfer between `from` and `to` mints, burns or moves
+    /// tokens.
+    pub fn transfer_kind(&self, from: &AccountKey, to: &AccountKey) -> Result<TransactionKind, TransferError> {
+        let minting_account = self.minting_account();
+        match (Some(*from) == minting_account, Some(*to) == minting_account) {
+            (true, true) => Err(TransferError::GenericError {
+                error_code: 3,
+                message: "The minting account cannot transfer to itself".to_string(),
+            }),
+            (true, false) => Ok(TransactionKind::Mint),
+            (false, true) => Ok(TransactionKind::Burn { spender: None }),
+            (false, false) => Ok(TransactionKind::Transfer),
+        }
+    }
+
+    /// The allowance `spender` may still use, treating expired ones as zero.
+    pub fn allowance(&self, owner: &AccountKey, spender: &AccountKey, now: u64) -> Allowance {
+        self.allowances
+            .get(&AllowanceKey { owner: *owner, spender: *spender })
+            .filter(|allowance| allowance.expires_at.map_or(true, |expires_at| expires_at > now))
+            .unwrap_or(Allowance { allowance: 0, expires_at: None })
+    }
+
+    pub fn set_allowance(&mut self, owner: AccountKey, spender: AccountKey, allowance: Allowance) {
+        let key = AllowanceKey { owner, spender };
+        if let Some(expires_at) = self.allowances.get(&key).and_then(|old| old.expires_at) {
+            self.allowance_expirations.remove(&ExpirationKey { expires_at, owner, spender });
+        }
+        if allowance.allowance == 0 {
+            self.allowances.remove(&key);
+            return;
+        }
+        if let Some(expires_at) = allowance.expires_at {
+            self.allowance_expirations.insert(ExpirationKey { expires_at, owner, spender }, ());
+        }
+        self.allowances.insert(key, allowance);
+    }
+
+    /// Drops allowances that expired at or before `now`.
+    pub fn prune_expired_allowances(&mut self, now: u64) {
+        let expired: Vec<ExpirationKey> = self
+            .allowance_expirations
+            .iter()
+            .take_while(|(key, _)| key.expires_at <= now)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            self.allowance_expirations.remove(&key);
+            self.allowances.remove(&AllowanceKey { owner: key.owner, spender: key.spender });
+        }
+    }
+
+    /// Forgets transactions too old to be retried, then fails if `hash` was
+    /// already applied within the window.
+    pub fn check_duplicate(&mut self, hash: Option<&TxHash>, now: u64) -> Result<(), TransferError> {
+        let cutoff = now.saturating_sub(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS);
+        let expired: Vec<RecentKey> = self
+            .recent_transactions_by_time
+            .iter()
+            .take_while(|(key, _)| key.created_at_time < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            self.recent_transactions_by_time.remove(&key);
+            self.recent_transactions.remove(&TxKey(key.hash));
+        }
+
+        match hash.and_then(|hash| self.recent_transactions.get(&TxKey(*hash))) {
+            Some(duplicate) => Err(TransferError::Duplicate { duplicate_of: duplicate.block_index }),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends `tx` to the block log and remembers its hash for
+    /// deduplication.
+    pub fn record(&mut self, tx: Transaction, dedup: Option<(TxHash, u64)>) -> u128 {
+        let fee_collector = self.fee_collector().filter(|_| tx.fee > 0).map(|collector| collector.account());
+        let block_index = icrc3::append(|parent| tx.to_block(parent, fee_collector.as_ref())) as u128;
+        if let Some((hash, created_at_time)) = dedup {
+            self.recent_transactions
+                .insert(TxKey(hash), RecentTransaction { block_index, created_at_time });
+            self.recent_transactions_by_time
+                .insert(RecentKey { created_at_time, hash }, ());
+        }
+        block_index
+    }
+
+    /// Charges `fee` to `account`, e.g. for an approval, and collects it.
+    pub fn charge_fee(&mut self, account: AccountKey, fee: u128) -> Result<(), TransferError> {
+        let remaining = self.debit(&account, fee)?;
+        self.set_balance(account, remaining);
+        self.collect_fee(fee);
+        Ok(())
+    }
+
+    /// Changes the transfer fee and appends the change to the block log.
+    pub fn set_fee(&mut self, fee: u128, changed_by: Principal, now: u64) -> u128 {
+        self.update_metadata(|metadata| metadata.fee = fee);
+        icrc3::append(|parent| fee_change_block(parent, fee, changed_by, now)) as u128
+    }
+}
+
+// Vesting
+impl State {
+    pub fn vesting_buckets(&self) -> Vec<(Bucket, VestingBucket)> {
+        self.vesting_buckets.iter().collect()
+    }
+
+    /// Sets a bucket's allocation and the schedule its future grants follow.
+    /// Existing grants keep the schedule they were made with.
+    pub fn set_vesting_bucket(&mut self, bucket: Bucket, allocation: u128, schedule: VestingSchedule) -> Result<(), String> {
+        schedule.validate()?;
+        let granted = self.vesting_buckets.get(&bucket).map_or(0, |existing| existing.granted);
+        if allocation < granted {
+            return Err(format!("{:?} has already granted {}, more than the new allocation", bucket, granted));
+        }
+        self.vesting_buckets.insert(bucket, VestingBucket { allocation, granted, schedule });
+        Ok(())
+    }
+
+    /// Mints `amount` from `bucket` to `beneficiary`, locked until it vests
+    /// and is released. Each beneficiary holds at most one grant per bucket.
+    pub fn grant(&mut self, bucket: Bucket, beneficiary: AccountKey, amount: u128) -> Result<(), String> {
+        let mut config = self
+            .vesting_buckets
+            .get(&bucket)
+            .ok_or_else(|| format!("{:?} has no allocation", bucket))?;
+        if Some(beneficiary) == self.minting_account() {
+            return Err("The minting account cannot receive grants".to_string());
+        }
+        let remaining = config.allocation - config.granted;
+        if amount == 0 || amount > remaining {
+            return Err(format!("Grant must be between 1 and the {} left in {:?}", remaining, bucket));
+        }
+        let key = GrantKey { beneficiary, bucket };
+        if self.vesting_grants.contains_key(&key) {
+            return Err(format!("Beneficiary already has a grant from {:?}", bucket));
+        }
+        self.mint(beneficiary, amount)
+            .map_err(|_| "Grant would exceed the maximum supply".to_string())?;
+
+        let schedule = config.schedule;
+        config.granted += amount;
+        self.vesting_buckets.insert(bucket, config);
+        self.vesting_grants.insert(key, Grant { total: amount, released: 0, schedule });
+        Ok(())
+    }
+
+    fn grants_of(&self, beneficiary: AccountKey) -> impl Iterator<Item = (Bucket, Grant)> + '_ {
+        self.vesting_grants
+            .range(GrantKey { beneficiary, bucket: Bucket::Team }..)
+            .take_while(move |(key, _)| key.beneficiary == beneficiary)
+            .map(|(key, grant)| (key.bucket, grant))
+    }
+
+    /// Granted tokens in `account`'s balance that haven't been released.
+    pub fn locked(&self, account: &AccountKey) -> u128 {
+        self.grants_of(*account).map(|(_, grant)| grant.locked()).sum()
+    }
+
+    /// Unlocks everything that has vested in `beneficiary`'s grants and
+    /// returns the amount released.
+    pub fn release(&mut self, beneficiary: AccountKey, now: u64) -> u128 {
+        let grants: Vec<(Bucket, Grant)> = self.grants_of(beneficiary).collect();
+        let mut released = 0;
+        for (bucket, mut grant) in grants {
+            let releasable = grant.releasable(now);
+            if releasable == 0 {
+                continue;
+            }
+            grant.released += releasable;
+            released += releasable;
+            self.vesting_grants.insert(GrantKey { beneficiary, bucket }, grant);
+        }
+        released
+    }
+
+    pub fn vesting_status(&self, beneficiary: AccountKey, now: u64) -> VestingStatus {
+        self.grants_of(beneficiary)
+            .map(|(bucket, grant)| grant.status(bucket, now))
+            .collect()
+    }
+}
+
+/// A balance, stored as 16 big-endian bytes.
+struct Tokens(u128);
+
+impl Storable for Tokens {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(u128::from_be_bytes(bytes.as_ref().try_into().expect("balance bytes")))
+    }
+}
+
+impl BoundedStorable for Tokens {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl AccountKey {
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        let owner = self.owner.as_slice();
+        let mut padded = [0u8; 29];
+        padded[..owner.len()].copy_from_slice(owner);
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(&padded);
+        bytes.extend_from_slice(&self.subaccount);
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            subaccount: bytes[30..ACCOUNT_KEY_SIZE].try_into().expect("account key subaccount"),
+        }
+    }
+}
+
+impl Storable for AccountKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(ACCOUNT_KEY_SIZE);
+        self.write_bytes(&mut bytes);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::read_bytes(&bytes)
+    }
+}
+
+impl BoundedStorable for AccountKey {
+    const MAX_SIZE: u32 = ACCOUNT_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Key: (owner, spender)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct AllowanceKey {
+    owner: AccountKey,
+    spender: AccountKey,
+}
+
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(2 * ACCOUNT_KEY_SIZE);
+        self.owner.write_bytes(&mut bytes);
+        self.spender.write_bytes(&mut bytes);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            owner: AccountKey::read_bytes(&bytes[..ACCOUNT_KEY_SIZE]),
+            spender: AccountKey::read_bytes(&bytes[ACCOUNT_KEY_SIZE..]),
+        }
+    }
+}
+
+impl BoundedStorable for AllowanceKey {
+    const MAX_SIZE: u32 = 2 * ACCOUNT_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Allowances ordered by expiry, so pruning stops at the first live one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ExpirationKey {
+    expires_at: u64,
+    owner: AccountKey,
+    spender: AccountKey,
+}
+
+impl Storable for ExpirationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(8 + 2 * ACCOUNT_KEY_SIZE);
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        self.owner.write_bytes(&mut bytes);
+        self.spender.write_bytes(&mut bytes);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (expires_at, accounts) = bytes.split_at(8);
+        Self {
+            expires_at: u64::from_be_bytes(expires_at.try_into().expect("expiration key timestamp")),
+            owner: AccountKey::read_bytes(&accounts[..ACCOUNT_KEY_SIZE]),
+            spender: AccountKey::read_bytes(&accounts[ACCOUNT_KEY_SIZE..]),
+        }
+    }
+}
+
+impl BoundedStorable for ExpirationKey {
+    const MAX_SIZE: u32 = 8 + 2 * ACCOUNT_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TxKey(TxHash);
+
+impl Storable for TxKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.as_ref().try_into().expect("transaction hash"))
+    }
+}
+
+impl BoundedStorable for TxKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Recent transactions ordered by `created_at_time`, for expiring the
+/// deduplication window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RecentKey {
+    created_at_time: u64,
+    hash: TxHash,
+}
+
+impl Storable for RecentKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(40);
+        bytes.extend_from_slice(&self.created_at_time.to_be_bytes());
+        bytes.extend_from_slice(&self.hash);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (created_at_time, hash) = bytes.split_at(8);
+        Self {
+            created_at_time: u64::from_be_bytes(created_at_time.try_into().expect("recent key timestamp")),
+            hash: hash.try_into().expect("recent key hash"),
+        }
+    }
+}
+
+impl BoundedStorable for RecentKey {
+    const MAX_SIZE: u32 = 40;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for Allowance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode allowance"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode allowance")
+    }
+}
+
+impl BoundedStorable for Allowance {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RecentTransaction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode recent transaction"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode recent transaction")
+    }
+}
+
+impl BoundedStorable for RecentTransaction {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for StoredMetadata {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode token metadata"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode token metadata")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    const TRANSFER_FEE: u128 = 10_000;
+
+    fn configure(state: &mut State) {
+        state.update_metadata(|metadata| {
+            metadata.name = "ANIMA Token".to_string();
+            metadata.symbol = "ANIMA".to_string();
+            metadata.decimals = 8;
+            metadata.fee = TRANSFER_FEE;
+        });
+    }
+
+    fn fresh_state() -> State {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut state = State::init(|id| memory_manager.get(id));
+        configure(&mut state);
+        state
+    }
+
+    #[test]
+    fn test_account_key_round_trips() {
+        let mut subaccount = [0u8; 32];
+        subaccount[0] = 7;
+        for owner in [Principal::anonymous(), Principal::management_canister(), Principal::from_slice(&[1; 29])] {
+            let key = AccountKey { owner, subaccount };
+            assert_eq!(AccountKey::from_bytes(key.to_bytes()), key);
+        }
+    }
+
+    #[test]
+    fn test_expired_allowances_are_pruned() {
+        let owner = AccountKey::of(Principal::anonymous());
+        let spender = AccountKey::of(Principal::management_canister());
+        let mut state = fresh_state();
+        state.set_allowance(owner, spender, Allowance { allowance: 500, expires_at: Some(10) });
+
+        assert_eq!(state.allowance(&owner, &spender, 9).allowance, 500);
+        assert_eq!(state.allowance(&owner, &spender, 10).allowance, 0);
+
+        state.prune_expired_allowances(10);
+        assert!(state.allowances.is_empty());
+        assert!(state.allowance_expirations.is_empty());
+    }
+
+    #[test]
+    fn test_failed_transfer_leaves_balances_untouched() {
+        let from = AccountKey::of(Principal::anonymous());
+        let to = AccountKey::of(Principal::management_canister());
+        let mut state = fresh_state();
+        state.set_balance(from, 100);
+        state.set_balance(to, u128::MAX);
+
+        assert!(state.transfer(from, to, 50, 10).is_err());
+        assert_eq!(state.balance(&from), 100);
+        assert!(matches!(
+            state.transfer(from, to, 95, 10),
+            Err(TransferError::InsufficientFunds { balance: 100 })
+        ));
+    }
+
+    #[test]
+    fn test_fees_are_burned_without_a_collector() {
+        let from = AccountKey::of(Principal::anonymous());
+        let to = AccountKey::of(Principal::management_canister());
+        let collector = AccountKey::of(Principal::from_slice(&[1; 29]));
+        let mut state = fresh_state();
+        state.mint(from, 1_000_000).unwrap();
+
+        state.transfer(from, to, 100, TRANSFER_FEE).unwrap();
+        assert_eq!(state.total_supply(), 1_000_000 - TRANSFER_FEE);
+
+        state.set_fee_collector(Some(collector));
+        state.transfer(from, to, 100, TRANSFER_FEE).unwrap();
+        assert_eq!(state.balance(&collector), TRANSFER_FEE);
+        assert_eq!(state.total_supply(), 1_000_000 - TRANSFER_FEE);
+
+        let held: u128 = state.balances.iter().map(|(_, tokens)| tokens.0).sum();
+        assert_eq!(held, state.total_supply());
+    }
+
+    #[test]
+    fn test_mint_and_burn_limits() {
+        let holder = AccountKey::of(Principal::anonymous());
+        let mut state = fresh_state();
+
+        let max_supply = state.max_supply();
+        assert_eq!(max_supply, 1_000_000_000 * 100_000_000);
+
+        assert!(state.mint(holder, max_supply + 1).is_err());
+        state.mint(holder, max_supply).unwrap();
+        assert!(matches!(
+            state.burn(holder, TRANSFER_FEE - 1),
+            Err(TransferError::BadBurn { .. })
+        ));
+        state.burn(holder, TRANSFER_FEE).unwrap();
+        assert_eq!(state.total_supply(), max_supply - TRANSFER_FEE);
+    }
+
+    #[test]
+    fn test_state_survives_an_upgrade() {
+        let minter = AccountKey::of(Principal::from_slice(&[9; 29]));
+        let alice = AccountKey::of(Principal::anonymous());
+        let bob = AccountKey::of(Principal::management_canister());
+        let stable_memory = DefaultMemoryImpl::default();
+
+        // Install, mint and transfer
+        {
+            let memory_manager = MemoryManager::init(stable_memory.clone());
+            let mut state = State::init(|id| memory_manager.get(id));
+            configure(&mut state);
+            state.set_minting_account(Some(minter));
+            state.mint(alice, 1_000_000).unwrap();
+            state.transfer(alice, bob, 250_000, TRANSFER_FEE).unwrap();
+            state.set_allowance(alice, bob, Allowance { allowance: 5_000, expires_at: Some(100) });
+        }
+
+        // Upgrade: the heap is gone and only stable memory remains
+        let memory_manager = MemoryManager::init(stable_memory);
+        let state = State::init(|id| memory_manager.get(id));
+
+        assert_eq!(state.balance(&alice), 1_000_000 - 250_000 - TRANSFER_FEE);
+        assert_eq!(state.balance(&bob), 250_000);
+        assert_eq!(state.total_supply(), 1_000_000 - TRANSFER_FEE);
+        assert_eq!(state.minting_account(), Some(minter));
+        assert_eq!(state.allowance(&alice, &bob, 50).allowance, 5_000);
+        assert_eq!(state.allowance_expirations.len(), 1);
+        assert_eq!(state.metadata().symbol, "ANIMA");
+    }
+
+    #[test]
+    fn test_locked_grants_cannot_be_spent() {
+        let beneficiary = AccountKey::of(Principal::anonymous());
+        let other = AccountKey::of(Principal::management_canister());
+        let schedule = VestingSchedule { start: 0, cliff_nanos: 100, duration_nanos: 400 };
+        let mut state = fresh_state();
+        state.set_vesting_bucket(Bucket::Team, 1_000_000, schedule).unwrap();
+        state.grant(Bucket::Team, beneficiary, 1_000_000).unwrap();
+        assert!(state.grant(Bucket::Team, other, 1).is_err());
+
+        // Granted tokens are in the balance but locked
+        assert_eq!(state.balance(&beneficiary), 1_000_000);
+        assert!(matches!(
+            state.transfer(beneficiary, other, 1, TRANSFER_FEE),
+            Err(TransferError::InsufficientFunds { balance: 0 })
+        ));
+        assert!(state.burn(beneficiary, TRANSFER_FEE).is_err());
+        assert!(state.charge_fee(beneficiary, TRANSFER_FEE).is_err());
+
+        // Vested tokens stay locked until released
+        assert_eq!(state.release(beneficiary, 99), 0);
+        assert_eq!(state.vesting_status(beneficiary, 200).vested, 500_000);
+        assert_eq!(state.spendable_balance(&beneficiary), 0);
+        assert_eq!(state.release(beneficiary, 200), 500_000);
+        assert_eq!(state.release(beneficiary, 200), 0);
+
+        state.transfer(beneficiary, other, 400_000, TRANSFER_FEE).unwrap();
+        assert!(state.transfer(beneficiary, other, 100_000, TRANSFER_FEE).is_err());
+
+        let status = state.vesting_status(beneficiary, 400);
+        assert_eq!((status.vested, status.released, status.locked), (1_000_000, 500_000, 500_000));
+    }
+
+    #[test]
+    fn test_v1_metadata_migrates_to_the_former_constants() {
+        let minter = AccountKey::of(Principal::anonymous());
+        let stored = StoredMetadata::V1(MetadataV1 {
+            total_supply: 42,
+            minting_account: Some(minter),
+            fee_collector: None,
+        });
+
+        let metadata = StoredMetadata::from_bytes(stored.to_bytes()).into_current();
+        assert_eq!(metadata.symbol, "ANIMA");
+        assert_eq!(metadata.decimals, 8);
+        assert_eq!(metadata.fee, TRANSFER_FEE);
+        assert_eq!(metadata.total_supply, 42);
+        assert_eq!(metadata.minting_account, Some(minter));
+    }
+}
@@ -0,0 +1,247 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+use crate::state::ACCOUNT_KEY_SIZE;
+use crate::AccountKey;
+
+/// Allocation buckets that vested supply is granted from.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bucket {
+    Team,
+    Treasury,
+    Rewards,
+    Community,
+}
+
+impl Bucket {
+    const ALL: [Bucket; 4] = [Bucket::Team, Bucket::Treasury, Bucket::Rewards, Bucket::Community];
+
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        Self::ALL[byte as usize]
+    }
+}
+
+/// Nothing vests before `start + cliff_nanos`; from then on the grant vests
+/// linearly from `start` and is fully vested at `start + duration_nanos`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VestingSchedule {
+    pub start: u64,
+    pub cliff_nanos: u64,
+    pub duration_nanos: u64,
+}
+
+impl VestingSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cliff_nanos > self.duration_nanos {
+            return Err("The cliff cannot be longer than the vesting duration".to_string());
+        }
+        Ok(())
+    }
+
+    /// How much of `total` has vested at `now`.
+    pub fn vested(&self, total: u128, now: u64) -> u128 {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed < self.cliff_nanos {
+            return 0;
+        }
+        if elapsed >= self.duration_nanos {
+            return total;
+        }
+        // total * elapsed / duration, split so no step overflows: with
+        // total = q * duration + r, both q * elapsed and r * elapsed fit
+        let (elapsed, duration) = (elapsed as u128, self.duration_nanos as u128);
+        let (q, r) = (total / duration, total % duration);
+        (q * elapsed + r * elapsed / duration).min(total)
+    }
+}
+
+/// A bucket's allocation and the schedule new grants from it follow.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct VestingBucket {
+    pub allocation: u128,
+    pub granted: u128,
+    pub schedule: VestingSchedule,
+}
+
+/// Tokens granted to one beneficiary from one bucket. The whole grant is
+/// minted to the beneficiary up front; what hasn't been released stays
+/// locked in their balance.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Grant {
+    pub total: u128,
+    pub released: u128,
+    pub schedule: VestingSchedule,  // Copied from the bucket when granted
+}
+
+impl Grant {
+    pub fn locked(&self) -> u128 {
+        self.total.saturating_sub(self.released)
+    }
+
+    /// Vested tokens that haven't been released yet.
+    pub fn releasable(&self, now: u64) -> u128 {
+        self.schedule.vested(self.total, now).saturating_sub(self.released)
+    }
+
+    pub fn status(&self, bucket: Bucket, now: u64) -> GrantStatus {
+        GrantStatus {
+            bucket,
+            total: self.total,
+            vested: self.schedule.vested(self.total, now),
+            released: self.released,
+            locked: self.locked(),
+            schedule: self.schedule,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GrantStatus {
+    pub bucket: Bucket,
+    pub total: u128,
+    pub vested: u128,
+    pub released: u128,
+    pub locked: u128,
+    pub schedule: VestingSchedule,
+}
+
+/// A beneficiary's grants and their totals.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct VestingStatus {
+    pub vested: u128,
+    pub released: u128,
+    pub locked: u128,
+    pub grants: Vec<GrantStatus>,
+}
+
+impl FromIterator<GrantStatus> for VestingStatus {
+    fn from_iter<I: IntoIterator<Item = GrantStatus>>(grants: I) -> Self {
+        let mut status = VestingStatus::default();
+        for grant in grants {
+            status.vested += grant.vested;
+            status.released += grant.released;
+            status.locked += grant.locked;
+            status.grants.push(grant);
+        }
+        status
+    }
+}
+
+// Key: (beneficiary, bucket), so one account's grants are a single range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GrantKey {
+    pub beneficiary: AccountKey,
+    pub bucket: Bucket,
+}
+
+impl Storable for GrantKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.beneficiary.to_bytes().into_owned();
+        bytes.push(self.bucket.to_byte());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            beneficiary: AccountKey::from_bytes(Cow::Borrowed(&bytes[..ACCOUNT_KEY_SIZE])),
+            bucket: Bucket::from_byte(bytes[ACCOUNT_KEY_SIZE]),
+        }
+    }
+}
+
+impl BoundedStorable for GrantKey {
+    const MAX_SIZE: u32 = ACCOUNT_KEY_SIZE as u32 + 1;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for Bucket {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(vec![self.to_byte()])
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::from_byte(bytes[0])
+    }
+}
+
+impl BoundedStorable for Bucket {
+    const MAX_SIZE: u32 = 1;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for VestingBucket {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode vesting bucket"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode vesting bucket")
+    }
+}
+
+impl BoundedStorable for VestingBucket {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Grant {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode vesting grant"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode vesting grant")
+    }
+}
+
+impl BoundedStorable for Grant {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YEAR: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn test_nothing_vests_before_the_cliff() {
+        let schedule = VestingSchedule { start: 1_000, cliff_nanos: YEAR, duration_nanos: 4 * YEAR };
+        assert_eq!(schedule.vested(4_000, 0), 0);
+        assert_eq!(schedule.vested(4_000, 1_000 + YEAR - 1), 0);
+        // At the cliff, the first year vests at once
+        assert_eq!(schedule.vested(4_000, 1_000 + YEAR), 1_000);
+        assert_eq!(schedule.vested(4_000, 1_000 + 2 * YEAR), 2_000);
+        assert_eq!(schedule.vested(4_000, 1_000 + 10 * YEAR), 4_000);
+    }
+
+    #[test]
+    fn test_zero_duration_vests_immediately() {
+        let schedule = VestingSchedule { start: 5, cliff_nanos: 0, duration_nanos: 0 };
+        assert_eq!(schedule.vested(100, 5), 100);
+        assert!(VestingSchedule { start: 0, cliff_nanos: 2, duration_nanos: 1 }.validate().is_err());
+    }
+
+    #[test]
+    fn test_large_totals_vest_without_overflow() {
+        let schedule = VestingSchedule { start: 0, cliff_nanos: 0, duration_nanos: 4 * YEAR };
+        assert_eq!(schedule.vested(u128::MAX, 2 * YEAR), u128::MAX / 2);
+        assert!(schedule.vested(u128::MAX, 4 * YEAR - 1) < u128::MAX);
+        assert_eq!(schedule.vested(u128::MAX, 4 * YEAR), u128::MAX);
+    }
+
+    #[test]
+    fn test_grant_key_round_trips() {
+        let key = GrantKey {
+            beneficiary: AccountKey::of(candid::Principal::from_slice(&[3; 29])),
+            bucket: Bucket::Community,
+        };
+        assert_eq!(GrantKey::from_bytes(key.to_bytes()), key);
+    }
+}
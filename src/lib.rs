@@ -21,6 +21,13 @@ mod neural;
 
 use quantum::QuantumState;
 use error::Result;
+use payments::maintenance::{MaintenanceConfig, MaintenanceDebit, MaintenanceStatus};
+use payments::mint_phases::{DiscountCode, MintPhase};
+use payments::paid_mint::{PaidMintReceipt, PaidMintRequest};
+use payments::receipts::RecordedReceipt;
+use payments::reconciliation::ReconciliationReport;
+use payments::referrals::{ReferralAccountView, ReferralConfig};
+use payments::transaction_processor::{CheckoutConfig, CheckoutOptions, PaymentReceipt};
 // Removed unused NeuralSignature import
 
 #[derive(CandidType, Deserialize)]
@@ -50,6 +57,16 @@ thread_local! {
     );
 }
 
+#[ic_cdk::init]
+fn init() {
+    payments::maintenance::start_billing_timer();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    payments::maintenance::start_billing_timer();
+}
+
 #[ic_cdk::update]
 async fn initialize_genesis() -> Result<AnimaCreationResult> {
     let timestamp = time();
@@ -105,4 +122,135 @@ fn generate_base_patterns() -> Vec<f64> {
 #[ic_cdk::query]
 fn get_quantum_state() -> Result<QuantumState> {
     Ok(QuantumState::default())
-}
\ No newline at end of file
+}
+
+#[ic_cdk::update]
+async fn mint_paid_anima(request: PaidMintRequest) -> std::result::Result<PaidMintReceipt, String> {
+    payments::paid_mint::mint_for_session(request)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn get_paid_mint(session_id: String) -> Option<PaidMintReceipt> {
+    payments::paid_mint::receipt(&session_id)
+}
+
+#[ic_cdk::update]
+fn set_payment_canister(canister: candid::Principal) -> Result<()> {
+    payments::paid_mint::set_payment_canister(canister)
+}
+
+#[ic_cdk::update]
+async fn checkout_mint(
+    token_ledger: candid::Principal,
+    created_at_time: u64,
+    options: CheckoutOptions,
+) -> std::result::Result<PaymentReceipt, String> {
+    payments::transaction_processor::checkout(token_ledger, created_at_time, options)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[ic_cdk::query]
+fn get_checkout_config() -> Option<CheckoutConfig> {
+    payments::transaction_processor::checkout_config()
+}
+
+#[ic_cdk::update]
+fn set_checkout_config(config: CheckoutConfig) -> Result<()> {
+    payments::transaction_processor::set_checkout_config(config)
+}
+
+#[ic_cdk::query]
+fn get_mint_phases() -> Vec<MintPhase> {
+    payments::mint_phases::phases()
+}
+
+#[ic_cdk::update]
+fn set_mint_phases(phases: Vec<MintPhase>) -> Result<()> {
+    payments::mint_phases::set_phases(phases)
+}
+
+#[ic_cdk::query]
+fn get_discount_codes() -> Result<Vec<DiscountCode>> {
+    payments::mint_phases::discount_codes()
+}
+
+#[ic_cdk::update]
+fn upsert_discount_code(code: DiscountCode) -> Result<()> {
+    payments::mint_phases::upsert_discount_code(code)
+}
+
+#[ic_cdk::query]
+fn get_maintenance_config() -> MaintenanceConfig {
+    payments::maintenance::config()
+}
+
+#[ic_cdk::update]
+fn set_maintenance_config(config: MaintenanceConfig) -> Result<()> {
+    payments::maintenance::set_config(config)
+}
+
+#[ic_cdk::query]
+fn get_maintenance_status(token_id: String) -> Option<MaintenanceStatus> {
+    payments::maintenance::status(&token_id)
+}
+
+#[ic_cdk::query]
+fn get_maintenance_debits(token_id: String, offset: u64, limit: u64) -> Vec<MaintenanceDebit> {
+    payments::maintenance::debits(&token_id, offset, limit)
+}
+
+#[ic_cdk::update]
+async fn fund_maintenance(token_id: String, amount: u64) -> Result<MaintenanceStatus> {
+    payments::maintenance::fund(token_id, amount).await
+}
+
+#[ic_cdk::update]
+async fn cancel_maintenance(token_id: String) -> Result<Option<u64>> {
+    payments::maintenance::cancel(token_id).await
+}
+
+#[ic_cdk::query]
+fn get_referral_config() -> ReferralConfig {
+    payments::referrals::config()
+}
+
+#[ic_cdk::update]
+fn set_referral_config(config: ReferralConfig) -> Result<()> {
+    payments::referrals::set_config(config)
+}
+
+#[ic_cdk::query]
+fn get_my_referral_accounts() -> Vec<ReferralAccountView> {
+    payments::referrals::accounts_of(ic_cdk::caller())
+}
+
+#[ic_cdk::query]
+fn get_referral_accounts() -> Result<Vec<ReferralAccountView>> {
+    payments::referrals::accounts()
+}
+
+#[ic_cdk::update]
+async fn claim_referral_rewards(ledger: candid::Principal) -> Result<u64> {
+    payments::referrals::claim(ledger).await
+}
+
+#[ic_cdk::query]
+fn get_payment_receipts(from: u64, to: u64, offset: u64, limit: u64) -> Result<Vec<RecordedReceipt>> {
+    payments::reconciliation::require_controller()?;
+    Ok(payments::receipts::in_range(from, to, offset, limit))
+}
+
+#[ic_cdk::query]
+fn export_payment_receipts_csv(from: u64, to: u64, offset: u64, limit: u64) -> Result<String> {
+    payments::reconciliation::require_controller()?;
+    Ok(payments::receipts::export_csv(from, to, offset, limit))
+}
+
+#[ic_cdk::update]
+async fn reconcile_payments(from: u64, to: u64, offset: u64, limit: u64) -> Result<ReconciliationReport> {
+    payments::reconciliation::require_controller()?;
+    Ok(payments::reconciliation::reconcile(from, to, offset, limit).await)
+}
@@ -0,0 +1,123 @@
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use super::session_store::{self, Memory, ADMINS_MEMORY_ID, PENDING_ADMINS_MEMORY_ID};
+
+const PENDING_ADMIN_TTL_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days
+
+// Custom wrapper for Principal to implement BoundedStorable
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StorablePrincipal(Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Principal::from_slice(&bytes))
+    }
+}
+
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static ADMINS: RefCell<StableBTreeMap<StorablePrincipal, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(session_store::memory(ADMINS_MEMORY_ID))
+    );
+
+    // Proposed admin -> time of the proposal
+    static PENDING_ADMINS: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(session_store::memory(PENDING_ADMINS_MEMORY_ID))
+    );
+}
+
+/// Seeds the admin list with `principal` if no admin exists yet.
+pub fn seed(principal: Principal) {
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        if admins.is_empty() && principal != Principal::anonymous() {
+            admins.insert(StorablePrincipal(principal), ());
+        }
+    });
+}
+
+/// Controllers are always admins so that a lost admin list can be recovered.
+pub fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || ADMINS.with(|admins| admins.borrow().contains_key(&StorablePrincipal(*principal)))
+}
+
+pub fn require_admin() -> Result<(), String> {
+    if is_admin(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Caller is not an admin".to_string())
+    }
+}
+
+/// First step of adding an admin: the candidate must call `accept` to
+/// complete it, which proves they control the principal.
+pub fn propose(candidate: Principal) -> Result<(), String> {
+    if candidate == Principal::anonymous() {
+        return Err("The anonymous principal cannot be an admin".to_string());
+    }
+    if is_admin(&candidate) {
+        return Err("Principal is already an admin".to_string());
+    }
+
+    PENDING_ADMINS.with(|pending| {
+        pending.borrow_mut().insert(StorablePrincipal(candidate), time());
+    });
+    Ok(())
+}
+
+pub fn accept(candidate: Principal) -> Result<(), String> {
+    let proposed_at = PENDING_ADMINS
+        .with(|pending| pending.borrow_mut().remove(&StorablePrincipal(candidate)))
+        .ok_or("No pending admin proposal for caller")?;
+
+    if time().saturating_sub(proposed_at) > PENDING_ADMIN_TTL_NANOS {
+        return Err("Admin proposal has expired".to_string());
+    }
+
+    ADMINS.with(|admins| admins.borrow_mut().insert(StorablePrincipal(candidate), ()));
+    Ok(())
+}
+
+pub fn cancel(candidate: Principal) -> Result<(), String> {
+    PENDING_ADMINS
+        .with(|pending| pending.borrow_mut().remove(&StorablePrincipal(candidate)))
+        .map(|_| ())
+        .ok_or_else(|| "No pending admin proposal for principal".to_string())
+}
+
+pub fn remove(admin: Principal) -> Result<(), String> {
+    ADMINS.with(|admins| {
+        let mut admins = admins.borrow_mut();
+        // Prevent removing the last admin
+        if admins.len() <= 1 && admins.contains_key(&StorablePrincipal(admin)) {
+            return Err("Cannot remove the last admin".to_string());
+        }
+        admins
+            .remove(&StorablePrincipal(admin))
+            .map(|_| ())
+            .ok_or_else(|| "Admin not found".to_string())
+    })
+}
+
+pub fn list() -> Vec<Principal> {
+    ADMINS.with(|admins| admins.borrow().iter().map(|(admin, _)| admin.0).collect())
+}
+
+pub fn pending() -> Vec<(Principal, u64)> {
+    PENDING_ADMINS.with(|pending| {
+        pending.borrow().iter().map(|(candidate, proposed_at)| (candidate.0, proposed_at)).collect()
+    })
+}
@@ -0,0 +1,128 @@
+use ic_ledger_types::{AccountIdentifier, Block, Memo, Operation, Tokens};
+
+/// What a ledger block must contain to count as payment for a session.
+#[derive(Clone, Debug)]
+pub struct ExpectedTransfer {
+    pub to: AccountIdentifier,
+    pub amount: u64,
+    pub memo: u64,
+    pub not_after: u64,
+}
+
+/// A transfer that satisfied an `ExpectedTransfer`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifiedTransfer {
+    pub from: AccountIdentifier,
+    pub amount: u64,
+}
+
+/// Checks that a block is a transfer to the expected account with the
+/// expected memo and at least the expected amount. Only the legacy `transfer`
+/// memo is matched; ic-ledger-types 0.8 does not expose ICRC-1 memos.
+pub fn match_transfer(block: &Block, expected: &ExpectedTransfer) -> Result<VerifiedTransfer, String> {
+    let (from, to, amount) = match &block.transaction.operation {
+        Some(Operation::Transfer { from, to, amount, .. }) => (*from, *to, *amount),
+        _ => return Err("Block is not a transfer".to_string()),
+    };
+
+    if to != expected.to {
+        return Err("Transfer recipient does not match the session deposit account".to_string());
+    }
+
+    if block.transaction.memo != Memo(expected.memo) {
+        return Err(format!(
+            "Transfer memo {} does not match session memo {}",
+            block.transaction.memo.0, expected.memo
+        ));
+    }
+
+    if amount < Tokens::from_e8s(expected.amount) {
+        return Err(format!(
+            "Transfer amount {} is below the required {}",
+            amount.e8s(),
+            expected.amount
+        ));
+    }
+
+    if block.timestamp.timestamp_nanos > expected.not_after {
+        return Err("Transfer was made after the session expired".to_string());
+    }
+
+    Ok(VerifiedTransfer { from, amount: amount.e8s() })
+}
+
+/// The sender of a transfer into `to`. Used to return deposits that missed
+/// their session, so unlike `match_transfer` it checks neither the memo,
+/// the amount nor when the transfer was made.
+pub fn transfer_sender(block: &Block, to: AccountIdentifier) -> Result<AccountIdentifier, String> {
+    match &block.transaction.operation {
+        Some(Operation::Transfer { from, to: recipient, .. }) if *recipient == to => Ok(*from),
+        Some(Operation::Transfer { .. }) => {
+            Err("Transfer recipient does not match the session deposit account".to_string())
+        }
+        _ => Err("Block is not a transfer".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use ic_ledger_types::{Subaccount, Timestamp, Transaction, DEFAULT_SUBACCOUNT};
+
+    fn deposit_account() -> AccountIdentifier {
+        AccountIdentifier::new(&Principal::management_canister(), &Subaccount([7; 32]))
+    }
+
+    fn payer_account() -> AccountIdentifier {
+        AccountIdentifier::new(&Principal::anonymous(), &DEFAULT_SUBACCOUNT)
+    }
+
+    fn transfer_block(to: AccountIdentifier, amount: u64, memo: u64, timestamp: u64) -> Block {
+        Block {
+            parent_hash: None,
+            transaction: Transaction {
+                memo: Memo(memo),
+                operation: Some(Operation::Transfer {
+                    from: payer_account(),
+                    to,
+                    amount: Tokens::from_e8s(amount),
+                    fee: Tokens::from_e8s(10_000),
+                }),
+                created_at_time: Timestamp { timestamp_nanos: timestamp },
+            },
+            timestamp: Timestamp { timestamp_nanos: timestamp },
+        }
+    }
+
+    fn expected() -> ExpectedTransfer {
+        ExpectedTransfer { to: deposit_account(), amount: 100_000_000, memo: 42, not_after: 1_000 }
+    }
+
+    #[test]
+    fn test_matching_transfer_is_accepted() {
+        let block = transfer_block(deposit_account(), 100_000_000, 42, 500);
+        let verified = match_transfer(&block, &expected()).unwrap();
+        assert_eq!(verified.from, payer_account());
+        assert_eq!(verified.amount, 100_000_000);
+    }
+
+    #[test]
+    fn test_mismatched_transfers_are_rejected() {
+        let wrong_recipient = transfer_block(payer_account(), 100_000_000, 42, 500);
+        let wrong_memo = transfer_block(deposit_account(), 100_000_000, 7, 500);
+        let underpaid = transfer_block(deposit_account(), 99_999_999, 42, 500);
+        let late = transfer_block(deposit_account(), 100_000_000, 42, 1_001);
+
+        for block in [wrong_recipient, wrong_memo, underpaid, late] {
+            assert!(match_transfer(&block, &expected()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_late_transfer_sender_is_found() {
+        let late = transfer_block(deposit_account(), 5_000, 7, 1_001);
+        assert_eq!(transfer_sender(&late, deposit_account()).unwrap(), payer_account());
+        assert!(transfer_sender(&late, payer_account()).is_err());
+    }
+}
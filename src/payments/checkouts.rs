@@ -0,0 +1,133 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use super::icrc2::BlockIndex;
+use super::mint_phases::Admission;
+use super::referrals::ReferralCredit;
+use super::splitting::RevenueSplit;
+use super::transaction_processor::PaymentReceipt;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const CHECKOUTS_MEMORY_ID: MemoryId = MemoryId::new(11);
+
+/// Identifies one checkout: the payer and the `created_at_time` they chose.
+/// Retrying with the same pair resumes the checkout instead of starting a
+/// new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+pub struct CheckoutKey {
+    pub payer: Principal,
+    pub created_at_time: u64,
+}
+
+/// A checkout as priced before the payer was charged. Every ledger call is
+/// rebuilt from this record, so a retry sends identical arguments and the
+/// ledger deduplicates it.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Checkout {
+    pub token_ledger: Principal,
+    pub total_amount: u64,
+    pub price_e8s: u64,
+    pub split: RevenueSplit,
+    pub platform_amount: u64,  // Platform portion net of the referral
+    pub referral: Option<ReferralCredit>,
+    pub admission: Admission,
+    pub pull_block: Option<BlockIndex>,   // Set once the payer has been charged
+    pub receipt: Option<PaymentReceipt>,  // Set once the splits are distributed
+}
+
+impl Storable for CheckoutKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let payer = self.payer.as_slice();
+        let mut bytes = Vec::with_capacity(1 + payer.len() + 8);
+        bytes.push(payer.len() as u8);
+        bytes.extend_from_slice(payer);
+        bytes.extend_from_slice(&self.created_at_time.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            payer: Principal::from_slice(&bytes[1..1 + len]),
+            created_at_time: u64::from_be_bytes(bytes[1 + len..].try_into().expect("checkout key time")),
+        }
+    }
+}
+
+impl BoundedStorable for CheckoutKey {
+    const MAX_SIZE: u32 = 1 + 29 + 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Checkout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode checkout"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode checkout")
+    }
+}
+
+impl BoundedStorable for Checkout {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CHECKOUTS: RefCell<StableBTreeMap<CheckoutKey, Checkout, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(CHECKOUTS_MEMORY_ID))
+        )
+    );
+}
+
+pub fn get(key: &CheckoutKey) -> Option<Checkout> {
+    CHECKOUTS.with(|checkouts| checkouts.borrow().get(key))
+}
+
+/// Stores `checkout` unless a concurrent call stored one for the same key
+/// first, and returns whichever is stored.
+pub fn get_or_insert(key: CheckoutKey, checkout: Checkout) -> Checkout {
+    CHECKOUTS.with(|checkouts| {
+        let mut checkouts = checkouts.borrow_mut();
+        match checkouts.get(&key) {
+            Some(existing) => existing,
+            None => {
+                checkouts.insert(key, checkout.clone());
+                checkout
+            }
+        }
+    })
+}
+
+/// Applies `f` to a stored checkout and returns the updated record.
+pub fn update(key: &CheckoutKey, f: impl FnOnce(&mut Checkout)) -> Option<Checkout> {
+    CHECKOUTS.with(|checkouts| {
+        let mut checkouts = checkouts.borrow_mut();
+        let mut checkout = checkouts.get(key)?;
+        f(&mut checkout);
+        checkouts.insert(*key, checkout.clone());
+        Some(checkout)
+    })
+}
+
+/// Forgets a checkout whose payer was never charged.
+pub fn remove(key: &CheckoutKey) -> Option<Checkout> {
+    CHECKOUTS.with(|checkouts| checkouts.borrow_mut().remove(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkout_key_round_trips() {
+        let key = CheckoutKey { payer: Principal::from_slice(&[9; 29]), created_at_time: 1_700_000_000 };
+        assert_eq!(CheckoutKey::from_bytes(key.to_bytes()), key);
+    }
+}
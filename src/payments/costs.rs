@@ -0,0 +1,82 @@
+use candid::CandidType;
+
+use super::pricing_config::{itemize_cost, Discount, PricingConfig, RoyaltyConfig};
+use super::splitting::apply_bps;
+
+/// Calculate the total cost including R&D fees and an optional discount
+pub fn calculate_total_cost(
+    base_price: u64,
+    quantum_state_complexity: f64,
+    config: &PricingConfig,
+    discount: Option<&Discount>,
+) -> u64 {
+    itemize_cost(base_price, quantum_state_complexity, &config.fees, discount).total
+}
+
+/// Calculate royalties for marketplace transactions
+pub fn calculate_royalties(
+    sale_price: u64,
+    config: &RoyaltyConfig,
+) -> RoyaltyBreakdown {
+    let creator_amount = apply_bps(sale_price, config.creator_royalty_bps).max(config.minimum_royalty);
+    let platform_amount = apply_bps(sale_price, config.platform_fee_bps).max(config.minimum_royalty);
+    let research_amount = apply_bps(sale_price, config.quantum_research_fund_bps).max(config.minimum_royalty);
+    
+    RoyaltyBreakdown {
+        creator_amount,
+        platform_amount,
+        research_amount,
+        total: creator_amount
+            .saturating_add(platform_amount)
+            .saturating_add(research_amount),
+    }
+}
+
+#[derive(Debug, Clone, CandidType)]
+pub struct RoyaltyBreakdown {
+    pub creator_amount: u64,
+    pub platform_amount: u64,
+    pub research_amount: u64,
+    pub total: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payments::pricing_config::{PaymentSettings, PricingTiers, ServiceFees};
+
+    #[test]
+    fn test_cost_calculation() {
+        let config = PricingConfig {
+            tiers: PricingTiers::default(),
+            fees: ServiceFees::default(),
+            royalties: RoyaltyConfig::default(),
+            payment_settings: PaymentSettings::default(),
+        };
+
+        let base_price = config.tiers.legendary;
+        let complexity = 1.5;
+        
+        let total = calculate_total_cost(base_price, complexity, &config, None);
+        
+        assert!(total > base_price); // Total should be higher than base price
+        assert!(total > config.fees.quantum_compute_fee); // Should include compute fee
+    }
+
+    #[test]
+    fn test_royalty_calculation() {
+        let config = RoyaltyConfig::default();
+        let sale_price = 10_000_000_000; // 100 ICP
+        
+        let breakdown = calculate_royalties(sale_price, &config);
+        
+        assert!(breakdown.creator_amount >= config.minimum_royalty);
+        assert!(breakdown.platform_amount >= config.minimum_royalty);
+        assert!(breakdown.research_amount >= config.minimum_royalty);
+        assert_eq!(breakdown.creator_amount, 250_000_000); // exactly 2.5%
+        assert_eq!(
+            breakdown.total,
+            breakdown.creator_amount + breakdown.platform_amount + breakdown.research_amount
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use super::{
+    deposit_balance, mint_handoff, session_state, session_store, PaymentSession, PaymentStatus, SessionId,
+    LEDGER_FEE_E8S,
+};
+
+const SWEEP_INTERVAL_SECS: u64 = 300; // 5 minutes
+const MAX_SESSIONS_PER_RUN: usize = 200;
+const MAX_DEPOSIT_CHECK_INTERVAL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000; // 1 day
+
+/// What a single sweep run did.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SweepReport {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub scanned: u64,
+    pub expired: Vec<SessionId>,
+    pub deposits_checked: u64,
+    pub queued_for_refund: Vec<SessionId>,
+    pub mint_retries: Vec<SessionId>,
+    pub errors: Vec<String>,
+}
+
+thread_local! {
+    static LAST_REPORT: RefCell<Option<SweepReport>> = const { RefCell::new(None) };
+    static SWEEP_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Starts the periodic sweep. Timers do not survive upgrades, so this must
+/// be called from both `init` and `post_upgrade`.
+pub fn start_expiry_timer() -> TimerId {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(SWEEP_INTERVAL_SECS), || {
+        ic_cdk::spawn(async {
+            run_sweep().await;
+        });
+    })
+}
+
+pub fn last_report() -> Option<SweepReport> {
+    LAST_REPORT.with(|report| report.borrow().clone())
+}
+
+/// Holds `SWEEP_RUNNING` for one run. The flag is cleared on drop, which
+/// also happens when a ledger callback traps and the run's future is
+/// cleaned up, so a failed run never blocks the next one.
+struct SweepGuard;
+
+impl SweepGuard {
+    fn acquire() -> Option<Self> {
+        let already_running = SWEEP_RUNNING.with(|running| std::mem::replace(&mut *running.borrow_mut(), true));
+        (!already_running).then_some(SweepGuard)
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEP_RUNNING.with(|running| *running.borrow_mut() = false);
+    }
+}
+
+/// Whether an expired session's deposit is due for another balance check.
+/// A deposit can land at any time, so checks never stop, but they back off:
+/// the wait after a check grows with how long the session had been expired
+/// at that check, from one sweep interval up to a day.
+fn deposit_check_due(expires_at: u64, checked_at: Option<u64>, now: u64) -> bool {
+    let Some(checked_at) = checked_at else {
+        return true;
+    };
+    let interval = checked_at
+        .saturating_sub(expires_at)
+        .clamp(SWEEP_INTERVAL_SECS * 1_000_000_000, MAX_DEPOSIT_CHECK_INTERVAL_NANOS);
+    now >= checked_at.saturating_add(interval)
+}
+
+/// Marks overdue sessions as expired, then checks the deposit subaccount of
+/// every expired session that is due for a check, least recently checked
+/// first, and queues those holding funds for a refund instead of dropping
+/// them. Also retries failed mints that are due.
+pub async fn run_sweep() -> SweepReport {
+    // A slow ledger can make runs overlap; skip rather than double-process.
+    let Some(_running) = SweepGuard::acquire() else {
+        return last_report().unwrap_or_default();
+    };
+
+    let now = time();
+    let mut report = SweepReport { started_at: now, ..Default::default() };
+
+    let overdue: Vec<SessionId> = session_store::by_status(&PaymentStatus::Created)
+        .into_iter()
+        .filter(|session| session.expires_at <= now)
+        .take(MAX_SESSIONS_PER_RUN)
+        .map(|session| session.session_id)
+        .collect();
+    report.scanned = overdue.len() as u64;
+
+    for session_id in overdue {
+        match session_state::transition(&session_id, PaymentStatus::Expired, |_| {}) {
+            Ok(_) => report.expired.push(session_id),
+            Err(e) => report.errors.push(format!("{}: {}", session_id, e)),
+        }
+    }
+
+    let mut expired: Vec<PaymentSession> = session_store::by_status(&PaymentStatus::Expired)
+        .into_iter()
+        .filter(|session| deposit_check_due(session.expires_at, session.deposit_checked_at, now))
+        .collect();
+    expired.sort_by_key(|session| session.deposit_checked_at);
+
+    for session in expired.into_iter().take(MAX_SESSIONS_PER_RUN) {
+        let session_id = session.session_id.clone();
+        let balance = match deposit_balance(&session).await {
+            Ok(balance) => balance,
+            Err(e) => {
+                report.errors.push(format!("{}: {}", session_id, e));
+                continue;
+            }
+        };
+        report.deposits_checked += 1;
+
+        // The session may have been verified or refunded while we awaited
+        let still_expired = session_store::update(&session_id, |session| {
+            session.deposit_balance = Some(balance);
+            session.deposit_checked_at = Some(time());
+            session.status == PaymentStatus::Expired
+        });
+        if still_expired != Some(true) {
+            continue;
+        }
+
+        if balance > LEDGER_FEE_E8S {
+            if !session_store::is_refund_queued(&session_id) {
+                report.queued_for_refund.push(session_id.clone());
+            }
+            session_store::queue_refund(&session_id, balance);
+        } else {
+            // Nothing left that a refund could return
+            session_store::dequeue_refund(&session_id);
+        }
+    }
+
+    for session_id in mint_handoff::due_for_retry(time()) {
+        if let Err(e) = mint_handoff::mint_session(&session_id).await {
+            report.errors.push(format!("{}: mint retry failed: {}", session_id, e));
+        }
+        report.mint_retries.push(session_id);
+    }
+
+    report.finished_at = time();
+    LAST_REPORT.with(|last| *last.borrow_mut() = Some(report.clone()));
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    #[test]
+    fn test_deposit_checks_back_off() {
+        assert!(deposit_check_due(0, None, 0));
+
+        // Checked right after expiry: due again one sweep interval later
+        assert!(!deposit_check_due(0, Some(MINUTE), 5 * MINUTE));
+        assert!(deposit_check_due(0, Some(MINUTE), 6 * MINUTE));
+
+        // Checked an hour after expiry: due again an hour later
+        assert!(!deposit_check_due(0, Some(60 * MINUTE), 119 * MINUTE));
+        assert!(deposit_check_due(0, Some(60 * MINUTE), 120 * MINUTE));
+
+        // Never more than a day apart
+        let checked_at = 10 * MAX_DEPOSIT_CHECK_INTERVAL_NANOS;
+        assert!(deposit_check_due(0, Some(checked_at), checked_at + MAX_DEPOSIT_CHECK_INTERVAL_NANOS));
+    }
+}
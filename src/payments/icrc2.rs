@@ -0,0 +1,125 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use num_traits::ToPrimitive;
+
+pub type BlockIndex = u64;
+pub type Subaccount = [u8; 32];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn of(owner: Principal) -> Self {
+        Self { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Why a transfer did not produce a block.
+#[derive(Clone, Debug)]
+pub enum TransferFailure {
+    Rejected(String),  // The ledger refused the transfer; nothing moved
+    Unknown(String),   // The call failed; the transfer may have happened
+}
+
+impl std::fmt::Display for TransferFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferFailure::Rejected(e) => write!(f, "{}", e),
+            TransferFailure::Unknown(e) => write!(f, "{} (outcome unknown; retry with the same arguments)", e),
+        }
+    }
+}
+
+fn block_index(nat: Nat) -> Result<BlockIndex, TransferFailure> {
+    // The ledger accepted the transfer, so its outcome is known even if the
+    // index cannot be represented
+    nat.0
+        .to_u64()
+        .ok_or_else(|| TransferFailure::Unknown(format!("Block index {} does not fit in u64", nat)))
+}
+
+/// Calls `icrc1_transfer`, treating a deduplicated retry as success.
+pub async fn icrc1_transfer(ledger: Principal, arg: TransferArg) -> Result<BlockIndex, TransferFailure> {
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| TransferFailure::Unknown(format!("icrc1_transfer call failed: {:?} - {}", code, msg)))?;
+
+    match result {
+        Ok(block) => block_index(block),
+        Err(TransferError::Duplicate { duplicate_of }) => block_index(duplicate_of),
+        Err(e) => Err(TransferFailure::Rejected(format!("icrc1_transfer rejected: {:?}", e))),
+    }
+}
+
+/// Calls `icrc2_transfer_from`, treating a deduplicated retry as success.
+pub async fn icrc2_transfer_from(ledger: Principal, args: TransferFromArgs) -> Result<BlockIndex, TransferFailure> {
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                TransferFailure::Unknown(format!("icrc2_transfer_from call failed: {:?} - {}", code, msg))
+            })?;
+
+    match result {
+        Ok(block) => block_index(block),
+        Err(TransferFromError::Duplicate { duplicate_of }) => block_index(duplicate_of),
+        Err(e) => Err(TransferFailure::Rejected(format!("icrc2_transfer_from rejected: {:?}", e))),
+    }
+}
+
+/// Reads the ledger's current transfer fee.
+pub async fn icrc1_fee(ledger: Principal) -> Result<u64, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("icrc1_fee call failed: {:?} - {}", code, msg))?;
+
+    fee.0.to_u64().ok_or_else(|| format!("Ledger fee {} does not fit in u64", fee))
+}
@@ -0,0 +1,32 @@
+use candid::Principal;
+use ic_ledger_types::{Block, BlockIndex, GetBlocksArgs};
+
+/// Fetches a single block from the ledger, following the archive callback
+/// when the block has already been moved out of the ledger canister.
+pub async fn fetch_block(ledger: Principal, block_index: BlockIndex) -> Result<Block, String> {
+    let args = GetBlocksArgs { start: block_index, length: 1 };
+    let response = ic_ledger_types::query_blocks(ledger, args.clone())
+        .await
+        .map_err(|e| format!("Failed to query ledger blocks: {:?}", e))?;
+
+    if let Some(block) = response.blocks.into_iter().next() {
+        return Ok(block);
+    }
+
+    let archive = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| block_index >= range.start && block_index < range.start + range.length)
+        .ok_or_else(|| format!("Block {} not found", block_index))?;
+
+    let range = ic_ledger_types::query_archived_blocks(&archive.callback, args)
+        .await
+        .map_err(|e| format!("Failed to query archive: {:?}", e))?
+        .map_err(|e| format!("Archive rejected block request: {:?}", e))?;
+
+    range
+        .blocks
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("Block {} not found in archive", block_index))
+}
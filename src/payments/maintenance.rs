@@ -0,0 +1,669 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_timers::TimerId;
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use super::icrc2::{self, Account, BlockIndex, TransferArg, TransferFailure, TransferFromArgs};
+use super::paid_mint;
+use super::pricing_config::ServiceFees;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const MAINTENANCE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(8);
+const SUBSCRIPTIONS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const DEBITS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PENDING_FUNDINGS_MEMORY_ID: MemoryId = MemoryId::new(19);
+
+const BILLING_INTERVAL_SECS: u64 = 60 * 60; // 1 hour
+const MAX_SUBSCRIPTIONS_PER_RUN: usize = 500;
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MIN_PERIOD_NANOS: u64 = DAY_NANOS;
+
+const MEMO_MAINTENANCE_FUNDING: &[u8] = &[5];
+const MEMO_MAINTENANCE_REFUND: &[u8] = &[6];
+
+/// How the recurring maintenance fee is billed. The fee is in the units of
+/// `ledger`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceConfig {
+    pub ledger: Principal,
+    pub fee: u64,           // Debited once per period
+    pub period_nanos: u64,
+    pub grace_nanos: u64,   // How long an unpaid token keeps running before suspension
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            ledger: MAINNET_LEDGER_CANISTER_ID,
+            fee: ServiceFees::default().maintenance_fee,  // 0.25 ICP
+            period_nanos: 30 * DAY_NANOS,
+            grace_nanos: 7 * DAY_NANOS,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum BillingStatus {
+    Active,
+    /// The period starting at `since` could not be paid
+    Grace { since: u64 },
+    /// Grace ran out at `since`; nothing is billed until refunded
+    Suspended { since: u64 },
+    Cancelled { at: u64 },
+}
+
+/// The maintenance balance of one Anima.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MaintenanceSubscription {
+    pub token_id: String,
+    pub owner: Principal,
+    pub ledger: Principal,
+    pub balance: u64,
+    pub paid_through: u64,  // The next fee is due at this time
+    pub total_debited: u64,
+    pub debit_count: u64,
+    pub status: BillingStatus,
+    pub started_at: u64,
+    pub pending_refund: Option<PendingRefund>,  // Refund whose transfer has not been confirmed
+}
+
+/// A refund taken out of the balance on cancellation. Its transfer is
+/// rebuilt from this record, so cancelling again resends identical
+/// arguments and the ledger deduplicates it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingRefund {
+    pub amount: u64,  // Taken from the balance, fee included
+    pub fee: u64,
+    pub created_at_time: u64,
+}
+
+/// A funding pull whose outcome is not known yet, resent as is by the next
+/// `fund` call for the token.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+struct PendingFunding {
+    ledger: Principal,
+    amount: u64,
+    created_at_time: u64,
+}
+
+impl MaintenanceSubscription {
+    /// A token stays in good standing through the grace period.
+    pub fn in_good_standing(&self) -> bool {
+        matches!(self.status, BillingStatus::Active | BillingStatus::Grace { .. })
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MaintenanceStatus {
+    pub subscription: MaintenanceSubscription,
+    pub in_good_standing: bool,
+}
+
+impl From<MaintenanceSubscription> for MaintenanceStatus {
+    fn from(subscription: MaintenanceSubscription) -> Self {
+        Self { in_good_standing: subscription.in_good_standing(), subscription }
+    }
+}
+
+/// One period's fee taken from a token's balance.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaintenanceDebit {
+    pub index: u64,
+    pub amount: u64,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub debited_at: u64,
+    pub balance_after: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TokenKey(String);
+
+impl Storable for TokenKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.into_owned()).expect("token id is valid utf-8"))
+    }
+}
+
+impl BoundedStorable for TokenKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Debits are keyed by (token id, index) so a token's history is one range
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DebitKey {
+    token_id: String,
+    index: u64,
+}
+
+impl Storable for DebitKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(8 + self.token_id.len());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(self.token_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (index, token_id) = bytes.split_at(8);
+        Self {
+            token_id: String::from_utf8(token_id.to_vec()).expect("token id is valid utf-8"),
+            index: u64::from_be_bytes(index.try_into().expect("debit key index")),
+        }
+    }
+}
+
+impl BoundedStorable for DebitKey {
+    const MAX_SIZE: u32 = 8 + 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for MaintenanceConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode maintenance config"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_default()
+    }
+}
+
+impl Storable for MaintenanceSubscription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode maintenance subscription"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode maintenance subscription")
+    }
+}
+
+impl BoundedStorable for MaintenanceSubscription {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for MaintenanceDebit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode maintenance debit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode maintenance debit")
+    }
+}
+
+impl Storable for PendingFunding {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode pending funding"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode pending funding")
+    }
+}
+
+impl BoundedStorable for PendingFunding {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for MaintenanceDebit {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<MaintenanceConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(MAINTENANCE_CONFIG_MEMORY_ID)),
+            MaintenanceConfig::default(),
+        ).expect("failed to initialize maintenance config")
+    );
+
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<TokenKey, MaintenanceSubscription, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(SUBSCRIPTIONS_MEMORY_ID))
+        )
+    );
+
+    static DEBITS: RefCell<StableBTreeMap<DebitKey, MaintenanceDebit, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(DEBITS_MEMORY_ID))
+        )
+    );
+
+    static PENDING_FUNDINGS: RefCell<StableBTreeMap<TokenKey, PendingFunding, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_FUNDINGS_MEMORY_ID))
+        )
+    );
+}
+
+/// Starts the periodic billing run. Timers do not survive upgrades, so this
+/// must be called from both `init` and `post_upgrade`.
+pub fn start_billing_timer() -> TimerId {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(BILLING_INTERVAL_SECS), || {
+        run_billing(time());
+    })
+}
+
+pub fn config() -> MaintenanceConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_config(config: MaintenanceConfig) -> Result<()> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can configure maintenance billing".to_string()));
+    }
+    if config.period_nanos < MIN_PERIOD_NANOS {
+        return Err(Error::Payment("Billing period must be at least one day".to_string()));
+    }
+
+    // Balances are held in the old ledger's units
+    let current = self::config();
+    if config.ledger != current.ledger && has_open_subscriptions() {
+        return Err(Error::InvalidState("Cannot change the billing ledger while subscriptions are open".to_string()));
+    }
+
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .map(|_| ())
+            .map_err(|e| Error::System(format!("Failed to store maintenance config: {:?}", e)))
+    })
+}
+
+fn has_open_subscriptions() -> bool {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .any(|(_, subscription)| !matches!(subscription.status, BillingStatus::Cancelled { .. }))
+    })
+}
+
+pub fn status(token_id: &str) -> Option<MaintenanceStatus> {
+    subscription(token_id).map(MaintenanceStatus::from)
+}
+
+/// A token's debits, oldest first, skipping `offset` and returning at most
+/// `limit` (capped at `MAX_PAGE_SIZE`).
+pub fn debits(token_id: &str, offset: u64, limit: u64) -> Vec<MaintenanceDebit> {
+    DEBITS.with(|debits| {
+        debits
+            .borrow()
+            .range(DebitKey { token_id: token_id.to_string(), index: 0 }..)
+            .take_while(|(key, _)| key.token_id == token_id)
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(_, debit)| debit)
+            .collect()
+    })
+}
+
+fn subscription(token_id: &str) -> Option<MaintenanceSubscription> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&TokenKey(token_id.to_string())))
+}
+
+fn require_owner(token_id: &str) -> Result<Principal> {
+    let caller = ic_cdk::caller();
+    if paid_mint::owner_of(token_id) != Some(caller) {
+        return Err(Error::NotAuthorized("Only the owner can manage an Anima's maintenance".to_string()));
+    }
+    Ok(caller)
+}
+
+/// Adds `amount` to a token's maintenance balance, pulling it from the
+/// owner's ICRC-2 allowance. Funding a token for the first time, or after
+/// cancelling, opts it in and charges the first period right away. Funding
+/// a suspended token restarts billing from now.
+///
+/// If an earlier pull's outcome is unknown, it must be resent first by
+/// funding the same amount again; the ledger deduplicates it.
+pub async fn fund(token_id: String, amount: u64) -> Result<MaintenanceStatus> {
+    let owner = require_owner(&token_id)?;
+    if amount == 0 {
+        return Err(Error::Payment("Funding amount must be greater than zero".to_string()));
+    }
+
+    let config = config();
+    let key = TokenKey(token_id.clone());
+    let pending = match PENDING_FUNDINGS.with(|pending| pending.borrow().get(&key)) {
+        Some(pending) if pending.amount != amount => {
+            return Err(Error::InvalidState(format!(
+                "A funding of {} is unconfirmed; fund that amount again to resend it",
+                pending.amount
+            )));
+        }
+        Some(pending) => pending,
+        None => {
+            let pending = PendingFunding {
+                ledger: subscription(&token_id).map_or(config.ledger, |subscription| subscription.ledger),
+                amount,
+                created_at_time: time(),
+            };
+            PENDING_FUNDINGS.with(|pendings| pendings.borrow_mut().insert(key.clone(), pending.clone()));
+            pending
+        }
+    };
+
+    let ledger = pending.ledger;
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::of(owner),
+        to: Account::of(ic_cdk::id()),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(MEMO_MAINTENANCE_FUNDING.to_vec()),
+        created_at_time: Some(pending.created_at_time),
+    };
+    match icrc2::icrc2_transfer_from(ledger, args).await {
+        Ok(_) => {}
+        Err(TransferFailure::Rejected(e)) => {
+            take_pending_funding(&key, &pending);
+            return Err(Error::Payment(format!("Maintenance funding failed: {}", e)));
+        }
+        Err(e @ TransferFailure::Unknown(_)) => {
+            return Err(Error::Payment(format!("Maintenance funding failed; fund again to resend it: {}", e)));
+        }
+    }
+
+    // A concurrent retry of the same pull may have credited it already
+    if !take_pending_funding(&key, &pending) {
+        return subscription(&token_id)
+            .map(MaintenanceStatus::from)
+            .ok_or_else(|| Error::InvalidState("Anima has no maintenance subscription".to_string()));
+    }
+
+    // Read the subscription again; the timer may have billed it meanwhile
+    let now = time();
+    let mut subscription = match subscription(&token_id) {
+        Some(subscription) if !matches!(subscription.status, BillingStatus::Cancelled { .. }) => subscription,
+        previous => MaintenanceSubscription {
+            token_id: token_id.clone(),
+            owner,
+            ledger,
+            balance: previous.as_ref().map_or(0, |previous| previous.balance),
+            paid_through: now,
+            total_debited: previous.as_ref().map_or(0, |previous| previous.total_debited),
+            debit_count: previous.as_ref().map_or(0, |previous| previous.debit_count),
+            status: BillingStatus::Active,
+            started_at: now,
+            pending_refund: previous.and_then(|previous| previous.pending_refund),
+        },
+    };
+
+    // Suspended time is not billed
+    if matches!(subscription.status, BillingStatus::Suspended { .. }) {
+        subscription.paid_through = now;
+    }
+    subscription.balance = subscription.balance.saturating_add(amount);
+
+    let debits = settle(&mut subscription, &config, now);
+    store(subscription.clone(), debits);
+    Ok(subscription.into())
+}
+
+/// Stops billing a token and returns its unused balance to the owner, less
+/// the ledger fee. Balances too small to cover the fee stay on record. If
+/// an earlier refund's outcome is unknown, cancelling again resends it.
+pub async fn cancel(token_id: String) -> Result<Option<BlockIndex>> {
+    let owner = require_owner(&token_id)?;
+    let Some(subscription) = subscription(&token_id) else {
+        return Err(Error::InvalidState("Anima has no maintenance subscription".to_string()));
+    };
+    let ledger = subscription.ledger;
+    if subscription.pending_refund.is_none() {
+        let fee = icrc2::icrc1_fee(ledger).await.map_err(Error::Payment)?;
+        start_refund(&token_id, fee, time());
+    }
+    let Some(pending) = self::subscription(&token_id).and_then(|subscription| subscription.pending_refund) else {
+        return Ok(None);
+    };
+
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account::of(owner),
+        amount: Nat::from(pending.amount - pending.fee),
+        fee: Some(Nat::from(pending.fee)),
+        memo: Some(MEMO_MAINTENANCE_REFUND.to_vec()),
+        created_at_time: Some(pending.created_at_time),
+    };
+    match icrc2::icrc1_transfer(ledger, arg).await {
+        Ok(block) => {
+            finish_refund(&token_id, &pending, true);
+            Ok(Some(block))
+        }
+        Err(TransferFailure::Rejected(e)) => {
+            // Keep the balance so the owner can cancel again
+            finish_refund(&token_id, &pending, false);
+            Err(Error::Payment(format!("Maintenance refund failed: {}", e)))
+        }
+        Err(e @ TransferFailure::Unknown(_)) => {
+            Err(Error::Payment(format!("Maintenance refund failed; cancel again to resend it: {}", e)))
+        }
+    }
+}
+
+/// Cancels a subscription and, if its balance covers the ledger fee, moves
+/// the balance into a pending refund. Re-reads the subscription, so a
+/// concurrent cancel that already started a refund is left alone.
+fn start_refund(token_id: &str, fee: u64, now: u64) {
+    let Some(mut subscription) = subscription(token_id) else {
+        return;
+    };
+    subscription.status = BillingStatus::Cancelled { at: now };
+    if subscription.pending_refund.is_none() && subscription.balance > fee {
+        subscription.pending_refund = Some(PendingRefund { amount: subscription.balance, fee, created_at_time: now });
+        subscription.balance = 0;
+    }
+    store(subscription, Vec::new());
+}
+
+/// Settles `pending`: drops it if its transfer went through, otherwise
+/// returns it to the balance. A concurrent retry may have settled it
+/// already, in which case nothing changes.
+fn finish_refund(token_id: &str, pending: &PendingRefund, paid: bool) {
+    let Some(mut subscription) = subscription(token_id) else {
+        return;
+    };
+    if subscription.pending_refund.as_ref() != Some(pending) {
+        return;
+    }
+    subscription.pending_refund = None;
+    if !paid {
+        subscription.balance = subscription.balance.saturating_add(pending.amount);
+    }
+    store(subscription, Vec::new());
+}
+
+/// Removes the pending funding for a token if it is still `pending`, and
+/// reports whether it was.
+fn take_pending_funding(key: &TokenKey, pending: &PendingFunding) -> bool {
+    PENDING_FUNDINGS.with(|pendings| {
+        let mut pendings = pendings.borrow_mut();
+        if pendings.get(key).as_ref() != Some(pending) {
+            return false;
+        }
+        pendings.remove(key);
+        true
+    })
+}
+
+/// Debits every token whose fee is due and moves unpaid tokens into grace
+/// or suspension. Returns how many subscriptions were updated.
+pub fn run_billing(now: u64) -> usize {
+    let config = config();
+    let due: Vec<MaintenanceSubscription> = SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                matches!(subscription.status, BillingStatus::Active | BillingStatus::Grace { .. })
+                    && subscription.paid_through <= now
+            })
+            .take(MAX_SUBSCRIPTIONS_PER_RUN)
+            .collect()
+    });
+
+    let count = due.len();
+    for mut subscription in due {
+        let debits = settle(&mut subscription, &config, now);
+        store(subscription, debits);
+    }
+    count
+}
+
+fn store(subscription: MaintenanceSubscription, debits: Vec<MaintenanceDebit>) {
+    DEBITS.with(|store| {
+        let mut store = store.borrow_mut();
+        for debit in debits {
+            store.insert(DebitKey { token_id: subscription.token_id.clone(), index: debit.index }, debit);
+        }
+    });
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow_mut()
+            .insert(TokenKey(subscription.token_id.clone()), subscription);
+    });
+}
+
+/// Debits every period due by `now` that the balance covers, then derives
+/// the billing status from what is left unpaid.
+fn settle(
+    subscription: &mut MaintenanceSubscription,
+    config: &MaintenanceConfig,
+    now: u64,
+) -> Vec<MaintenanceDebit> {
+    let mut debits = Vec::new();
+    if matches!(subscription.status, BillingStatus::Cancelled { .. }) {
+        return debits;
+    }
+
+    while subscription.paid_through <= now && subscription.balance >= config.fee {
+        let period_start = subscription.paid_through;
+        let period_end = period_start.saturating_add(config.period_nanos);
+        subscription.balance -= config.fee;
+        subscription.total_debited = subscription.total_debited.saturating_add(config.fee);
+        subscription.paid_through = period_end;
+
+        debits.push(MaintenanceDebit {
+            index: subscription.debit_count,
+            amount: config.fee,
+            period_start,
+            period_end,
+            debited_at: now,
+            balance_after: subscription.balance,
+        });
+        subscription.debit_count += 1;
+    }
+
+    subscription.status = if subscription.paid_through > now {
+        BillingStatus::Active
+    } else {
+        let suspends_at = subscription.paid_through.saturating_add(config.grace_nanos);
+        match subscription.status {
+            BillingStatus::Suspended { since } => BillingStatus::Suspended { since },
+            _ if now >= suspends_at => BillingStatus::Suspended { since: suspends_at },
+            _ => BillingStatus::Grace { since: subscription.paid_through },
+        }
+    };
+
+    debits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MaintenanceConfig {
+        MaintenanceConfig {
+            ledger: MAINNET_LEDGER_CANISTER_ID,
+            fee: 100,
+            period_nanos: 10,
+            grace_nanos: 5,
+        }
+    }
+
+    fn subscription(balance: u64) -> MaintenanceSubscription {
+        MaintenanceSubscription {
+            token_id: "anima_1".to_string(),
+            owner: Principal::anonymous(),
+            ledger: MAINNET_LEDGER_CANISTER_ID,
+            balance,
+            paid_through: 0,
+            total_debited: 0,
+            debit_count: 0,
+            status: BillingStatus::Active,
+            started_at: 0,
+            pending_refund: None,
+        }
+    }
+
+    #[test]
+    fn test_due_periods_are_debited() {
+        let mut subscription = subscription(250);
+        let debits = settle(&mut subscription, &config(), 15);
+
+        assert_eq!(debits.len(), 2);
+        assert_eq!(debits[1].period_start, 10);
+        assert_eq!(debits[1].balance_after, 50);
+        assert_eq!(subscription.paid_through, 20);
+        assert_eq!(subscription.status, BillingStatus::Active);
+        assert!(subscription.in_good_standing());
+    }
+
+    #[test]
+    fn test_unpaid_token_goes_through_grace_to_suspension() {
+        let mut subscription = subscription(50);
+
+        assert!(settle(&mut subscription, &config(), 3).is_empty());
+        assert_eq!(subscription.status, BillingStatus::Grace { since: 0 });
+        assert!(subscription.in_good_standing());
+
+        settle(&mut subscription, &config(), 7);
+        assert_eq!(subscription.status, BillingStatus::Suspended { since: 5 });
+        assert!(!subscription.in_good_standing());
+    }
+
+    #[test]
+    fn test_debit_key_round_trips() {
+        let key = DebitKey { token_id: "anima_1".to_string(), index: 7 };
+        assert_eq!(DebitKey::from_bytes(key.to_bytes()), key);
+    }
+
+    #[test]
+    fn test_refund_is_settled_once() {
+        store(subscription(250), Vec::new());
+        start_refund("anima_1", 10, 42);
+        start_refund("anima_1", 10, 43);
+
+        let stored = super::subscription("anima_1").unwrap();
+        let pending = PendingRefund { amount: 250, fee: 10, created_at_time: 42 };
+        assert_eq!(stored.pending_refund, Some(pending.clone()));
+        assert_eq!(stored.balance, 0);
+        assert_eq!(stored.status, BillingStatus::Cancelled { at: 43 });
+
+        finish_refund("anima_1", &pending, false);
+        finish_refund("anima_1", &pending, false);
+        let stored = super::subscription("anima_1").unwrap();
+        assert_eq!(stored.pending_refund, None);
+        assert_eq!(stored.balance, 250);
+    }
+}
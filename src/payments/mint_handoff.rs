@@ -0,0 +1,184 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::time;
+use ic_ledger_types::{Memo, Timestamp, Tokens};
+use std::cell::RefCell;
+
+use super::refunds::DepositTransfer;
+use super::{
+    pinned_transfer, quotes, session_state, session_store, PaymentSession, PaymentStatus, SessionId,
+    LEDGER_FEE_E8S, TREASURY_ACCOUNT,
+};
+
+pub const MAX_MINT_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_NANOS: u64 = 60_000_000_000; // 1 minute, doubled per attempt
+const MAX_ERROR_LEN: usize = 512;
+
+/// Request sent to the anima canister's `mint_paid_anima` endpoint.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PaidMintRequest {
+    pub session_id: SessionId,
+    pub owner: Principal,
+    pub name: String,
+    pub amount_paid: u64,
+    pub block_index: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PaidMintReceipt {
+    pub session_id: SessionId,
+    pub token_id: String,
+    pub minted_at: u64,
+}
+
+/// A paid session whose mint kept failing and needs manual attention.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub session_id: SessionId,
+    pub owner: Principal,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: u64,
+}
+
+thread_local! {
+    static ANIMA_CANISTER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+pub fn set_anima_canister(canister: Principal) {
+    ANIMA_CANISTER.with(|anima| *anima.borrow_mut() = Some(canister));
+}
+
+pub fn anima_canister() -> Option<Principal> {
+    ANIMA_CANISTER.with(|anima| *anima.borrow())
+}
+
+/// Mints the Anima for a funded session on the anima canister, binds the
+/// returned token id to the session and sweeps the payment to the treasury.
+///
+/// Safe to call repeatedly: the anima canister mints at most once per
+/// session id and the sweep reuses its `created_at_time`, so the ledger
+/// deduplicates it. The sweep is only re-pinned once that timestamp is too
+/// old and the deposit shows it never landed.
+pub async fn mint_session(session_id: &str) -> Result<PaymentSession, String> {
+    let session = session_store::get(session_id).ok_or("Session not found")?;
+    if session.status == PaymentStatus::Settled {
+        return Ok(session);
+    }
+
+    let now = time();
+    let session = session_state::transition(session_id, PaymentStatus::Minting, |session| {
+        session.sweep_created_at.get_or_insert(now);
+        *session.mint_attempts.get_or_insert(0) += 1;
+    })?
+    .session;
+
+    match mint_and_sweep(&session).await {
+        Ok((token_id, block)) => {
+            session_store::remove_dead_letter(session_id);
+            let transition = session_state::transition(session_id, PaymentStatus::Settled, |session| {
+                session.token_id = Some(token_id);
+                session.settlement_block = Some(block);
+                session.last_mint_error = None;
+            })?;
+
+            // Count each settled mint once against its tier's supply
+            if let Some(quote) = transition.session.quote.as_ref().filter(|_| transition.applied) {
+                quotes::record_mint(&quote.tier);
+            }
+            Ok(transition.session)
+        }
+        Err(e) => {
+            let e: String = e.chars().take(MAX_ERROR_LEN).collect();
+            let failed = session_state::transition(session_id, PaymentStatus::Failed, |session| {
+                session.last_mint_error = Some(e.clone());
+            })?
+            .session;
+
+            if failed.mint_attempts() >= MAX_MINT_ATTEMPTS {
+                session_store::add_dead_letter(DeadLetter {
+                    session_id: failed.session_id.clone(),
+                    owner: failed.owner,
+                    attempts: failed.mint_attempts(),
+                    last_error: e.clone(),
+                    failed_at: time(),
+                });
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn mint_and_sweep(session: &PaymentSession) -> Result<(String, u64), String> {
+    let token_id = match &session.token_id {
+        // Minted on an earlier attempt whose sweep failed
+        Some(token_id) => token_id.clone(),
+        None => {
+            let receipt = request_mint(session).await?;
+            // Bind the token immediately so a failed sweep never loses it
+            session_store::update(&session.session_id, |stored| {
+                stored.token_id = Some(receipt.token_id.clone());
+            });
+            receipt.token_id
+        }
+    };
+
+    let block = sweep_to_treasury(session).await?;
+    Ok((token_id, block))
+}
+
+async fn request_mint(session: &PaymentSession) -> Result<PaidMintReceipt, String> {
+    let anima = anima_canister().ok_or("Anima canister is not configured")?;
+    let request = PaidMintRequest {
+        session_id: session.session_id.clone(),
+        owner: session.owner,
+        name: session.anima_name.clone().unwrap_or_default(),
+        amount_paid: session.paid_amount.unwrap_or(session.amount),
+        block_index: session.block_index.ok_or("Session has no funding block")?,
+    };
+
+    let (result,): (Result<PaidMintReceipt, String>,) =
+        ic_cdk::call(anima, "mint_paid_anima", (request,))
+            .await
+            .map_err(|(code, msg)| format!("Mint call failed: {:?} - {}", code, msg))?;
+
+    result
+}
+
+/// The anima canister's receipt for `session_id`, if it ever minted for it.
+/// It mints at most once per session, so this is authoritative even when
+/// our own view of the mint call was lost.
+pub async fn recorded_mint(session_id: &str) -> Result<Option<PaidMintReceipt>, String> {
+    let anima = anima_canister().ok_or("Anima canister is not configured")?;
+    let (receipt,): (Option<PaidMintReceipt>,) =
+        ic_cdk::call(anima, "get_paid_mint", (session_id.to_string(),))
+            .await
+            .map_err(|(code, msg)| format!("Mint lookup failed: {:?} - {}", code, msg))?;
+
+    Ok(receipt)
+}
+
+async fn sweep_to_treasury(session: &PaymentSession) -> Result<u64, String> {
+    let transfer_args = ic_ledger_types::TransferArgs {
+        memo: Memo(session.memo),
+        amount: Tokens::from_e8s(session.amount.saturating_sub(LEDGER_FEE_E8S)),
+        fee: Tokens::from_e8s(LEDGER_FEE_E8S),
+        from_subaccount: Some(session.deposit_subaccount),
+        to: TREASURY_ACCOUNT.with(|acc| *acc.borrow()),
+        created_at_time: session.sweep_created_at.map(|timestamp_nanos| Timestamp { timestamp_nanos }),
+    };
+
+    pinned_transfer(&session.session_id, DepositTransfer::Sweep, transfer_args).await
+}
+
+/// Failed mints that are due for another attempt, with exponential backoff.
+pub fn due_for_retry(now: u64) -> Vec<SessionId> {
+    session_store::by_status(&PaymentStatus::Failed)
+        .into_iter()
+        .filter(|session| session.mint_attempts() < MAX_MINT_ATTEMPTS)
+        .filter(|session| {
+            let delay = RETRY_BASE_DELAY_NANOS.saturating_mul(1u64 << session.mint_attempts().min(16));
+            now >= session.last_updated().saturating_add(delay)
+        })
+        .map(|session| session.session_id)
+        .collect()
+}
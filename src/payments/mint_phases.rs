@@ -0,0 +1,458 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::error::{Error, Result};
+use super::checkouts::CheckoutKey;
+use super::pricing_config::Discount;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const PHASES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const DISCOUNT_CODES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const PHASE_MINTS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ADMISSIONS_MEMORY_ID: MemoryId = MemoryId::new(12);
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Allowlist {
+    Principals(Vec<Principal>),
+    /// Root of a tree whose leaves are `sha256(principal bytes)` and whose
+    /// nodes hash the sorted pair of their children
+    MerkleRoot(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum PhaseAccess {
+    Closed,
+    Allowlist(Allowlist),
+    Public,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct MintPhase {
+    pub name: String,
+    pub access: PhaseAccess,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub per_principal_cap: Option<u32>,
+    pub price_override: Option<u64>,  // Base price in e8s for this phase
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct PhaseSchedule {
+    phases: Vec<MintPhase>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct DiscountCode {
+    pub code: String,
+    pub discount: Discount,
+    pub max_uses: u32,  // 1 for a single-use code
+    pub uses: u32,
+    pub expires_at: Option<u64>,
+}
+
+/// A discount as applied to one checkout, recorded on its receipt.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct AppliedDiscount {
+    pub code: String,
+    pub discount: Discount,
+}
+
+/// A phase slot, and optionally a discount code use, held for a checkout
+/// in flight until it is settled or released.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Admission {
+    pub phase: Option<String>,
+    pub price_override: Option<u64>,
+    pub discount: Option<AppliedDiscount>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Name(String);
+
+impl Storable for Name {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.into_owned()).expect("name is valid utf-8"))
+    }
+}
+
+impl BoundedStorable for Name {
+    const MAX_SIZE: u32 = MAX_NAME_LEN as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Key for per-principal mint counts: (principal, phase name)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct PhaseMintKey {
+    principal: Principal,
+    phase: String,
+}
+
+impl Storable for PhaseMintKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = Vec::with_capacity(1 + principal.len() + self.phase.len());
+        bytes.push(principal.len() as u8);
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(self.phase.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            principal: Principal::from_slice(&bytes[1..1 + len]),
+            phase: String::from_utf8(bytes[1 + len..].to_vec()).expect("phase name is valid utf-8"),
+        }
+    }
+}
+
+impl BoundedStorable for PhaseMintKey {
+    const MAX_SIZE: u32 = 1 + 29 + MAX_NAME_LEN as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PhaseSchedule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode mint phases"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode mint phases")
+    }
+}
+
+impl Storable for DiscountCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode discount code"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode discount code")
+    }
+}
+
+impl BoundedStorable for DiscountCode {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Admission {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode admission"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode admission")
+    }
+}
+
+impl BoundedStorable for Admission {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PHASES: RefCell<StableCell<PhaseSchedule, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PHASES_MEMORY_ID)),
+            PhaseSchedule::default(),
+        ).expect("failed to initialize mint phases")
+    );
+
+    static DISCOUNT_CODES: RefCell<StableBTreeMap<Name, DiscountCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(DISCOUNT_CODES_MEMORY_ID))
+        )
+    );
+
+    static PHASE_MINTS: RefCell<StableBTreeMap<PhaseMintKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PHASE_MINTS_MEMORY_ID))
+        )
+    );
+
+    // Checkout -> admission it holds, so a retried checkout is admitted once
+    static ADMISSIONS: RefCell<StableBTreeMap<CheckoutKey, Admission, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(ADMISSIONS_MEMORY_ID))
+        )
+    );
+}
+
+fn require_controller() -> Result<()> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can configure minting".to_string()));
+    }
+    Ok(())
+}
+
+pub fn phases() -> Vec<MintPhase> {
+    PHASES.with(|phases| phases.borrow().get().phases.clone())
+}
+
+pub fn set_phases(phases: Vec<MintPhase>) -> Result<()> {
+    require_controller()?;
+    for phase in &phases {
+        if phase.name.is_empty() || phase.name.len() > MAX_NAME_LEN {
+            return Err(Error::Payment(format!("Phase name must be 1-{} bytes", MAX_NAME_LEN)));
+        }
+        if phase.starts_at >= phase.ends_at {
+            return Err(Error::Payment(format!("Phase {} ends before it starts", phase.name)));
+        }
+    }
+
+    PHASES.with(|cell| {
+        cell.borrow_mut()
+            .set(PhaseSchedule { phases })
+            .map(|_| ())
+            .map_err(|e| Error::System(format!("Failed to store mint phases: {:?}", e)))
+    })
+}
+
+pub fn discount_codes() -> Result<Vec<DiscountCode>> {
+    require_controller()?;
+    Ok(DISCOUNT_CODES.with(|codes| codes.borrow().iter().map(|(_, code)| code).collect()))
+}
+
+/// Creates or replaces a discount code.
+pub fn upsert_discount_code(code: DiscountCode) -> Result<()> {
+    require_controller()?;
+    if code.code.is_empty() || code.code.len() > MAX_NAME_LEN {
+        return Err(Error::Payment(format!("Discount code must be 1-{} bytes", MAX_NAME_LEN)));
+    }
+    if !code.code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Error::Payment("Discount codes may only use letters, digits, '-' and '_'".to_string()));
+    }
+    if code.max_uses == 0 {
+        return Err(Error::Payment("Discount code must allow at least one use".to_string()));
+    }
+
+    DISCOUNT_CODES.with(|codes| codes.borrow_mut().insert(Name(code.code.clone()), code));
+    Ok(())
+}
+
+/// The phase running at `now`. With no phases configured minting is public
+/// and unrestricted; once any are configured, gaps between them are closed.
+pub fn active_phase(now: u64) -> Option<MintPhase> {
+    phases()
+        .into_iter()
+        .find(|phase| phase.starts_at <= now && now < phase.ends_at)
+}
+
+/// Checks that the payer of `checkout` may mint at `now` and holds a phase
+/// slot and a discount code use for it. A checkout that was already
+/// admitted gets its stored admission back without holding anything more.
+/// Both are given back through `release`, or kept through `settle`.
+pub fn admit(
+    checkout: CheckoutKey,
+    now: u64,
+    merkle_proof: Option<Vec<Vec<u8>>>,
+    discount_code: Option<String>,
+) -> Result<Admission> {
+    if let Some(admission) = ADMISSIONS.with(|admissions| admissions.borrow().get(&checkout)) {
+        return Ok(admission);
+    }
+
+    let principal = checkout.payer;
+    let phase = if phases().is_empty() {
+        None
+    } else {
+        let phase = active_phase(now).ok_or_else(|| Error::Payment("Minting is closed".to_string()))?;
+        check_access(&phase, &principal, merkle_proof.as_deref())?;
+        Some(phase)
+    };
+
+    // Look up the code before holding anything so a bad code costs nothing
+    let discount = discount_code.map(|code| check_discount(&code, now)).transpose()?;
+
+    if let Some(phase) = &phase {
+        let key = PhaseMintKey { principal, phase: phase.name.clone() };
+        let minted = PHASE_MINTS.with(|mints| mints.borrow().get(&key).unwrap_or(0));
+        if phase.per_principal_cap.map_or(false, |cap| minted >= cap) {
+            return Err(Error::Payment(format!("Mint limit reached for phase {}", phase.name)));
+        }
+        PHASE_MINTS.with(|mints| mints.borrow_mut().insert(key, minted + 1));
+    }
+    if let Some(applied) = &discount {
+        change_uses(&applied.code, |uses| uses + 1);
+    }
+
+    let admission = Admission {
+        price_override: phase.as_ref().and_then(|phase| phase.price_override),
+        phase: phase.map(|phase| phase.name),
+        discount,
+    };
+    ADMISSIONS.with(|admissions| admissions.borrow_mut().insert(checkout, admission.clone()));
+    Ok(admission)
+}
+
+/// Gives back the phase slot and discount use of a failed checkout. Only
+/// the first call for a checkout gives anything back.
+pub fn release(checkout: &CheckoutKey) {
+    let Some(admission) = ADMISSIONS.with(|admissions| admissions.borrow_mut().remove(checkout)) else {
+        return;
+    };
+
+    if let Some(phase) = admission.phase {
+        let key = PhaseMintKey { principal: checkout.payer, phase };
+        PHASE_MINTS.with(|mints| {
+            let mut mints = mints.borrow_mut();
+            if let Some(minted) = mints.get(&key) {
+                mints.insert(key, minted.saturating_sub(1));
+            }
+        });
+    }
+    if let Some(applied) = admission.discount {
+        change_uses(&applied.code, |uses| uses.saturating_sub(1));
+    }
+}
+
+/// Keeps the phase slot and discount use of a paid checkout for good.
+pub fn settle(checkout: &CheckoutKey) {
+    ADMISSIONS.with(|admissions| admissions.borrow_mut().remove(checkout));
+}
+
+fn check_access(phase: &MintPhase, principal: &Principal, proof: Option<&[Vec<u8>]>) -> Result<()> {
+    let allowed = match &phase.access {
+        PhaseAccess::Closed => false,
+        PhaseAccess::Public => true,
+        PhaseAccess::Allowlist(Allowlist::Principals(principals)) => principals.contains(principal),
+        PhaseAccess::Allowlist(Allowlist::MerkleRoot(root)) => {
+            proof.map_or(false, |proof| verify_merkle_proof(root, principal, proof))
+        }
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::NotAuthorized(format!("Not allowed to mint during phase {}", phase.name)))
+    }
+}
+
+fn check_discount(code: &str, now: u64) -> Result<AppliedDiscount> {
+    let stored = DISCOUNT_CODES
+        .with(|codes| codes.borrow().get(&Name(code.to_string())))
+        .ok_or_else(|| Error::Payment("Unknown discount code".to_string()))?;
+
+    if stored.expires_at.map_or(false, |expires_at| now >= expires_at) {
+        return Err(Error::Payment("Discount code has expired".to_string()));
+    }
+    if stored.uses >= stored.max_uses {
+        return Err(Error::Payment("Discount code has been used up".to_string()));
+    }
+
+    Ok(AppliedDiscount { code: stored.code, discount: stored.discount })
+}
+
+fn change_uses(code: &str, f: impl FnOnce(u32) -> u32) {
+    DISCOUNT_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        let key = Name(code.to_string());
+        if let Some(mut stored) = codes.get(&key) {
+            stored.uses = f(stored.uses);
+            codes.insert(key, stored);
+        }
+    });
+}
+
+/// Verifies that `principal` is a leaf under `root`. Each proof element is
+/// a sibling hash; pairs are hashed in sorted order so no direction bits
+/// are needed.
+pub fn verify_merkle_proof(root: &[u8], principal: &Principal, proof: &[Vec<u8>]) -> bool {
+    let mut node = Sha256::digest(principal.as_slice()).to_vec();
+    for sibling in proof {
+        let (first, second) = if node <= *sibling { (&node, sibling) } else { (sibling, &node) };
+        let mut hasher = Sha256::new();
+        hasher.update(first);
+        hasher.update(second);
+        node = hasher.finalize().to_vec();
+    }
+    node == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(principal: &Principal) -> Vec<u8> {
+        Sha256::digest(principal.as_slice()).to_vec()
+    }
+
+    fn parent(a: &[u8], b: &[u8]) -> Vec<u8> {
+        let (first, second) = if a <= b { (a, b) } else { (b, a) };
+        let mut hasher = Sha256::new();
+        hasher.update(first);
+        hasher.update(second);
+        hasher.finalize().to_vec()
+    }
+
+    #[test]
+    fn test_merkle_proof_accepts_members_only() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let dave = Principal::from_slice(&[4]);
+
+        let left = parent(&leaf(&alice), &leaf(&bob));
+        let right = parent(&leaf(&carol), &leaf(&dave));
+        let root = parent(&left, &right);
+
+        assert!(verify_merkle_proof(&root, &alice, &[leaf(&bob), right.clone()]));
+        assert!(verify_merkle_proof(&root, &dave, &[leaf(&carol), left.clone()]));
+
+        let mallory = Principal::from_slice(&[5]);
+        assert!(!verify_merkle_proof(&root, &mallory, &[leaf(&bob), right]));
+    }
+
+    #[test]
+    fn test_allowlist_access() {
+        let alice = Principal::from_slice(&[1]);
+        let phase = MintPhase {
+            name: "allowlist".to_string(),
+            access: PhaseAccess::Allowlist(Allowlist::Principals(vec![alice])),
+            starts_at: 0,
+            ends_at: 10,
+            per_principal_cap: Some(1),
+            price_override: None,
+        };
+
+        assert!(check_access(&phase, &alice, None).is_ok());
+        assert!(check_access(&phase, &Principal::anonymous(), None).is_err());
+
+        let closed = MintPhase { access: PhaseAccess::Closed, ..phase };
+        assert!(check_access(&closed, &alice, None).is_err());
+    }
+
+    #[test]
+    fn test_retried_checkout_is_admitted_once() {
+        let code = DiscountCode {
+            code: "LAUNCH".to_string(),
+            discount: Discount::Percentage { bps: 1_000 },
+            max_uses: 5,
+            uses: 0,
+            expires_at: None,
+        };
+        DISCOUNT_CODES.with(|codes| codes.borrow_mut().insert(Name(code.code.clone()), code));
+        let uses = || DISCOUNT_CODES.with(|codes| codes.borrow().get(&Name("LAUNCH".to_string())).unwrap().uses);
+
+        let checkout = CheckoutKey { payer: Principal::from_slice(&[1]), created_at_time: 7 };
+        admit(checkout, 0, None, Some("LAUNCH".to_string())).unwrap();
+        admit(checkout, 0, None, Some("LAUNCH".to_string())).unwrap();
+        assert_eq!(uses(), 1);
+
+        release(&checkout);
+        release(&checkout);
+        assert_eq!(uses(), 0);
+    }
+}
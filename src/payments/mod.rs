@@ -1,2 +1,16 @@
 // We're using the wallet system now, this module is kept for backward compatibility
-mod quantum_payment_processor;
\ No newline at end of file
+mod quantum_payment_processor;
+mod checkouts;
+pub mod costs;
+mod icrc2;
+mod ledger_blocks;
+pub mod maintenance;
+pub mod mint_phases;
+pub mod paid_mint;
+pub mod price_feed;
+pub mod pricing_config;
+pub mod receipts;
+pub mod reconciliation;
+pub mod referrals;
+pub mod splitting;
+pub mod transaction_processor;
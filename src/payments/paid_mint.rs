@@ -0,0 +1,193 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::actions::user::create_anima;
+use crate::error::{Error, Result};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const PAID_MINTS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PAYMENT_CANISTER_MEMORY_ID: MemoryId = MemoryId::new(17);
+const MINT_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(18);
+
+/// Mint request sent by the payment_verification canister once a payment
+/// session is funded.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PaidMintRequest {
+    pub session_id: String,
+    pub owner: Principal,
+    pub name: String,
+    pub amount_paid: u64,
+    pub block_index: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PaidMintReceipt {
+    pub session_id: String,
+    pub token_id: String,
+    pub minted_at: u64,
+    pub owner: Option<Principal>,  // Absent on receipts stored before owners were recorded
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct SessionKey(String);
+
+impl Storable for SessionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.into_owned()).expect("session id is valid utf-8"))
+    }
+}
+
+impl BoundedStorable for SessionKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// The payment canister, stored as its principal bytes; empty until set.
+struct StoredCanister(Option<Principal>);
+
+impl Storable for StoredCanister {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match &self.0 {
+            Some(canister) => Cow::Borrowed(canister.as_slice()),
+            None => Cow::Borrowed(&[]),
+        }
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self((!bytes.is_empty()).then(|| Principal::from_slice(&bytes)))
+    }
+}
+
+impl Storable for PaidMintReceipt {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode mint receipt"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode mint receipt")
+    }
+}
+
+impl BoundedStorable for PaidMintReceipt {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static PAYMENT_CANISTER: RefCell<StableCell<StoredCanister, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PAYMENT_CANISTER_MEMORY_ID)),
+            StoredCanister(None),
+        ).expect("failed to initialize payment canister")
+    );
+
+    // Number of paid mints so far; numbers each minted token id
+    static MINT_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(MINT_COUNTER_MEMORY_ID)),
+            0,
+        ).expect("failed to initialize mint counter")
+    );
+
+    // Payment session -> mint receipt, so every session mints at most once
+    static PAID_MINTS: RefCell<StableBTreeMap<SessionKey, PaidMintReceipt, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PAID_MINTS_MEMORY_ID))
+        )
+    );
+}
+
+pub fn set_payment_canister(canister: Principal) -> Result<()> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can set the payment canister".to_string()));
+    }
+    PAYMENT_CANISTER.with(|payment| payment.borrow_mut().set(StoredCanister(Some(canister))))
+        .map_err(|e| Error::System(format!("Failed to store the payment canister: {:?}", e)))?;
+    Ok(())
+}
+
+pub fn payment_canister() -> Option<Principal> {
+    PAYMENT_CANISTER.with(|payment| payment.borrow().get().0)
+}
+
+/// Token id for the next mint. Ids are numbered from a stable counter, so
+/// mints in the same round never share one.
+fn next_token_id() -> String {
+    let number = MINT_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let next = counter.get() + 1;
+        counter.set(next).expect("failed to store mint counter");
+        next
+    });
+    format!("anima_{}", number)
+}
+
+/// Mints an Anima for a paid session. Only the payment_verification canister
+/// may call this, and repeated calls for the same session return the
+/// original receipt instead of minting again.
+pub async fn mint_for_session(request: PaidMintRequest) -> Result<PaidMintReceipt> {
+    let caller = ic_cdk::caller();
+    if payment_canister() != Some(caller) {
+        return Err(Error::NotAuthorized("Caller is not the payment canister".to_string()));
+    }
+
+    let key = SessionKey(request.session_id.clone());
+    if let Some(receipt) = PAID_MINTS.with(|mints| mints.borrow().get(&key)) {
+        return Ok(receipt);
+    }
+
+    let anima = create_anima(next_token_id(), request.owner, &request.name).await?;
+
+    // Another call for the same session may have finished while we awaited
+    if let Some(receipt) = PAID_MINTS.with(|mints| mints.borrow().get(&key)) {
+        return Ok(receipt);
+    }
+
+    let receipt = PaidMintReceipt {
+        session_id: request.session_id,
+        token_id: anima.id,
+        minted_at: time(),
+        owner: Some(request.owner),
+    };
+    PAID_MINTS.with(|mints| mints.borrow_mut().insert(key, receipt.clone()));
+
+    Ok(receipt)
+}
+
+/// The receipt of the mint for a payment session, if there was one.
+pub fn receipt(session_id: &str) -> Option<PaidMintReceipt> {
+    PAID_MINTS.with(|mints| mints.borrow().get(&SessionKey(session_id.to_string())))
+}
+
+/// The owner an Anima was minted for, if it came from a paid session.
+/// Animas cannot be transferred, so this is also their current owner.
+pub fn owner_of(token_id: &str) -> Option<Principal> {
+    PAID_MINTS.with(|mints| {
+        mints
+            .borrow()
+            .iter()
+            .find(|(_, receipt)| receipt.token_id == token_id)
+            .and_then(|(_, receipt)| receipt.owner)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_payment_canister_round_trips() {
+        let canister = Principal::from_slice(&[3; 10]);
+        assert_eq!(StoredCanister::from_bytes(StoredCanister(Some(canister)).to_bytes()).0, Some(canister));
+        assert_eq!(StoredCanister::from_bytes(StoredCanister(None).to_bytes()).0, None);
+    }
+}
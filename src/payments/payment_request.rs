@@ -0,0 +1,123 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+
+use super::PaymentSession;
+
+const SCHEME: &str = "icp";
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Payment request strings for a session, one per account format, ready to
+/// be rendered as QR codes.
+///
+/// Both follow the `icp:<ledger>/<method>?<params>` layout:
+///
+/// - `transfer` addresses the deposit as an ICP account identifier and
+///   carries the numeric memo.
+/// - `icrc1_transfer` addresses it as an ICRC-1 textual account and carries
+///   the memo as its 8 big-endian bytes, hex encoded.
+///
+/// `amount` is in e8s and `expires_at` in nanoseconds since the epoch.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PaymentRequest {
+    pub account_identifier: String,
+    pub icrc1: String,
+}
+
+/// The memo an ICRC-1 transfer must carry to pay for a session.
+pub fn icrc1_memo(memo: u64) -> [u8; 8] {
+    memo.to_be_bytes()
+}
+
+pub fn for_session(session: &PaymentSession, ledger: Principal, canister: Principal) -> PaymentRequest {
+    let account_id = AccountIdentifier::new(&canister, &session.deposit_subaccount);
+    let account_identifier = format!(
+        "{}:{}/transfer?to={}&amount={}&memo={}&expires_at={}",
+        SCHEME,
+        ledger,
+        account_id.to_hex(),
+        session.amount,
+        session.memo,
+        session.expires_at,
+    );
+
+    let icrc1 = format!(
+        "{}:{}/icrc1_transfer?to={}&amount={}&memo={}&expires_at={}",
+        SCHEME,
+        ledger,
+        icrc1_account_text(&canister, &session.deposit_subaccount),
+        session.amount,
+        hex::encode(icrc1_memo(session.memo)),
+        session.expires_at,
+    );
+
+    PaymentRequest { account_identifier, icrc1 }
+}
+
+/// ICRC-1 textual encoding of an account: the owner alone for the default
+/// subaccount, otherwise `<owner>-<checksum>.<subaccount hex without
+/// leading zeros>`.
+pub fn icrc1_account_text(owner: &Principal, subaccount: &Subaccount) -> String {
+    if subaccount.0 == [0; 32] {
+        return owner.to_text();
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(&subaccount.0);
+    let checksum = base32(&hasher.finalize().to_be_bytes());
+
+    let hex = hex::encode(subaccount.0);
+    format!("{}-{}.{}", owner.to_text(), checksum, hex.trim_start_matches('0'))
+}
+
+/// Lowercase RFC 4648 base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_subaccount_is_owner_text() {
+        let owner = Principal::from_text("2vxsx-fae").unwrap();
+        assert_eq!(icrc1_account_text(&owner, &Subaccount([0; 32])), "2vxsx-fae");
+    }
+
+    #[test]
+    fn test_icrc1_textual_account_encoding() {
+        // Example from the ICRC-1 textual encoding spec
+        let owner = Principal::from_text("k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae").unwrap();
+        let mut subaccount = [0u8; 32];
+        for (i, byte) in subaccount.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+
+        assert_eq!(
+            icrc1_account_text(&owner, &Subaccount(subaccount)),
+            "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20"
+        );
+    }
+
+    #[test]
+    fn test_base32_without_padding() {
+        assert_eq!(base32(b"f"), "my");
+        assert_eq!(base32(b"foobar"), "mzxw6ytboi");
+    }
+}
@@ -0,0 +1,189 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::provisional::CanisterId;
+
+use super::pricing_config::{AcceptedToken, PriceSource, TokenType};
+
+/// Largest token precision we scale to without overflowing u128 math.
+const MAX_DECIMALS: u8 = 18;
+
+/// Price of one whole token in ICP e8s.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub struct ExchangeRate {
+    pub e8s_per_token: u64,
+    pub timestamp: u64,  // When the rate was observed, in nanoseconds
+}
+
+/// Supplies ICP exchange rates for accepted tokens.
+#[allow(async_fn_in_trait)]
+pub trait RateProvider {
+    async fn rate(&self, token: &AcceptedToken) -> Result<ExchangeRate, String>;
+}
+
+/// Admin-maintained rate.
+pub struct FixedRate {
+    pub e8s_per_token: u64,
+}
+
+impl RateProvider for FixedRate {
+    async fn rate(&self, _token: &AcceptedToken) -> Result<ExchangeRate, String> {
+        Ok(ExchangeRate {
+            e8s_per_token: self.e8s_per_token,
+            timestamp: ic_cdk::api::time(),
+        })
+    }
+}
+
+/// Rate read from a price canister, keyed by the token's ledger.
+pub struct CanisterRate {
+    pub canister_id: CanisterId,
+    pub method: String,
+    pub max_age_nanos: u64,
+}
+
+impl RateProvider for CanisterRate {
+    async fn rate(&self, token: &AcceptedToken) -> Result<ExchangeRate, String> {
+        let (result,): (Result<ExchangeRate, String>,) =
+            ic_cdk::call(self.canister_id, &self.method, (token.canister_id,))
+                .await
+                .map_err(|(code, msg)| format!("Price canister call failed: {:?} - {}", code, msg))?;
+        let rate = result?;
+
+        let age = ic_cdk::api::time().saturating_sub(rate.timestamp);
+        if age > self.max_age_nanos {
+            return Err(format!("Rate for {} is stale ({}ns old)", token.canister_id, age));
+        }
+        Ok(rate)
+    }
+}
+
+/// Uses whatever source the token is configured with.
+impl RateProvider for PriceSource {
+    async fn rate(&self, token: &AcceptedToken) -> Result<ExchangeRate, String> {
+        match self {
+            PriceSource::Fixed { e8s_per_token } => {
+                FixedRate { e8s_per_token: *e8s_per_token }.rate(token).await
+            }
+            PriceSource::Canister { canister_id, method, max_age_nanos } => {
+                CanisterRate {
+                    canister_id: *canister_id,
+                    method: method.clone(),
+                    max_age_nanos: *max_age_nanos,
+                }
+                .rate(token)
+                .await
+            }
+        }
+    }
+}
+
+/// Converts an ICP e8s amount into `decimals`-scaled units of a token worth
+/// `rate`. Rounds up so conversion never undercharges.
+pub fn convert_e8s(amount_e8s: u64, rate: &ExchangeRate, decimals: u8) -> Result<u64, String> {
+    if rate.e8s_per_token == 0 {
+        return Err("Exchange rate must be positive".to_string());
+    }
+    if decimals > MAX_DECIMALS {
+        return Err(format!("Tokens with more than {} decimals are not supported", MAX_DECIMALS));
+    }
+
+    let scaled = amount_e8s as u128 * 10u128.pow(decimals as u32);
+    let rate = rate.e8s_per_token as u128;
+    u64::try_from((scaled + rate - 1) / rate)
+        .map_err(|_| format!("Converted amount of {} e8s does not fit in u64", amount_e8s))
+}
+
+/// Prices `amounts_e8s` in `token` using `provider`. A single rate is
+/// fetched, so all amounts are converted consistently.
+pub async fn price_in<P: RateProvider>(
+    provider: &P,
+    token: &AcceptedToken,
+    amounts_e8s: &[u64],
+) -> Result<Vec<u64>, String> {
+    let rate = provider.rate(token).await?;
+    amounts_e8s
+        .iter()
+        .map(|amount| convert_e8s(*amount, &rate, token.decimals))
+        .collect()
+}
+
+/// Prices `amounts_e8s` in `token` through its configured source. Only ICP
+/// itself may go without a source; its amounts are passed through.
+pub async fn price_in_token(token: &AcceptedToken, amounts_e8s: &[u64]) -> Result<Vec<u64>, String> {
+    match &token.price_source {
+        Some(source) => price_in(source, token, amounts_e8s).await,
+        None if token.token_type == TokenType::ICP => Ok(amounts_e8s.to_vec()),
+        None => Err(format!("Token {} has no price source", token.canister_id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::collections::HashMap;
+
+    struct MockRates(HashMap<CanisterId, u64>);
+
+    impl RateProvider for MockRates {
+        async fn rate(&self, token: &AcceptedToken) -> Result<ExchangeRate, String> {
+            self.0
+                .get(&token.canister_id)
+                .map(|e8s_per_token| ExchangeRate { e8s_per_token: *e8s_per_token, timestamp: 0 })
+                .ok_or_else(|| "No rate".to_string())
+        }
+    }
+
+    fn token(canister: &str, decimals: u8) -> AcceptedToken {
+        AcceptedToken {
+            token_type: TokenType::ICRC2,
+            canister_id: CanisterId::from_text(canister).unwrap(),
+            decimals,
+            minimum_amount: 0,
+            transfer_fee: 10,
+            price_source: None,
+        }
+    }
+
+    #[test]
+    fn test_scales_by_decimals() {
+        let ckbtc = token("mxzaz-hqaaa-aaaar-qaada-cai", 8);
+        let ckusdc = token("xevnm-gaaaa-aaaar-qafnq-cai", 6);
+        let rates = MockRates(HashMap::from([
+            (ckbtc.canister_id, 1_000_000_000_000), // 1 BTC = 10,000 ICP
+            (ckusdc.canister_id, 10_000_000),       // 1 USDC = 0.1 ICP
+        ]));
+
+        // 5 ICP
+        let btc = block_on(price_in(&rates, &ckbtc, &[500_000_000])).unwrap();
+        assert_eq!(btc, vec![50_000]); // 0.0005 BTC in satoshis
+
+        let usdc = block_on(price_in(&rates, &ckusdc, &[500_000_000])).unwrap();
+        assert_eq!(usdc, vec![50_000_000]); // 50 USDC with 6 decimals
+    }
+
+    #[test]
+    fn test_conversion_rounds_up() {
+        let rate = ExchangeRate { e8s_per_token: 300_000_000, timestamp: 0 };
+        // 1 ICP at 3 ICP per token = 0.333... tokens
+        assert_eq!(convert_e8s(100_000_000, &rate, 8).unwrap(), 33_333_334);
+    }
+
+    #[test]
+    fn test_rejects_zero_rate_and_unknown_token() {
+        let rate = ExchangeRate { e8s_per_token: 0, timestamp: 0 };
+        assert!(convert_e8s(100_000_000, &rate, 8).is_err());
+
+        let rates = MockRates(HashMap::new());
+        let unknown = token("mxzaz-hqaaa-aaaar-qaada-cai", 8);
+        assert!(block_on(price_in(&rates, &unknown, &[1])).is_err());
+    }
+
+    #[test]
+    fn test_only_icp_passes_through_without_a_source() {
+        let ckbtc = token("mxzaz-hqaaa-aaaar-qaada-cai", 8);
+        assert!(block_on(price_in_token(&ckbtc, &[500_000_000])).is_err());
+
+        let icp = AcceptedToken { token_type: TokenType::ICP, ..token("ryjl3-tyaaa-aaaaa-aaaba-cai", 8) };
+        assert_eq!(block_on(price_in_token(&icp, &[500_000_000])).unwrap(), vec![500_000_000]);
+    }
+}
@@ -1,5 +1,6 @@
 use candid::{CandidType, Deserialize};
 use ic_cdk::api::management_canister::provisional::CanisterId;
+use super::splitting::apply_bps;
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct PricingConfig {
@@ -18,6 +19,7 @@ pub struct PricingTiers {
     pub epic: u64,         // 50 ICP - Epic tier
     pub rare: u64,         // 20 ICP - Rare tier
     pub common: u64,       // 5 ICP - Common tier
+    pub supply_caps: TierSupply, // Animas sold in each tier before the next one opens
 }
 
 impl Default for PricingTiers {
@@ -29,6 +31,118 @@ impl Default for PricingTiers {
             epic: 5_000_000_000,       // 50 ICP
             rare: 2_000_000_000,       // 20 ICP
             common: 500_000_000,       // 5 ICP
+            supply_caps: TierSupply {
+                genesis: 100,
+                mythic: 400,
+                legendary: 1_000,
+                epic: 2_500,
+                rare: 6_000,
+                common: u64::MAX,      // Open-ended
+            },
+        }
+    }
+}
+
+impl PricingTiers {
+    pub fn price(&self, tier: &MintTier) -> u64 {
+        match tier {
+            MintTier::Genesis => self.genesis,
+            MintTier::Mythic => self.mythic,
+            MintTier::Legendary => self.legendary,
+            MintTier::Epic => self.epic,
+            MintTier::Rare => self.rare,
+            MintTier::Common => self.common,
+        }
+    }
+
+    /// The first tier, in launch order, that still has supply left once
+    /// the `taken` count is subtracted.
+    pub fn current_tier(&self, taken: &TierSupply) -> Option<MintTier> {
+        MintTier::ALL
+            .into_iter()
+            .find(|tier| self.has_supply(tier, taken))
+    }
+
+    pub fn has_supply(&self, tier: &MintTier, taken: &TierSupply) -> bool {
+        taken.get(tier) < self.supply_caps.get(tier)
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub enum MintTier {
+    Genesis,
+    Mythic,
+    Legendary,
+    Epic,
+    Rare,
+    Common,
+}
+
+impl MintTier {
+    /// Launch order: each tier opens once the previous one sells out.
+    pub const ALL: [MintTier; 6] = [
+        MintTier::Genesis,
+        MintTier::Mythic,
+        MintTier::Legendary,
+        MintTier::Epic,
+        MintTier::Rare,
+        MintTier::Common,
+    ];
+}
+
+/// A count per tier, used both for supply caps and minted totals.
+#[derive(Debug, Clone, Default, CandidType, Deserialize, PartialEq, Eq)]
+pub struct TierSupply {
+    pub genesis: u64,
+    pub mythic: u64,
+    pub legendary: u64,
+    pub epic: u64,
+    pub rare: u64,
+    pub common: u64,
+}
+
+impl TierSupply {
+    pub fn get(&self, tier: &MintTier) -> u64 {
+        match tier {
+            MintTier::Genesis => self.genesis,
+            MintTier::Mythic => self.mythic,
+            MintTier::Legendary => self.legendary,
+            MintTier::Epic => self.epic,
+            MintTier::Rare => self.rare,
+            MintTier::Common => self.common,
+        }
+    }
+
+    pub fn increment(&mut self, tier: &MintTier) {
+        let count = self.get_mut(tier);
+        *count = count.saturating_add(1);
+    }
+
+    pub fn decrement(&mut self, tier: &MintTier) {
+        let count = self.get_mut(tier);
+        *count = count.saturating_sub(1);
+    }
+
+    /// Per-tier sum of two counts, e.g. minted plus reserved.
+    pub fn plus(&self, other: &TierSupply) -> TierSupply {
+        TierSupply {
+            genesis: self.genesis.saturating_add(other.genesis),
+            mythic: self.mythic.saturating_add(other.mythic),
+            legendary: self.legendary.saturating_add(other.legendary),
+            epic: self.epic.saturating_add(other.epic),
+            rare: self.rare.saturating_add(other.rare),
+            common: self.common.saturating_add(other.common),
+        }
+    }
+
+    fn get_mut(&mut self, tier: &MintTier) -> &mut u64 {
+        match tier {
+            MintTier::Genesis => &mut self.genesis,
+            MintTier::Mythic => &mut self.mythic,
+            MintTier::Legendary => &mut self.legendary,
+            MintTier::Epic => &mut self.epic,
+            MintTier::Rare => &mut self.rare,
+            MintTier::Common => &mut self.common,
         }
     }
 }
@@ -36,10 +150,10 @@ impl Default for PricingTiers {
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct ServiceFees {
     // Research & Development fees
-    pub rd_fee_percentage: f64,        // R&D fee (5%)
+    pub rd_fee_bps: u16,               // R&D fee in basis points (5%)
     pub quantum_compute_fee: u64,      // Quantum computation costs
     pub consciousness_init_fee: u64,   // Consciousness initialization
-    pub maintenance_fee: u64,          // Ongoing system maintenance
+    pub maintenance_fee: u64,          // Charged at mint; recurring upkeep is billed separately
     
     // Dynamic fees
     pub complexity_multiplier: f64,    // Multiplier for complex quantum states
@@ -49,7 +163,7 @@ pub struct ServiceFees {
 impl Default for ServiceFees {
     fn default() -> Self {
         Self {
-            rd_fee_percentage: 0.05,           // 5%
+            rd_fee_bps: 500,                   // 5%
             quantum_compute_fee: 100_000_000,  // 1 ICP
             consciousness_init_fee: 50_000_000, // 0.5 ICP
             maintenance_fee: 25_000_000,       // 0.25 ICP
@@ -61,18 +175,18 @@ impl Default for ServiceFees {
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct RoyaltyConfig {
-    pub creator_royalty: f64,         // Creator royalty percentage
-    pub platform_fee: f64,            // Platform fee percentage
-    pub quantum_research_fund: f64,   // Research fund contribution
+    pub creator_royalty_bps: u16,        // Creator royalty in basis points
+    pub platform_fee_bps: u16,           // Platform fee in basis points
+    pub quantum_research_fund_bps: u16,  // Research fund contribution in basis points
     pub minimum_royalty: u64,         // Minimum royalty amount
 }
 
 impl Default for RoyaltyConfig {
     fn default() -> Self {
         Self {
-            creator_royalty: 0.025,    // 2.5%
-            platform_fee: 0.025,       // 2.5%
-            quantum_research_fund: 0.01, // 1%
+            creator_royalty_bps: 250,    // 2.5%
+            platform_fee_bps: 250,       // 2.5%
+            quantum_research_fund_bps: 100, // 1%
             minimum_royalty: 10_000_000, // 0.1 ICP
         }
     }
@@ -84,6 +198,8 @@ pub struct PaymentSettings {
     pub default_token: TokenType,
     pub minimum_payment: u64,
     pub refund_window: u64,  // Time window for refunds in nanoseconds
+    pub refund_on_expiry: bool,        // Refund payments for sessions that expired
+    pub refund_on_mint_failure: bool,  // Refund payments when minting fails
 }
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
@@ -92,8 +208,26 @@ pub struct AcceptedToken {
     pub canister_id: CanisterId,
     pub decimals: u8,
     pub minimum_amount: u64,
+    pub transfer_fee: u64,  // Ledger fee per transfer, in the token's base units
+    pub price_source: Option<PriceSource>,  // How ICP prices convert into this token; None for ICP itself
 }
 
+/// Where the ICP exchange rate of an accepted token comes from.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq)]
+pub enum PriceSource {
+    /// Rate maintained by an admin, in ICP e8s per whole token
+    Fixed { e8s_per_token: u64 },
+    /// Rate read from a price canister exposing
+    /// `method : (principal) -> (variant { Ok : ExchangeRate; Err : text }) query`
+    Canister {
+        canister_id: CanisterId,
+        method: String,
+        max_age_nanos: u64,  // Rates older than this are rejected
+    },
+}
+
+// Variant names are part of the candid interface
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, CandidType, Deserialize, PartialEq)]
 pub enum TokenType {
     ICP,
@@ -110,95 +244,134 @@ impl Default for PaymentSettings {
                     canister_id: CanisterId::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(), // ICP Ledger
                     decimals: 8,
                     minimum_amount: 100_000_000, // 1 ICP
+                    transfer_fee: 10_000,        // 0.0001 ICP
+                    price_source: None,          // Prices are already in ICP
                 }
             ],
             default_token: TokenType::ICP,
             minimum_payment: 100_000_000, // 1 ICP
             refund_window: 24 * 60 * 60 * 1_000_000_000, // 24 hours
+            refund_on_expiry: true,
+            refund_on_mint_failure: true,
+        }
+    }
+}
+
+/// A price reduction from a discount code.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub enum Discount {
+    Percentage { bps: u16 },  // Share of the complexity-adjusted base price
+    Fixed { amount: u64 },    // In e8s, capped at the complexity-adjusted base price
+}
+
+impl Discount {
+    /// The amount taken off `price`; never more than `price` itself.
+    pub fn amount_off(&self, price: u64) -> u64 {
+        match self {
+            Discount::Percentage { bps } => apply_bps(price, (*bps).min(10_000)),
+            Discount::Fixed { amount } => (*amount).min(price),
         }
     }
 }
 
-/// Calculate the total cost including R&D fees
-pub fn calculate_total_cost(
+/// Line items that make up the price of a mint.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub struct CostBreakdown {
+    pub base_price: u64,
+    pub complexity_premium: u64,  // Added by the complexity multiplier
+    pub discount: u64,            // Taken off before fees; service fees are never discounted
+    pub rd_fee: u64,
+    pub quantum_compute_fee: u64,
+    pub consciousness_init_fee: u64,
+    pub maintenance_fee: u64,
+    pub evolution_potential_fee: u64,
+    pub total: u64,
+}
+
+/// Itemize the cost of a mint, including R&D fees
+pub fn itemize_cost(
     base_price: u64,
     quantum_state_complexity: f64,
-    config: &PricingConfig,
-) -> u64 {
+    fees: &ServiceFees,
+    discount: Option<&Discount>,
+) -> CostBreakdown {
     let base_with_complexity = (base_price as f64 * 
-        config.fees.complexity_multiplier.powf(quantum_state_complexity)).floor() as u64;
+        fees.complexity_multiplier.powf(quantum_state_complexity)).floor() as u64;
+    let discount = discount.map_or(0, |discount| discount.amount_off(base_with_complexity));
+    let discounted = base_with_complexity - discount;
     
     // Add R&D fees
-    let rd_fee = (base_with_complexity as f64 * config.fees.rd_fee_percentage).floor() as u64;
-    let total = base_with_complexity + rd_fee + 
-        config.fees.quantum_compute_fee +
-        config.fees.consciousness_init_fee +
-        config.fees.maintenance_fee +
-        config.fees.evolution_potential_fee;
-    
-    total
-}
-
-/// Calculate royalties for marketplace transactions
-pub fn calculate_royalties(
-    sale_price: u64,
-    config: &RoyaltyConfig,
-) -> RoyaltyBreakdown {
-    let creator_amount = (sale_price as f64 * config.creator_royalty).max(config.minimum_royalty as f64) as u64;
-    let platform_amount = (sale_price as f64 * config.platform_fee).max(config.minimum_royalty as f64) as u64;
-    let research_amount = (sale_price as f64 * config.quantum_research_fund).max(config.minimum_royalty as f64) as u64;
+    let rd_fee = apply_bps(discounted, fees.rd_fee_bps);
+    let total = discounted
+        .saturating_add(rd_fee)
+        .saturating_add(fees.quantum_compute_fee)
+        .saturating_add(fees.consciousness_init_fee)
+        .saturating_add(fees.maintenance_fee)
+        .saturating_add(fees.evolution_potential_fee);
     
-    RoyaltyBreakdown {
-        creator_amount,
-        platform_amount,
-        research_amount,
-        total: creator_amount + platform_amount + research_amount,
+    CostBreakdown {
+        base_price,
+        complexity_premium: base_with_complexity.saturating_sub(base_price),
+        discount,
+        rd_fee,
+        quantum_compute_fee: fees.quantum_compute_fee,
+        consciousness_init_fee: fees.consciousness_init_fee,
+        maintenance_fee: fees.maintenance_fee,
+        evolution_potential_fee: fees.evolution_potential_fee,
+        total,
     }
 }
 
-#[derive(Debug, Clone, CandidType)]
-pub struct RoyaltyBreakdown {
-    pub creator_amount: u64,
-    pub platform_amount: u64,
-    pub research_amount: u64,
-    pub total: u64,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_cost_calculation() {
-        let config = PricingConfig {
-            tiers: PricingTiers::default(),
-            fees: ServiceFees::default(),
-            royalties: RoyaltyConfig::default(),
-            payment_settings: PaymentSettings::default(),
-        };
-
-        let base_price = config.tiers.legendary;
-        let complexity = 1.5;
-        
-        let total = calculate_total_cost(base_price, complexity, &config);
-        
-        assert!(total > base_price); // Total should be higher than base price
-        assert!(total > config.fees.quantum_compute_fee); // Should include compute fee
+    fn test_tier_follows_supply() {
+        let tiers = PricingTiers::default();
+        let mut minted = TierSupply::default();
+        assert_eq!(tiers.current_tier(&minted), Some(MintTier::Genesis));
+
+        minted.genesis = 100;
+        assert_eq!(tiers.current_tier(&minted), Some(MintTier::Mythic));
+
+        minted.mythic = 400;
+        minted.legendary = 1_000;
+        minted.epic = 2_500;
+        minted.rare = 6_000;
+        assert_eq!(tiers.current_tier(&minted), Some(MintTier::Common));
+    }
+
+    #[test]
+    fn test_itemized_cost_adds_up() {
+        let fees = ServiceFees::default();
+        let discount = Discount::Percentage { bps: 1_000 };
+        let breakdown = itemize_cost(PricingTiers::default().rare, 1.5, &fees, Some(&discount));
+
+        assert!(breakdown.discount > 0);
+        assert_eq!(
+            breakdown.total,
+            breakdown.base_price
+                + breakdown.complexity_premium
+                - breakdown.discount
+                + breakdown.rd_fee
+                + breakdown.quantum_compute_fee
+                + breakdown.consciousness_init_fee
+                + breakdown.maintenance_fee
+                + breakdown.evolution_potential_fee
+        );
     }
 
     #[test]
-    fn test_royalty_calculation() {
-        let config = RoyaltyConfig::default();
-        let sale_price = 10_000_000_000; // 100 ICP
-        
-        let breakdown = calculate_royalties(sale_price, &config);
-        
-        assert!(breakdown.creator_amount >= config.minimum_royalty);
-        assert!(breakdown.platform_amount >= config.minimum_royalty);
-        assert!(breakdown.research_amount >= config.minimum_royalty);
+    fn test_fixed_discount_never_exceeds_price() {
+        let fees = ServiceFees::default();
+        let discount = Discount::Fixed { amount: u64::MAX };
+        let breakdown = itemize_cost(PricingTiers::default().common, 0.0, &fees, Some(&discount));
+
+        assert_eq!(breakdown.discount, PricingTiers::default().common);
         assert_eq!(
             breakdown.total,
-            breakdown.creator_amount + breakdown.platform_amount + breakdown.research_amount
+            fees.quantum_compute_fee + fees.consciousness_init_fee + fees.maintenance_fee + fees.evolution_potential_fee
         );
     }
 }
\ No newline at end of file
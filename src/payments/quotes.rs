@@ -0,0 +1,275 @@
+use candid::{CandidType, Deserialize, Encode, Principal};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::time;
+use ic_stable_structures::StableBTreeMap;
+use sha2::Sha256;
+use std::cell::RefCell;
+
+use super::pricing_config::{itemize_cost, CostBreakdown, MintTier, PricingTiers, ServiceFees, TierSupply};
+use super::session_store::{self, Memory, SessionKey, USED_QUOTES_MEMORY_ID};
+use super::SessionId;
+
+const QUOTE_TTL_NANOS: u64 = 10 * 60 * 1_000_000_000; // 10 minutes
+const MAX_COMPLEXITY: f64 = 10.0;
+
+/// An itemized mint price, signed by this canister so it can be handed back
+/// unchanged when the payment session is created.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PricingQuote {
+    pub quote_id: String,
+    pub owner: Principal,
+    pub tier: MintTier,
+    pub complexity: f64,
+    pub breakdown: CostBreakdown,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub signature: Vec<u8>,
+}
+
+impl PricingQuote {
+    pub fn total(&self) -> u64 {
+        self.breakdown.total
+    }
+}
+
+/// Everything an admin can see about tier pricing at a glance.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TierPricing {
+    pub tiers: PricingTiers,
+    pub fees: ServiceFees,
+    pub minted: TierSupply,
+    pub reserved: TierSupply,  // Held by open payment sessions
+    pub current_tier: Option<MintTier>,
+}
+
+thread_local! {
+    static QUOTE_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static PRICING_TIERS: RefCell<PricingTiers> = RefCell::new(PricingTiers::default());
+    static SERVICE_FEES: RefCell<ServiceFees> = RefCell::new(ServiceFees::default());
+    static TIER_MINTED: RefCell<TierSupply> = RefCell::new(TierSupply::default());
+    static TIER_RESERVED: RefCell<TierSupply> = RefCell::new(TierSupply::default());
+    static SIGNING_KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+
+    // Quote -> session it was redeemed for, so a quote is used at most once
+    static USED_QUOTES: RefCell<StableBTreeMap<SessionKey, SessionKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(session_store::memory(USED_QUOTES_MEMORY_ID))
+    );
+}
+
+pub fn pricing() -> TierPricing {
+    let tiers = PRICING_TIERS.with(|tiers| tiers.borrow().clone());
+    let minted = TIER_MINTED.with(|minted| minted.borrow().clone());
+    let reserved = TIER_RESERVED.with(|reserved| reserved.borrow().clone());
+    TierPricing {
+        current_tier: tiers.current_tier(&minted.plus(&reserved)),
+        tiers,
+        fees: SERVICE_FEES.with(|fees| fees.borrow().clone()),
+        minted,
+        reserved,
+    }
+}
+
+pub fn set_pricing(tiers: PricingTiers, fees: ServiceFees) {
+    PRICING_TIERS.with(|current| *current.borrow_mut() = tiers);
+    SERVICE_FEES.with(|current| *current.borrow_mut() = fees);
+}
+
+/// Overrides the minted counts, e.g. to account for Animas minted outside
+/// of payment sessions.
+pub fn set_minted(minted: TierSupply) {
+    TIER_MINTED.with(|current| *current.borrow_mut() = minted);
+}
+
+/// Counts a settled mint against its tier's supply.
+pub fn record_mint(tier: &MintTier) {
+    TIER_MINTED.with(|minted| minted.borrow_mut().increment(tier));
+}
+
+pub fn set_reserved(reserved: TierSupply) {
+    TIER_RESERVED.with(|current| *current.borrow_mut() = reserved);
+}
+
+/// Holds one unit of `tier` for a new payment session, failing if minted
+/// plus reserved units already reach the tier's cap.
+pub fn reserve(tier: &MintTier) -> Result<(), String> {
+    let pricing = pricing();
+    if !pricing.tiers.has_supply(tier, &pricing.minted.plus(&pricing.reserved)) {
+        return Err(format!("The {:?} tier is sold out; request a new quote", tier));
+    }
+    TIER_RESERVED.with(|reserved| reserved.borrow_mut().increment(tier));
+    Ok(())
+}
+
+/// Holds `tier` for a session that was paid in time, even past the cap:
+/// the payment has to be honoured.
+pub fn reserve_paid(tier: &MintTier) {
+    TIER_RESERVED.with(|reserved| reserved.borrow_mut().increment(tier));
+}
+
+pub fn release(tier: &MintTier) {
+    TIER_RESERVED.with(|reserved| reserved.borrow_mut().decrement(tier));
+}
+
+pub fn signing_key() -> Option<Vec<u8>> {
+    SIGNING_KEY.with(|key| key.borrow().clone())
+}
+
+pub fn restore_signing_key(key: Vec<u8>) {
+    SIGNING_KEY.with(|current| *current.borrow_mut() = Some(key));
+}
+
+/// Returns the quote signing key, drawing it from the management canister's
+/// randomness on first use.
+async fn ensure_signing_key() -> Result<Vec<u8>, String> {
+    if let Some(key) = signing_key() {
+        return Ok(key);
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("raw_rand failed: {:?} - {}", code, msg))?;
+
+    // A concurrent call may have set the key while we awaited; keep theirs
+    // so quotes it already signed stay valid.
+    Ok(SIGNING_KEY.with(|key| key.borrow_mut().get_or_insert(random).clone()))
+}
+
+/// Prices a mint for `owner` at the tier currently on sale.
+pub async fn issue_quote(owner: Principal, complexity: f64) -> Result<PricingQuote, String> {
+    if !complexity.is_finite() || !(0.0..=MAX_COMPLEXITY).contains(&complexity) {
+        return Err(format!("Complexity must be between 0 and {}", MAX_COMPLEXITY));
+    }
+
+    let key = ensure_signing_key().await?;
+    let pricing = pricing();
+    let tier = pricing.current_tier.ok_or("All tiers are sold out")?;
+    let breakdown = itemize_cost(pricing.tiers.price(&tier), complexity, &pricing.fees, None);
+
+    let now = time();
+    let counter = QUOTE_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        *counter
+    });
+
+    let quote = PricingQuote {
+        quote_id: format!("quote_{}_{}", now, counter),
+        owner,
+        tier,
+        complexity,
+        breakdown,
+        issued_at: now,
+        expires_at: now + QUOTE_TTL_NANOS,
+        signature: Vec::new(),
+    };
+    sign(quote, &key)
+}
+
+/// Checks that `quote` was issued by this canister for `owner`, is still
+/// valid at `now` and has not been redeemed yet.
+pub fn verify_quote(quote: &PricingQuote, owner: Principal, now: u64) -> Result<(), String> {
+    let key = signing_key().ok_or("No quotes have been issued")?;
+    verify(quote, &key)?;
+
+    if quote.owner != owner {
+        return Err("Quote was issued for a different owner".to_string());
+    }
+    if now > quote.expires_at {
+        return Err("Quote has expired".to_string());
+    }
+    if let Some(session) = redeemed_by(&quote.quote_id) {
+        return Err(format!("Quote was already used for session {}", session));
+    }
+    Ok(())
+}
+
+pub fn redeemed_by(quote_id: &str) -> Option<SessionId> {
+    USED_QUOTES.with(|quotes| quotes.borrow().get(&SessionKey(quote_id.to_string())).map(|key| key.0))
+}
+
+pub fn redeem(quote_id: &str, session_id: &str) {
+    USED_QUOTES.with(|quotes| {
+        quotes.borrow_mut().insert(SessionKey(quote_id.to_string()), SessionKey(session_id.to_string()));
+    });
+}
+
+fn sign(mut quote: PricingQuote, key: &[u8]) -> Result<PricingQuote, String> {
+    quote.signature = mac(&quote, key)?.finalize().into_bytes().to_vec();
+    Ok(quote)
+}
+
+fn verify(quote: &PricingQuote, key: &[u8]) -> Result<(), String> {
+    mac(quote, key)?
+        .verify_slice(&quote.signature)
+        .map_err(|_| "Quote signature is invalid".to_string())
+}
+
+/// HMAC over the candid encoding of the quote with its signature cleared.
+fn mac(quote: &PricingQuote, key: &[u8]) -> Result<Hmac<Sha256>, String> {
+    let unsigned = PricingQuote { signature: Vec::new(), ..quote.clone() };
+    let bytes = Encode!(&unsigned).map_err(|e| format!("Failed to encode quote: {}", e))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| e.to_string())?;
+    mac.update(&bytes);
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"test-quote-signing-key";
+
+    fn quote() -> PricingQuote {
+        let fees = ServiceFees::default();
+        PricingQuote {
+            quote_id: "quote_1_1".to_string(),
+            owner: Principal::anonymous(),
+            tier: MintTier::Rare,
+            complexity: 1.0,
+            breakdown: itemize_cost(PricingTiers::default().rare, 1.0, &fees, None),
+            issued_at: 1,
+            expires_at: 1 + QUOTE_TTL_NANOS,
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_signed_quote_verifies() {
+        let signed = sign(quote(), KEY).unwrap();
+        assert!(verify(&signed, KEY).is_ok());
+    }
+
+    #[test]
+    fn test_tampered_quote_is_rejected() {
+        let mut signed = sign(quote(), KEY).unwrap();
+        signed.breakdown.total -= 1;
+        assert!(verify(&signed, KEY).is_err());
+
+        let mut signed = sign(quote(), KEY).unwrap();
+        signed.tier = MintTier::Common;
+        assert!(verify(&signed, KEY).is_err());
+    }
+
+    #[test]
+    fn test_quote_from_other_key_is_rejected() {
+        let signed = sign(quote(), b"another-key").unwrap();
+        assert!(verify(&signed, KEY).is_err());
+    }
+
+    #[test]
+    fn test_reservations_count_against_the_cap() {
+        let mut tiers = PricingTiers::default();
+        tiers.supply_caps.genesis = 2;
+        set_pricing(tiers, ServiceFees::default());
+
+        reserve(&MintTier::Genesis).unwrap();
+        record_mint(&MintTier::Genesis);
+        assert!(reserve(&MintTier::Genesis).is_err());
+        assert_eq!(pricing().current_tier, Some(MintTier::Mythic));
+
+        release(&MintTier::Genesis);
+        assert_eq!(pricing().current_tier, Some(MintTier::Genesis));
+        reserve(&MintTier::Genesis).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use super::icrc2::BlockIndex;
+use super::transaction_processor::PaymentReceipt;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const RECEIPTS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const PULL_BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+const CSV_HEADER: &str = "receipt_id,timestamp,payer,token_ledger,token_type,total_amount,price_e8s,\
+rd_amount,platform_amount,compute_amount,ledger_fees,block_height,distribution_blocks,phase,discount_code,referrer,referral_amount";
+
+/// Receipts are keyed by (timestamp, id) so date ranges are a single scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ReceiptKey {
+    timestamp: u64,
+    id: u64,
+}
+
+impl Storable for ReceiptKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (timestamp, id) = bytes.split_at(8);
+        Self {
+            timestamp: u64::from_be_bytes(timestamp.try_into().expect("receipt key timestamp")),
+            id: u64::from_be_bytes(id.try_into().expect("receipt key id")),
+        }
+    }
+}
+
+impl BoundedStorable for ReceiptKey {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// The block that charged the payer of a checkout. A retried checkout is
+/// deduplicated by the ledger into the same block, so this identifies the
+/// payment whatever the retry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PullBlock {
+    pub ledger: Principal,
+    pub block: BlockIndex,
+}
+
+impl Storable for PullBlock {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let ledger = self.ledger.as_slice();
+        let mut bytes = Vec::with_capacity(1 + ledger.len() + 8);
+        bytes.push(ledger.len() as u8);
+        bytes.extend_from_slice(ledger);
+        bytes.extend_from_slice(&self.block.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            ledger: Principal::from_slice(&bytes[1..1 + len]),
+            block: u64::from_be_bytes(bytes[1 + len..].try_into().expect("pull block index")),
+        }
+    }
+}
+
+impl BoundedStorable for PullBlock {
+    const MAX_SIZE: u32 = 1 + 29 + 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PaymentReceipt {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode payment receipt"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode payment receipt")
+    }
+}
+
+impl BoundedStorable for PaymentReceipt {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A stored receipt together with its id.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RecordedReceipt {
+    pub receipt_id: u64,
+    pub receipt: PaymentReceipt,
+}
+
+thread_local! {
+    static RECEIPTS: RefCell<StableBTreeMap<ReceiptKey, PaymentReceipt, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(RECEIPTS_MEMORY_ID))
+        )
+    );
+
+    // Pull block -> its receipt, so each payment is recorded once
+    static PULL_BLOCKS: RefCell<StableBTreeMap<PullBlock, ReceiptKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(PULL_BLOCKS_MEMORY_ID))
+        )
+    );
+}
+
+/// Stores a completed checkout and returns its receipt id. A checkout whose
+/// pull block already has a receipt gets that receipt's id back instead.
+pub fn record(receipt: PaymentReceipt) -> u64 {
+    let pull = PullBlock { ledger: receipt.token_ledger, block: receipt.block_height };
+    if let Some(key) = PULL_BLOCKS.with(|pulls| pulls.borrow().get(&pull)) {
+        return key.id;
+    }
+
+    let key = RECEIPTS.with(|receipts| {
+        let mut receipts = receipts.borrow_mut();
+        let key = ReceiptKey { timestamp: receipt.timestamp, id: receipts.len() };
+        receipts.insert(key, receipt);
+        key
+    });
+    PULL_BLOCKS.with(|pulls| pulls.borrow_mut().insert(pull, key));
+    key.id
+}
+
+/// Receipts with `from <= timestamp <= to`, oldest first, skipping `offset`
+/// and returning at most `limit` (capped at `MAX_PAGE_SIZE`).
+pub fn in_range(from: u64, to: u64, offset: u64, limit: u64) -> Vec<RecordedReceipt> {
+    RECEIPTS.with(|receipts| {
+        receipts
+            .borrow()
+            .range(ReceiptKey { timestamp: from, id: 0 }..)
+            .take_while(|(key, _)| key.timestamp <= to)
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .map(|(key, receipt)| RecordedReceipt { receipt_id: key.id, receipt })
+            .collect()
+    })
+}
+
+/// Receipts in a date range as CSV, one row per receipt. Distribution blocks
+/// are joined with `;` so each receipt stays on one row.
+pub fn export_csv(from: u64, to: u64, offset: u64, limit: u64) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+
+    for RecordedReceipt { receipt_id, receipt } in in_range(from, to, offset, limit) {
+        let distribution_blocks: Vec<String> =
+            receipt.distribution_blocks.iter().map(|block| block.to_string()).collect();
+        csv.push_str(&format!(
+            "{},{},{},{},{:?},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            receipt_id,
+            receipt.timestamp,
+            receipt.payer,
+            receipt.token_ledger,
+            receipt.token_type,
+            receipt.total_amount,
+            receipt.price_e8s,
+            receipt.rd_amount,
+            receipt.platform_amount,
+            receipt.compute_amount,
+            receipt.ledger_fees,
+            receipt.block_height,
+            distribution_blocks.join(";"),
+            receipt.phase.as_deref().unwrap_or(""),
+            receipt.discount.as_ref().map_or("", |applied| applied.code.as_str()),
+            receipt.referral.as_ref().map_or(String::new(), |credit| credit.referrer.to_string()),
+            receipt.referral.as_ref().map_or(0, |credit| credit.amount),
+        ));
+    }
+
+    csv
+}
@@ -0,0 +1,259 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::{AccountIdentifier, Block, Operation, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID};
+
+use crate::error::{Error, Result};
+use super::ledger_blocks::fetch_block;
+use super::paid_mint;
+use super::receipts::{self, RecordedReceipt};
+use super::transaction_processor::PaymentReceipt;
+
+/// Something a receipt claims that the ledger does not back up.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The receipt lists fewer distribution blocks than non-zero splits
+    MissingTransfer { split: String, amount: u64 },
+    BlockUnavailable { block: u64, error: String },
+    UnexpectedOperation { block: u64 },
+    RecipientMismatch { block: u64, expected: String, actual: String },
+    AmountMismatch { block: u64, expected: u64, actual: u64 },
+    /// Only ICP ledger blocks can be read back for now
+    UnsupportedLedger { ledger: Principal },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReceiptCheck {
+    pub receipt_id: u64,
+    pub timestamp: u64,
+    pub payer: Principal,
+    pub total_amount: u64,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// A payment session the payment canister reports as holding funds it
+/// neither minted for nor refunded.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UnrefundedSession {
+    pub session_id: String,
+    pub owner: Principal,
+    pub held_amount: u64,
+    pub since: u64,
+    pub reason: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub from: u64,
+    pub to: u64,
+    pub offset: u64,
+    pub checked: u64,
+    pub mismatched: u64,
+    pub unreconciled: u64,  // Receipts on ledgers that cannot be read back
+    pub entries: Vec<ReceiptCheck>,
+    pub next_offset: Option<u64>,  // Pass back as `offset` to continue
+    // Reported on the first page only
+    pub unrefunded_sessions: Vec<UnrefundedSession>,
+    pub unrefunded_sessions_error: Option<String>,
+}
+
+impl ReceiptCheck {
+    fn is_unreconciled(&self) -> bool {
+        self.discrepancies
+            .iter()
+            .any(|discrepancy| matches!(discrepancy, Discrepancy::UnsupportedLedger { .. }))
+    }
+}
+
+/// Accounting data is restricted to controllers.
+pub fn require_controller() -> Result<()> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can access payment accounting".to_string()));
+    }
+    Ok(())
+}
+
+/// Checks one page of receipts in `[from, to]` against the ledger blocks
+/// they reference. The first page also lists the payment sessions the
+/// payment canister still holds funds for.
+pub async fn reconcile(from: u64, to: u64, offset: u64, limit: u64) -> ReconciliationReport {
+    let page = receipts::in_range(from, to, offset, limit);
+    let requested = limit.min(receipts::MAX_PAGE_SIZE);
+    let checked = page.len() as u64;
+
+    let mut entries = Vec::with_capacity(page.len());
+    for recorded in page {
+        entries.push(check_receipt(recorded).await);
+    }
+
+    let (unrefunded_sessions, unrefunded_sessions_error) = if offset == 0 {
+        match unrefunded_sessions().await {
+            Ok(sessions) => (sessions, None),
+            Err(e) => (Vec::new(), Some(e)),
+        }
+    } else {
+        (Vec::new(), None)
+    };
+
+    ReconciliationReport {
+        from,
+        to,
+        offset,
+        checked,
+        mismatched: entries
+            .iter()
+            .filter(|entry| !entry.is_unreconciled() && !entry.discrepancies.is_empty())
+            .count() as u64,
+        unreconciled: entries.iter().filter(|entry| entry.is_unreconciled()).count() as u64,
+        entries,
+        next_offset: (checked == requested && checked > 0).then(|| offset + checked),
+        unrefunded_sessions,
+        unrefunded_sessions_error,
+    }
+}
+
+/// Every session the payment canister reports as unrefunded, read page by
+/// page from its `get_unrefunded_sessions`.
+async fn unrefunded_sessions() -> std::result::Result<Vec<UnrefundedSession>, String> {
+    const PAGE_SIZE: u64 = 100;
+
+    let payment_canister = paid_mint::payment_canister().ok_or("Payment canister is not set")?;
+    let mut sessions = Vec::new();
+    loop {
+        let (page,): (Vec<UnrefundedSession>,) = ic_cdk::call(
+            payment_canister,
+            "get_unrefunded_sessions",
+            (sessions.len() as u64, PAGE_SIZE),
+        )
+        .await
+        .map_err(|(code, msg)| format!("get_unrefunded_sessions failed: {:?} - {}", code, msg))?;
+
+        let done = (page.len() as u64) < PAGE_SIZE;
+        sessions.extend(page);
+        if done {
+            return Ok(sessions);
+        }
+    }
+}
+
+async fn check_receipt(recorded: RecordedReceipt) -> ReceiptCheck {
+    let RecordedReceipt { receipt_id, receipt } = recorded;
+    let mut discrepancies = Vec::new();
+
+    if receipt.token_ledger != MAINNET_LEDGER_CANISTER_ID {
+        discrepancies.push(Discrepancy::UnsupportedLedger { ledger: receipt.token_ledger });
+    } else {
+        discrepancies.extend(check_blocks(&receipt).await);
+    }
+
+    ReceiptCheck {
+        receipt_id,
+        timestamp: receipt.timestamp,
+        payer: receipt.payer,
+        total_amount: receipt.total_amount,
+        discrepancies,
+    }
+}
+
+async fn check_blocks(receipt: &PaymentReceipt) -> Vec<Discrepancy> {
+    let treasury = AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT);
+    let mut discrepancies = Vec::new();
+
+    match fetch_block(receipt.token_ledger, receipt.block_height).await {
+        Ok(block) => discrepancies.extend(check_transfer(&block, receipt.block_height, treasury, receipt.total_amount)),
+        Err(error) => discrepancies.push(Discrepancy::BlockUnavailable { block: receipt.block_height, error }),
+    }
+
+    let expected = receipt.expected_distributions();
+    for (i, (split, wallet, amount)) in expected.into_iter().enumerate() {
+        let Some(&block_index) = receipt.distribution_blocks.get(i) else {
+            discrepancies.push(Discrepancy::MissingTransfer { split: split.to_string(), amount });
+            continue;
+        };
+
+        let to = AccountIdentifier::new(&wallet, &DEFAULT_SUBACCOUNT);
+        match fetch_block(receipt.token_ledger, block_index).await {
+            Ok(block) => discrepancies.extend(check_transfer(&block, block_index, to, amount)),
+            Err(error) => discrepancies.push(Discrepancy::BlockUnavailable { block: block_index, error }),
+        }
+    }
+
+    discrepancies
+}
+
+/// Compares the recipient and amount of a transfer or transfer_from block.
+fn check_transfer(block: &Block, index: u64, to: AccountIdentifier, amount: u64) -> Vec<Discrepancy> {
+    let (actual_to, actual_amount) = match &block.transaction.operation {
+        Some(Operation::Transfer { to, amount, .. }) | Some(Operation::TransferFrom { to, amount, .. }) => {
+            (*to, amount.e8s())
+        }
+        _ => return vec![Discrepancy::UnexpectedOperation { block: index }],
+    };
+
+    let mut discrepancies = Vec::new();
+    if actual_to != to {
+        discrepancies.push(Discrepancy::RecipientMismatch {
+            block: index,
+            expected: to.to_hex(),
+            actual: actual_to.to_hex(),
+        });
+    }
+    if actual_amount != amount {
+        discrepancies.push(Discrepancy::AmountMismatch { block: index, expected: amount, actual: actual_amount });
+    }
+    discrepancies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::{Memo, Timestamp, Tokens, Transaction};
+
+    fn block(to: AccountIdentifier, amount: u64) -> Block {
+        Block {
+            parent_hash: None,
+            transaction: Transaction {
+                memo: Memo(0),
+                operation: Some(Operation::Transfer {
+                    from: AccountIdentifier::new(&Principal::anonymous(), &DEFAULT_SUBACCOUNT),
+                    to,
+                    amount: Tokens::from_e8s(amount),
+                    fee: Tokens::from_e8s(10_000),
+                }),
+                created_at_time: Timestamp { timestamp_nanos: 0 },
+            },
+            timestamp: Timestamp { timestamp_nanos: 0 },
+        }
+    }
+
+    fn wallet() -> AccountIdentifier {
+        AccountIdentifier::new(&Principal::management_canister(), &DEFAULT_SUBACCOUNT)
+    }
+
+    #[test]
+    fn test_matching_transfer_has_no_discrepancies() {
+        assert!(check_transfer(&block(wallet(), 500), 7, wallet(), 500).is_empty());
+    }
+
+    #[test]
+    fn test_mismatches_are_flagged() {
+        let other = AccountIdentifier::new(&Principal::anonymous(), &DEFAULT_SUBACCOUNT);
+        let discrepancies = check_transfer(&block(other, 400), 7, wallet(), 500);
+
+        assert_eq!(discrepancies.len(), 2);
+        assert!(matches!(discrepancies[0], Discrepancy::RecipientMismatch { block: 7, .. }));
+        assert_eq!(discrepancies[1], Discrepancy::AmountMismatch { block: 7, expected: 500, actual: 400 });
+    }
+
+    #[test]
+    fn test_unsupported_ledger_is_unreconciled() {
+        let check = |discrepancies| ReceiptCheck {
+            receipt_id: 1,
+            timestamp: 0,
+            payer: Principal::anonymous(),
+            total_amount: 500,
+            discrepancies,
+        };
+
+        assert!(check(vec![Discrepancy::UnsupportedLedger { ledger: Principal::anonymous() }]).is_unreconciled());
+        assert!(!check(vec![Discrepancy::UnexpectedOperation { block: 7 }]).is_unreconciled());
+    }
+}
@@ -0,0 +1,395 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::error::{Error, Result};
+use super::icrc2::{self, Account, BlockIndex, TransferArg, TransferFailure};
+use super::receipts::PullBlock;
+use super::splitting::{apply_bps, BPS_DENOMINATOR};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const REFERRAL_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(6);
+const REFERRAL_ACCOUNTS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const CREDITED_PULLS_MEMORY_ID: MemoryId = MemoryId::new(14);
+
+const MEMO_REFERRAL_CLAIM: &[u8] = &[4];
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReferralConfig {
+    pub enabled: bool,
+    pub share_bps: u16,  // Share of the platform portion paid to the referrer
+}
+
+impl Default for ReferralConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            share_bps: 1_000, // 10% of the platform portion
+        }
+    }
+}
+
+impl ReferralConfig {
+    /// The referrer's cut of `platform_amount`, or zero when referrals are off.
+    pub fn cut(&self, platform_amount: u64) -> u64 {
+        if self.enabled {
+            apply_bps(platform_amount, self.share_bps)
+        } else {
+            0
+        }
+    }
+}
+
+/// What a checkout credited to its referrer, recorded on the receipt.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReferralCredit {
+    pub referrer: Principal,
+    pub amount: u64,
+}
+
+/// Referral earnings of one referrer in one token.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ReferralAccount {
+    pub referrals: u64,
+    pub earned: u64,
+    pub claimed: u64,
+    pub claimable: u64,
+    pub pending_claim: Option<PendingClaim>,  // Claim whose transfer has not been confirmed
+}
+
+/// A claim taken out of `claimable`. Its transfer is rebuilt from this
+/// record, so claiming again resends identical arguments and the ledger
+/// deduplicates it.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingClaim {
+    pub amount: u64,  // Taken from the claimable balance, fee included
+    pub fee: u64,
+    pub created_at_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReferralAccountView {
+    pub referrer: Principal,
+    pub ledger: Principal,
+    pub account: ReferralAccount,
+}
+
+// Key: (referrer, token ledger)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ReferralKey {
+    referrer: Principal,
+    ledger: Principal,
+}
+
+impl Storable for ReferralKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let referrer = self.referrer.as_slice();
+        let mut bytes = Vec::with_capacity(1 + referrer.len() + self.ledger.as_slice().len());
+        bytes.push(referrer.len() as u8);
+        bytes.extend_from_slice(referrer);
+        bytes.extend_from_slice(self.ledger.as_slice());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            referrer: Principal::from_slice(&bytes[1..1 + len]),
+            ledger: Principal::from_slice(&bytes[1 + len..]),
+        }
+    }
+}
+
+impl BoundedStorable for ReferralKey {
+    const MAX_SIZE: u32 = 1 + 29 + 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReferralAccount {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode referral account"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode referral account")
+    }
+}
+
+impl BoundedStorable for ReferralAccount {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReferralConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode referral config"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_default()
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<ReferralConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(REFERRAL_CONFIG_MEMORY_ID)),
+            ReferralConfig::default(),
+        ).expect("failed to initialize referral config")
+    );
+
+    static ACCOUNTS: RefCell<StableBTreeMap<ReferralKey, ReferralAccount, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(REFERRAL_ACCOUNTS_MEMORY_ID))
+        )
+    );
+
+    // Pull block -> amount credited for it, so each payment is credited once
+    static CREDITED_PULLS: RefCell<StableBTreeMap<PullBlock, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(CREDITED_PULLS_MEMORY_ID))
+        )
+    );
+}
+
+fn require_controller() -> Result<()> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can manage referrals".to_string()));
+    }
+    Ok(())
+}
+
+pub fn config() -> ReferralConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_config(config: ReferralConfig) -> Result<()> {
+    require_controller()?;
+    if config.share_bps > BPS_DENOMINATOR {
+        return Err(Error::Payment(format!("Referral share cannot exceed {} bps", BPS_DENOMINATOR)));
+    }
+    CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .map(|_| ())
+            .map_err(|e| Error::System(format!("Failed to store referral config: {:?}", e)))
+    })
+}
+
+/// Rejects referrers that would let a buyer pay themselves: the payer, the
+/// revenue wallets, this canister and the anonymous principal.
+pub fn check_referrer(referrer: &Principal, payer: &Principal, wallets: &[Principal]) -> Result<()> {
+    if referrer == payer {
+        return Err(Error::Payment("Buyers cannot refer themselves".to_string()));
+    }
+    if *referrer == Principal::anonymous() || *referrer == ic_cdk::id() || wallets.contains(referrer) {
+        return Err(Error::Payment("Referrer is not eligible for rewards".to_string()));
+    }
+    Ok(())
+}
+
+/// Credits a completed checkout to its referrer. The amount stays in this
+/// canister's account until claimed. A checkout is identified by the block
+/// that charged its payer, and is credited only once.
+pub fn credit(referrer: Principal, ledger: Principal, amount: u64, pull_block: BlockIndex) {
+    let pull = PullBlock { ledger, block: pull_block };
+    if CREDITED_PULLS.with(|pulls| pulls.borrow().contains_key(&pull)) {
+        return;
+    }
+    CREDITED_PULLS.with(|pulls| pulls.borrow_mut().insert(pull, amount));
+
+    update_account(referrer, ledger, |account| {
+        account.referrals += 1;
+        account.earned = account.earned.saturating_add(amount);
+        account.claimable = account.claimable.saturating_add(amount);
+    });
+}
+
+pub fn account(referrer: Principal, ledger: Principal) -> ReferralAccount {
+    ACCOUNTS.with(|accounts| accounts.borrow().get(&ReferralKey { referrer, ledger }).unwrap_or_default())
+}
+
+pub fn accounts_of(referrer: Principal) -> Vec<ReferralAccountView> {
+    all_accounts()
+        .into_iter()
+        .filter(|view| view.referrer == referrer)
+        .collect()
+}
+
+/// Every referrer's earnings, for admins.
+pub fn accounts() -> Result<Vec<ReferralAccountView>> {
+    require_controller()?;
+    Ok(all_accounts())
+}
+
+fn all_accounts() -> Vec<ReferralAccountView> {
+    ACCOUNTS.with(|accounts| {
+        accounts
+            .borrow()
+            .iter()
+            .map(|(key, account)| ReferralAccountView { referrer: key.referrer, ledger: key.ledger, account })
+            .collect()
+    })
+}
+
+/// Pays the caller's claimable balance in `ledger` out to them, minus the
+/// ledger fee. If an earlier claim's outcome is unknown, claiming again
+/// resends that claim instead of starting a new one.
+pub async fn claim(ledger: Principal) -> Result<BlockIndex> {
+    let referrer = ic_cdk::caller();
+    if account(referrer, ledger).pending_claim.is_none() {
+        let fee = icrc2::icrc1_fee(ledger).await.map_err(Error::Payment)?;
+        start_claim(referrer, ledger, fee, ic_cdk::api::time())?;
+    }
+    let pending = account(referrer, ledger)
+        .pending_claim
+        .ok_or_else(|| Error::InvalidState("Referral claim was settled concurrently".to_string()))?;
+
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account::of(referrer),
+        amount: Nat::from(pending.amount - pending.fee),
+        fee: Some(Nat::from(pending.fee)),
+        memo: Some(MEMO_REFERRAL_CLAIM.to_vec()),
+        created_at_time: Some(pending.created_at_time),
+    };
+
+    match icrc2::icrc1_transfer(ledger, arg).await {
+        Ok(block) => {
+            finish_claim(referrer, ledger, &pending, true);
+            Ok(block)
+        }
+        Err(TransferFailure::Rejected(e)) => {
+            finish_claim(referrer, ledger, &pending, false);
+            Err(Error::Payment(format!("Referral claim failed: {}", e)))
+        }
+        Err(e @ TransferFailure::Unknown(_)) => {
+            // The claim stays pending until a retry learns its outcome
+            Err(Error::Payment(format!("Referral claim failed; claim again to resend it: {}", e)))
+        }
+    }
+}
+
+/// Moves the claimable balance into a pending claim, unless a concurrent
+/// call already started one.
+fn start_claim(referrer: Principal, ledger: Principal, fee: u64, now: u64) -> Result<()> {
+    let account = account(referrer, ledger);
+    if account.pending_claim.is_some() {
+        return Ok(());
+    }
+    if account.claimable <= fee {
+        return Err(Error::Payment(format!(
+            "Claimable balance {} does not cover the ledger fee {}",
+            account.claimable, fee
+        )));
+    }
+
+    update_account(referrer, ledger, |account| {
+        account.pending_claim = Some(PendingClaim { amount: account.claimable, fee, created_at_time: now });
+        account.claimable = 0;
+    });
+    Ok(())
+}
+
+/// Settles `pending`: records it as claimed if its transfer went through,
+/// otherwise returns it to the claimable balance. A concurrent retry may
+/// have settled it already, in which case nothing changes.
+fn finish_claim(referrer: Principal, ledger: Principal, pending: &PendingClaim, paid: bool) {
+    update_account(referrer, ledger, |account| {
+        if account.pending_claim.as_ref() != Some(pending) {
+            return;
+        }
+        account.pending_claim = None;
+        if paid {
+            account.claimed = account.claimed.saturating_add(pending.amount);
+        } else {
+            account.claimable = account.claimable.saturating_add(pending.amount);
+        }
+    });
+}
+
+fn update_account(referrer: Principal, ledger: Principal, f: impl FnOnce(&mut ReferralAccount)) {
+    ACCOUNTS.with(|accounts| {
+        let mut accounts = accounts.borrow_mut();
+        let key = ReferralKey { referrer, ledger };
+        let mut account = accounts.get(&key).unwrap_or_default();
+        f(&mut account);
+        accounts.insert(key, account);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cut_is_share_of_platform_portion() {
+        let config = ReferralConfig { enabled: true, share_bps: 1_000 };
+        assert_eq!(config.cut(1_000_000), 100_000);
+        assert_eq!(ReferralConfig { enabled: false, ..config }.cut(1_000_000), 0);
+    }
+
+    #[test]
+    fn test_self_referral_is_rejected() {
+        let payer = Principal::from_slice(&[1; 29]);
+        assert!(check_referrer(&payer, &payer, &[]).is_err());
+    }
+
+    #[test]
+    fn test_referral_key_round_trips() {
+        let key = ReferralKey {
+            referrer: Principal::from_slice(&[1; 29]),
+            ledger: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        };
+        assert_eq!(ReferralKey::from_bytes(key.to_bytes()), key);
+    }
+
+    #[test]
+    fn test_retried_checkout_is_credited_once() {
+        let referrer = Principal::from_slice(&[1; 29]);
+        let ledger = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        credit(referrer, ledger, 500, 42);
+        credit(referrer, ledger, 500, 42);
+        credit(referrer, ledger, 500, 43);
+
+        let account = account(referrer, ledger);
+        assert_eq!(account.referrals, 2);
+        assert_eq!(account.claimable, 1_000);
+    }
+
+    #[test]
+    fn test_pending_claim_is_settled_once() {
+        let referrer = Principal::from_slice(&[2; 29]);
+        let ledger = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        credit(referrer, ledger, 1_000, 44);
+
+        start_claim(referrer, ledger, 10, 7).unwrap();
+        let pending = account(referrer, ledger).pending_claim.unwrap();
+        assert_eq!(pending, PendingClaim { amount: 1_000, fee: 10, created_at_time: 7 });
+
+        // A second claim keeps the pending one, so its retry resends the same transfer
+        credit(referrer, ledger, 500, 45);
+        start_claim(referrer, ledger, 10, 8).unwrap();
+        assert_eq!(account(referrer, ledger).pending_claim, Some(pending.clone()));
+
+        finish_claim(referrer, ledger, &pending, true);
+        finish_claim(referrer, ledger, &pending, true);
+        let account = account(referrer, ledger);
+        assert_eq!((account.claimed, account.claimable, account.pending_claim), (1_000, 500, None));
+    }
+
+    #[test]
+    fn test_account_with_pending_claim_fits_its_bound() {
+        let account = ReferralAccount {
+            referrals: u64::MAX,
+            earned: u64::MAX,
+            claimed: u64::MAX,
+            claimable: u64::MAX,
+            pending_claim: Some(PendingClaim { amount: u64::MAX, fee: u64::MAX, created_at_time: u64::MAX }),
+        };
+        assert!(account.to_bytes().len() <= ReferralAccount::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,316 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_ledger_types::AccountIdentifier;
+
+use super::pricing_config::PaymentSettings;
+use super::{PaymentSession, PaymentStatus, SessionId};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RefundKind {
+    Full,        // Everything the payer sent, minus the ledger fee
+    Overpayment, // Only the amount paid above the session price
+    Stray,       // A deposit that arrived after the session expired
+}
+
+/// A refund the canister is allowed to send.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefundPlan {
+    pub kind: RefundKind,
+    pub to: AccountIdentifier,
+    pub amount: u64,
+}
+
+/// Decides whether a session can be fully refunded right now.
+///
+/// Refunds always go to the account that made the verified payment, never to
+/// an address derived from the session owner.
+pub fn plan_refund(
+    session: &PaymentSession,
+    settings: &PaymentSettings,
+    now: u64,
+    ledger_fee: u64,
+) -> Result<RefundPlan, String> {
+    let to = session.payer_account.ok_or(
+        "No verified payment recorded for this session; verify the funding block first",
+    )?;
+    if session.token_id.is_some() {
+        return Err("An Anima was already minted for this session".to_string());
+    }
+    let mut paid = session.paid_amount.unwrap_or(0);
+    if session.overpayment_refund_created_at.is_some() {
+        // The excess has already been (or is being) returned separately
+        paid = paid.min(session.amount);
+    }
+
+    let window_start = match session.status {
+        // A refund retry re-sends the identical transfer
+        PaymentStatus::Refunding => None,
+        PaymentStatus::Funded => session.funded_at,
+        PaymentStatus::Expired if settings.refund_on_expiry => Some(session.expires_at),
+        PaymentStatus::Failed if settings.refund_on_mint_failure => Some(session.last_updated()),
+        ref status => return Err(format!("Session in state {:?} is not refundable", status)),
+    };
+
+    if let Some(start) = window_start {
+        if now > start.saturating_add(settings.refund_window) {
+            return Err("Refund window has closed".to_string());
+        }
+    }
+
+    let amount = paid
+        .checked_sub(ledger_fee)
+        .filter(|amount| *amount > 0)
+        .ok_or("Payment does not cover the ledger fee")?;
+
+    Ok(RefundPlan { kind: RefundKind::Full, to, amount })
+}
+
+/// Plans the refund of whatever was paid above the session price. The
+/// session itself continues to mint with the exact price.
+pub fn plan_overpayment_refund(session: &PaymentSession, ledger_fee: u64) -> Result<RefundPlan, String> {
+    let to = session.payer_account.ok_or("No verified payment recorded for this session")?;
+
+    if !session.status.is_paid() {
+        return Err(format!(
+            "Session in state {:?} has no payment to partially refund",
+            session.status
+        ));
+    }
+
+    let excess = session.paid_amount.unwrap_or(0).saturating_sub(session.amount);
+    let amount = excess
+        .checked_sub(ledger_fee)
+        .filter(|amount| *amount > 0)
+        .ok_or("Overpayment does not cover the ledger fee")?;
+
+    Ok(RefundPlan { kind: RefundKind::Overpayment, to, amount })
+}
+
+/// Plans the return of a deposit found on an expired session's subaccount.
+/// The deposit was never verified as payment, so `sender` comes from the
+/// ledger block the caller points at, and the whole current `balance` of
+/// the subaccount goes back, minus the ledger fee.
+pub fn plan_stray_refund(
+    session: &PaymentSession,
+    sender: AccountIdentifier,
+    balance: u64,
+    ledger_fee: u64,
+) -> Result<RefundPlan, String> {
+    if session.status != PaymentStatus::Expired {
+        return Err(format!("Session in state {:?} has no stray deposit", session.status));
+    }
+
+    let amount = balance
+        .checked_sub(ledger_fee)
+        .filter(|amount| *amount > 0)
+        .ok_or("Deposit does not cover the ledger fee")?;
+
+    Ok(RefundPlan { kind: RefundKind::Stray, to: sender, amount })
+}
+
+/// A transfer out of a session's deposit subaccount whose `created_at_time`
+/// is pinned on the session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DepositTransfer {
+    Sweep,             // Payment to the treasury after the mint
+    Refund,            // Full or stray refund
+    OverpaymentRefund,
+}
+
+impl DepositTransfer {
+    pub fn created_at(self, session: &mut PaymentSession) -> &mut Option<u64> {
+        match self {
+            DepositTransfer::Sweep => &mut session.sweep_created_at,
+            DepositTransfer::Refund => &mut session.refund_created_at,
+            DepositTransfer::OverpaymentRefund => &mut session.overpayment_refund_created_at,
+        }
+    }
+}
+
+/// What the deposit subaccount holds if `transfer` never landed: the
+/// payment, less the other transfers the session records as sent. A lower
+/// balance means `transfer` may have gone through.
+pub fn balance_before(session: &PaymentSession, transfer: DepositTransfer) -> u64 {
+    let paid = session.paid_amount.unwrap_or(session.amount);
+    let swept = match transfer {
+        DepositTransfer::Sweep => 0,
+        _ if session.status == PaymentStatus::Settled => session.amount,
+        _ => 0,
+    };
+    let overpayment_refunded = match transfer {
+        DepositTransfer::OverpaymentRefund => 0,
+        _ if session.overpayment_refund_block.is_some() => paid.saturating_sub(session.amount),
+        _ => 0,
+    };
+    paid.saturating_sub(swept).saturating_sub(overpayment_refunded)
+}
+
+/// A session that still holds funds which were neither used for a mint nor
+/// returned to the payer.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct UnrefundedSession {
+    pub session_id: SessionId,
+    pub owner: Principal,
+    pub status: PaymentStatus,
+    pub held_amount: u64,
+    pub since: u64,
+    pub reason: String,
+}
+
+/// Flags `session` if it is holding funds it should have refunded.
+/// `queued_balance` is the stray deposit the expiry sweep found, if any.
+pub fn unrefunded(session: &PaymentSession, queued_balance: Option<u64>) -> Option<UnrefundedSession> {
+    let (held_amount, since, reason) = match session.status {
+        PaymentStatus::Expired => (queued_balance?, session.expires_at, "Expired with an unrefunded deposit"),
+        PaymentStatus::Failed => (
+            session.paid_amount?,
+            session.last_updated(),
+            "Mint failed and the payment was not refunded",
+        ),
+        PaymentStatus::Refunding => (
+            session.paid_amount?,
+            session.last_updated(),
+            "Refund transfer was never confirmed",
+        ),
+        _ => return None,
+    };
+
+    Some(UnrefundedSession {
+        session_id: session.session_id.clone(),
+        owner: session.owner,
+        status: session.status.clone(),
+        held_amount,
+        since,
+        reason: reason.to_string(),
+    })
+}
+
+/// True once a balance check made at least `grace` after expiry found the
+/// deposit subaccount empty. Deposits still in flight at expiry have landed
+/// by then, so the session can be removed without stranding funds.
+pub fn confirmed_empty(session: &PaymentSession, grace: u64) -> bool {
+    let settled_by = session.expires_at.saturating_add(grace);
+    session.deposit_balance == Some(0)
+        && session.deposit_checked_at.is_some_and(|checked_at| checked_at >= settled_by)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::{Subaccount, DEFAULT_SUBACCOUNT};
+
+    const FEE: u64 = 10_000;
+    const HOUR: u64 = 3_600_000_000_000;
+
+    fn payer() -> AccountIdentifier {
+        AccountIdentifier::new(&Principal::anonymous(), &DEFAULT_SUBACCOUNT)
+    }
+
+    fn session(status: PaymentStatus, paid: u64) -> PaymentSession {
+        PaymentSession {
+            session_id: "session_1_1".to_string(),
+            payment_address: String::new(),
+            amount: 100_000_000,
+            owner: Principal::anonymous(),
+            expires_at: HOUR,
+            status,
+            token_id: None,
+            deposit_subaccount: Subaccount([1; 32]),
+            memo: 1,
+            block_index: Some(5),
+            sweep_created_at: None,
+            refund_created_at: None,
+            settlement_block: None,
+            payer_account: Some(payer()),
+            paid_amount: Some(paid),
+            funded_at: Some(HOUR / 2),
+            updated_at: Some(HOUR),
+            overpayment_refund_created_at: None,
+            overpayment_refund_block: None,
+            anima_name: Some("Nova".to_string()),
+            mint_attempts: Some(0),
+            last_mint_error: None,
+            quote: None,
+            deposit_balance: None,
+            deposit_checked_at: None,
+            reserved_tier: None,
+        }
+    }
+
+    #[test]
+    fn test_full_refund_goes_to_payer_minus_fee() {
+        let settings = PaymentSettings::default();
+        let plan = plan_refund(&session(PaymentStatus::Failed, 100_000_000), &settings, HOUR + 1, FEE).unwrap();
+        assert_eq!(plan.to, payer());
+        assert_eq!(plan.amount, 100_000_000 - FEE);
+        assert_eq!(plan.kind, RefundKind::Full);
+    }
+
+    #[test]
+    fn test_refund_window_is_enforced() {
+        let settings = PaymentSettings::default();
+        let late = HOUR + settings.refund_window + 1;
+        assert!(plan_refund(&session(PaymentStatus::Expired, 100_000_000), &settings, late, FEE).is_err());
+    }
+
+    #[test]
+    fn test_settled_sessions_are_not_refundable() {
+        let settings = PaymentSettings::default();
+        assert!(plan_refund(&session(PaymentStatus::Settled, 100_000_000), &settings, HOUR, FEE).is_err());
+    }
+
+    #[test]
+    fn test_overpayment_refund_returns_only_the_excess() {
+        let plan = plan_overpayment_refund(&session(PaymentStatus::Funded, 150_000_000), FEE).unwrap();
+        assert_eq!(plan.amount, 50_000_000 - FEE);
+        assert!(plan_overpayment_refund(&session(PaymentStatus::Funded, 100_005_000), FEE).is_err());
+    }
+
+    #[test]
+    fn test_unrefunded_sessions_are_flagged() {
+        let expired = session(PaymentStatus::Expired, 0);
+        assert!(unrefunded(&expired, None).is_none());
+        assert_eq!(unrefunded(&expired, Some(5_000_000)).unwrap().held_amount, 5_000_000);
+
+        assert!(unrefunded(&session(PaymentStatus::Failed, 100_000_000), None).is_some());
+        assert!(unrefunded(&session(PaymentStatus::Refunding, 100_000_000), None).is_some());
+        assert!(unrefunded(&session(PaymentStatus::Settled, 100_000_000), None).is_none());
+    }
+
+    #[test]
+    fn test_stray_refund_returns_the_balance_to_the_sender() {
+        let sender = AccountIdentifier::new(&Principal::management_canister(), &DEFAULT_SUBACCOUNT);
+        let expired = session(PaymentStatus::Expired, 0);
+
+        let plan = plan_stray_refund(&expired, sender, 5_000_000, FEE).unwrap();
+        assert_eq!(plan, RefundPlan { kind: RefundKind::Stray, to: sender, amount: 5_000_000 - FEE });
+        assert!(plan_stray_refund(&expired, sender, FEE, FEE).is_err());
+        assert!(plan_stray_refund(&session(PaymentStatus::Funded, 0), sender, 5_000_000, FEE).is_err());
+    }
+
+    #[test]
+    fn test_balance_before_excludes_other_sent_transfers() {
+        let mut settled = session(PaymentStatus::Settled, 150_000_000);
+        assert_eq!(balance_before(&settled, DepositTransfer::OverpaymentRefund), 50_000_000);
+
+        settled.overpayment_refund_block = Some(9);
+        settled.status = PaymentStatus::Minting;
+        assert_eq!(balance_before(&settled, DepositTransfer::Sweep), 100_000_000);
+        assert_eq!(balance_before(&settled, DepositTransfer::OverpaymentRefund), 150_000_000);
+    }
+
+    #[test]
+    fn test_only_empty_deposits_checked_after_grace_are_confirmed() {
+        let mut expired = session(PaymentStatus::Expired, 0);
+        assert!(!confirmed_empty(&expired, HOUR));
+
+        expired.deposit_balance = Some(0);
+        expired.deposit_checked_at = Some(HOUR + HOUR / 2);
+        assert!(!confirmed_empty(&expired, HOUR));
+
+        expired.deposit_checked_at = Some(2 * HOUR);
+        assert!(confirmed_empty(&expired, HOUR));
+
+        expired.deposit_balance = Some(FEE);
+        assert!(!confirmed_empty(&expired, HOUR));
+    }
+}
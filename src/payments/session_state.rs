@@ -0,0 +1,134 @@
+use super::{quotes, session_store, PaymentSession, PaymentStatus};
+
+/// Outcome of a guarded status change.
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub session: PaymentSession,
+    // False when the session was already in the target state
+    pub applied: bool,
+}
+
+impl PaymentStatus {
+    /// Allowed lifecycle edges:
+    ///
+    /// Created -> Funded -> Minting -> Settled
+    /// Created -> Expired -> Funded (payment made in time, verified late)
+    /// Minting -> Failed -> Minting (retry)
+    /// Funded | Expired | Failed -> Refunding -> Refunded
+    pub fn can_transition_to(&self, next: &PaymentStatus) -> bool {
+        use PaymentStatus::*;
+        matches!(
+            (self, next),
+            (Created, Funded)
+                | (Created, Expired)
+                | (Expired, Funded)
+                | (Funded, Minting)
+                | (Minting, Settled)
+                | (Minting, Failed)
+                | (Failed, Minting)
+                | (Funded, Refunding)
+                | (Expired, Refunding)
+                | (Failed, Refunding)
+                | (Refunding, Refunded)
+        )
+    }
+
+    /// True while the session holds a unit of its tier's supply.
+    pub fn holds_supply(&self) -> bool {
+        matches!(
+            self,
+            PaymentStatus::Created | PaymentStatus::Funded | PaymentStatus::Minting | PaymentStatus::Failed
+        )
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, PaymentStatus::Settled | PaymentStatus::Refunded)
+    }
+
+    /// True once the ledger payment has been verified for the session.
+    pub fn is_paid(&self) -> bool {
+        matches!(
+            self,
+            PaymentStatus::Funded | PaymentStatus::Minting | PaymentStatus::Settled
+        )
+    }
+}
+
+/// Moves a session to `next`, applying `f` in the same synchronous step.
+///
+/// Calling this again once the session is already in `next` is a no-op that
+/// returns the stored session with `applied == false`, so retried or
+/// concurrent callers can detect that someone else did the work.
+pub fn transition(
+    session_id: &str,
+    next: PaymentStatus,
+    f: impl FnOnce(&mut PaymentSession),
+) -> Result<Transition, String> {
+    let mut session = session_store::get(session_id).ok_or("Session not found")?;
+
+    if session.status == next {
+        return Ok(Transition { session, applied: false });
+    }
+
+    if !session.status.can_transition_to(&next) {
+        return Err(format!(
+            "Invalid session transition: {:?} -> {:?}",
+            session.status, next
+        ));
+    }
+
+    session.status = next;
+    session.updated_at = Some(ic_cdk::api::time());
+    f(&mut session);
+    sync_reservation(&mut session);
+    session_store::insert(session.clone());
+
+    Ok(Transition { session, applied: true })
+}
+
+/// Releases the session's tier reservation when it leaves the states that
+/// hold supply, and takes one again for a session paid in time but verified
+/// after it expired.
+fn sync_reservation(session: &mut PaymentSession) {
+    if !session.status.holds_supply() {
+        if let Some(tier) = session.reserved_tier.take() {
+            quotes::release(&tier);
+        }
+    } else if session.reserved_tier.is_none() {
+        if let Some(tier) = session.quote.as_ref().map(|quote| quote.tier.clone()) {
+            quotes::reserve_paid(&tier);
+            session.reserved_tier = Some(tier);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PaymentStatus::*;
+
+    #[test]
+    fn test_happy_path_is_allowed() {
+        assert!(Created.can_transition_to(&Funded));
+        assert!(Funded.can_transition_to(&Minting));
+        assert!(Minting.can_transition_to(&Settled));
+        assert!(Funded.can_transition_to(&Refunding));
+        assert!(Refunding.can_transition_to(&Refunded));
+    }
+
+    #[test]
+    fn test_sweep_and_refund_are_mutually_exclusive() {
+        assert!(!Minting.can_transition_to(&Refunding));
+        assert!(!Settled.can_transition_to(&Refunding));
+        assert!(!Refunding.can_transition_to(&Minting));
+        assert!(!Refunded.can_transition_to(&Minting));
+    }
+
+    #[test]
+    fn test_terminal_states_have_no_exits() {
+        let all = [Created, Funded, Minting, Settled, Expired, Failed, Refunding, Refunded];
+        for terminal in all.iter().filter(|s| s.is_terminal()) {
+            assert!(all.iter().all(|next| !terminal.can_transition_to(next)));
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_ledger_types::Subaccount;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use super::mint_handoff::DeadLetter;
+use super::pricing_config::{PaymentSettings, PricingTiers, ServiceFees, TierSupply};
+use super::{PaymentSession, PaymentStatus, SessionId};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const SESSIONS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const OWNER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const STATUS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const UPGRADE_STATE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const USED_BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const REFUND_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const ADMINS_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const PENDING_ADMINS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const DEAD_LETTERS_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const USED_QUOTES_MEMORY_ID: MemoryId = MemoryId::new(9);
+
+const MAX_SESSION_ID_SIZE: u32 = 64;
+const MAX_PRINCIPAL_SIZE: u32 = 29;
+const MAX_SESSION_SIZE: u32 = 4096;
+
+// Key wrapper for session ids
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionKey(pub SessionId);
+
+impl Storable for SessionKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(String::from_utf8(bytes.into_owned()).expect("session id is valid utf-8"))
+    }
+}
+
+impl BoundedStorable for SessionKey {
+    const MAX_SIZE: u32 = MAX_SESSION_ID_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Secondary index key: (owner, session id)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct OwnerIndexKey {
+    owner: Principal,
+    session_id: SessionId,
+}
+
+impl Storable for OwnerIndexKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let owner = self.owner.as_slice();
+        let mut bytes = Vec::with_capacity(1 + owner.len() + self.session_id.len());
+        bytes.push(owner.len() as u8);
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(self.session_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let owner_len = bytes[0] as usize;
+        Self {
+            owner: Principal::from_slice(&bytes[1..1 + owner_len]),
+            session_id: String::from_utf8(bytes[1 + owner_len..].to_vec())
+                .expect("session id is valid utf-8"),
+        }
+    }
+}
+
+impl BoundedStorable for OwnerIndexKey {
+    const MAX_SIZE: u32 = 1 + MAX_PRINCIPAL_SIZE + MAX_SESSION_ID_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Secondary index key: (status tag, session id)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct StatusIndexKey {
+    status: u8,
+    session_id: SessionId,
+}
+
+impl Storable for StatusIndexKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(1 + self.session_id.len());
+        bytes.push(self.status);
+        bytes.extend_from_slice(self.session_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            status: bytes[0],
+            session_id: String::from_utf8(bytes[1..].to_vec()).expect("session id is valid utf-8"),
+        }
+    }
+}
+
+impl BoundedStorable for StatusIndexKey {
+    const MAX_SIZE: u32 = 1 + MAX_SESSION_ID_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Sessions as first stored, before they were verified against ledger
+/// blocks. Only ever read, and upgraded to `PaymentSession` on decode.
+#[derive(CandidType, Deserialize)]
+struct SessionV1 {
+    session_id: SessionId,
+    payment_address: String,
+    amount: u64,
+    owner: Principal,
+    expires_at: u64,
+    status: StatusV1,
+    token_id: Option<String>,
+    deposit_subaccount: Subaccount,
+}
+
+#[derive(CandidType, Deserialize)]
+enum StatusV1 {
+    Pending,
+    Confirmed,
+    Expired,
+    Failed,
+}
+
+impl From<SessionV1> for PaymentSession {
+    fn from(session: SessionV1) -> Self {
+        Self {
+            session_id: session.session_id,
+            payment_address: session.payment_address,
+            amount: session.amount,
+            owner: session.owner,
+            expires_at: session.expires_at,
+            status: match session.status {
+                StatusV1::Pending => PaymentStatus::Created,
+                StatusV1::Confirmed => PaymentStatus::Funded,
+                StatusV1::Expired => PaymentStatus::Expired,
+                StatusV1::Failed => PaymentStatus::Failed,
+            },
+            token_id: session.token_id,
+            deposit_subaccount: session.deposit_subaccount,
+            memo: 0, // V1 payments were not matched by memo
+            block_index: None,
+            sweep_created_at: None,
+            refund_created_at: None,
+            settlement_block: None,
+            payer_account: None,
+            paid_amount: None,
+            funded_at: None,
+            updated_at: Some(session.expires_at),
+            overpayment_refund_created_at: None,
+            overpayment_refund_block: None,
+            anima_name: None,
+            mint_attempts: None,
+            last_mint_error: None,
+            quote: None,
+            deposit_balance: None,
+            deposit_checked_at: None,
+            reserved_tier: None,
+        }
+    }
+}
+
+impl Storable for PaymentSession {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode payment session"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), SessionV1).map(Self::from))
+            .expect("failed to decode payment session")
+    }
+}
+
+impl BoundedStorable for PaymentSession {
+    const MAX_SIZE: u32 = MAX_SESSION_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Heap state that has no stable home of its own and is carried across upgrades.
+#[derive(candid::CandidType, candid::Deserialize, Clone, Debug, Default)]
+pub struct UpgradeState {
+    pub session_counter: u64,
+    pub payment_settings: Option<PaymentSettings>,
+    pub anima_canister: Option<Principal>,
+    pub pricing_tiers: Option<PricingTiers>,
+    pub service_fees: Option<ServiceFees>,
+    pub tier_minted: Option<TierSupply>,
+    pub tier_reserved: Option<TierSupply>,
+    pub quote_signing_key: Option<Vec<u8>>,
+}
+
+impl Storable for DeadLetter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode dead letter"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode dead letter")
+    }
+}
+
+impl BoundedStorable for DeadLetter {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UpgradeState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode upgrade state"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode upgrade state")
+    }
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static SESSIONS: RefCell<StableBTreeMap<SessionKey, PaymentSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SESSIONS_MEMORY_ID))
+        )
+    );
+
+    static OWNER_INDEX: RefCell<StableBTreeMap<OwnerIndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(OWNER_INDEX_MEMORY_ID))
+        )
+    );
+
+    static STATUS_INDEX: RefCell<StableBTreeMap<StatusIndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(STATUS_INDEX_MEMORY_ID))
+        )
+    );
+
+    static UPGRADE_STATE: RefCell<StableCell<UpgradeState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADE_STATE_MEMORY_ID)),
+            UpgradeState::default(),
+        ).expect("failed to initialize upgrade state")
+    );
+
+    // Ledger block index -> session that consumed it
+    static USED_BLOCKS: RefCell<StableBTreeMap<u64, SessionKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(USED_BLOCKS_MEMORY_ID))
+        )
+    );
+
+    // Session -> stray balance found on its deposit subaccount
+    static REFUND_QUEUE: RefCell<StableBTreeMap<SessionKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(REFUND_QUEUE_MEMORY_ID))
+        )
+    );
+
+    // Paid sessions whose mint exhausted its retries
+    static DEAD_LETTERS: RefCell<StableBTreeMap<SessionKey, DeadLetter, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(DEAD_LETTERS_MEMORY_ID))
+        )
+    );
+}
+
+/// Hands out a virtual memory to other stable structures of this canister.
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Stable tag used by the status index. Tags must never be renumbered.
+fn status_tag(status: &PaymentStatus) -> u8 {
+    match status {
+        PaymentStatus::Created => 0,
+        PaymentStatus::Funded => 1,
+        PaymentStatus::Expired => 2,
+        PaymentStatus::Failed => 3,
+        PaymentStatus::Minting => 4,
+        PaymentStatus::Settled => 5,
+        PaymentStatus::Refunding => 6,
+        PaymentStatus::Refunded => 7,
+    }
+}
+
+pub fn get(session_id: &str) -> Option<PaymentSession> {
+    SESSIONS.with(|sessions| sessions.borrow().get(&SessionKey(session_id.to_string())))
+}
+
+/// Inserts or replaces a session, keeping both secondary indexes in sync.
+pub fn insert(session: PaymentSession) {
+    let key = SessionKey(session.session_id.clone());
+    let previous = SESSIONS.with(|sessions| sessions.borrow_mut().insert(key, session.clone()));
+
+    if let Some(previous) = previous {
+        remove_from_indexes(&previous);
+    }
+
+    OWNER_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            OwnerIndexKey { owner: session.owner, session_id: session.session_id.clone() },
+            (),
+        )
+    });
+    STATUS_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            StatusIndexKey { status: status_tag(&session.status), session_id: session.session_id },
+            (),
+        )
+    });
+}
+
+/// Applies `f` to a stored session and writes the result back.
+pub fn update<R>(session_id: &str, f: impl FnOnce(&mut PaymentSession) -> R) -> Option<R> {
+    let mut session = get(session_id)?;
+    let result = f(&mut session);
+    insert(session);
+    Some(result)
+}
+
+pub fn remove(session_id: &str) -> Option<PaymentSession> {
+    let removed = SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&SessionKey(session_id.to_string()))
+    })?;
+    remove_from_indexes(&removed);
+    Some(removed)
+}
+
+fn remove_from_indexes(session: &PaymentSession) {
+    OWNER_INDEX.with(|index| {
+        index.borrow_mut().remove(&OwnerIndexKey {
+            owner: session.owner,
+            session_id: session.session_id.clone(),
+        })
+    });
+    STATUS_INDEX.with(|index| {
+        index.borrow_mut().remove(&StatusIndexKey {
+            status: status_tag(&session.status),
+            session_id: session.session_id.clone(),
+        })
+    });
+}
+
+pub fn by_owner(owner: Principal) -> Vec<PaymentSession> {
+    let start = OwnerIndexKey { owner, session_id: String::new() };
+    let ids: Vec<SessionId> = OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(key, _)| key.session_id)
+            .collect()
+    });
+    ids.iter().filter_map(|id| get(id)).collect()
+}
+
+pub fn by_status(status: &PaymentStatus) -> Vec<PaymentSession> {
+    let tag = status_tag(status);
+    let start = StatusIndexKey { status: tag, session_id: String::new() };
+    let ids: Vec<SessionId> = STATUS_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.status == tag)
+            .map(|(key, _)| key.session_id)
+            .collect()
+    });
+    ids.iter().filter_map(|id| get(id)).collect()
+}
+
+pub fn all() -> Vec<PaymentSession> {
+    SESSIONS.with(|sessions| sessions.borrow().iter().map(|(_, session)| session).collect())
+}
+
+/// Returns the session that already consumed `block_index`, if any.
+pub fn block_owner(block_index: u64) -> Option<SessionId> {
+    USED_BLOCKS.with(|blocks| blocks.borrow().get(&block_index).map(|key| key.0))
+}
+
+/// Marks `block_index` as consumed by `session_id`. Fails if another
+/// session already claimed it.
+pub fn claim_block(block_index: u64, session_id: &str) -> Result<(), String> {
+    USED_BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        match blocks.get(&block_index) {
+            Some(owner) if owner.0 != session_id => Err(format!(
+                "Block {} was already used for session {}",
+                block_index, owner.0
+            )),
+            _ => {
+                blocks.insert(block_index, SessionKey(session_id.to_string()));
+                Ok(())
+            }
+        }
+    })
+}
+
+pub fn queue_refund(session_id: &str, balance: u64) {
+    REFUND_QUEUE.with(|queue| queue.borrow_mut().insert(SessionKey(session_id.to_string()), balance));
+}
+
+pub fn dequeue_refund(session_id: &str) -> Option<u64> {
+    REFUND_QUEUE.with(|queue| queue.borrow_mut().remove(&SessionKey(session_id.to_string())))
+}
+
+pub fn is_refund_queued(session_id: &str) -> bool {
+    REFUND_QUEUE.with(|queue| queue.borrow().contains_key(&SessionKey(session_id.to_string())))
+}
+
+pub fn queued_refund(session_id: &str) -> Option<u64> {
+    REFUND_QUEUE.with(|queue| queue.borrow().get(&SessionKey(session_id.to_string())))
+}
+
+pub fn refund_queue() -> Vec<(SessionId, u64)> {
+    REFUND_QUEUE.with(|queue| queue.borrow().iter().map(|(key, balance)| (key.0, balance)).collect())
+}
+
+pub fn add_dead_letter(letter: DeadLetter) {
+    DEAD_LETTERS.with(|letters| {
+        letters.borrow_mut().insert(SessionKey(letter.session_id.clone()), letter)
+    });
+}
+
+pub fn remove_dead_letter(session_id: &str) -> Option<DeadLetter> {
+    DEAD_LETTERS.with(|letters| letters.borrow_mut().remove(&SessionKey(session_id.to_string())))
+}
+
+pub fn dead_letters() -> Vec<DeadLetter> {
+    DEAD_LETTERS.with(|letters| letters.borrow().iter().map(|(_, letter)| letter).collect())
+}
+
+pub fn save_upgrade_state(state: UpgradeState) {
+    UPGRADE_STATE.with(|cell| {
+        cell.borrow_mut().set(state).expect("failed to save upgrade state");
+    });
+}
+
+pub fn load_upgrade_state() -> UpgradeState {
+    UPGRADE_STATE.with(|cell| cell.borrow().get().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_session_is_upgraded_on_decode() {
+        let stored = SessionV1 {
+            session_id: "session_1_1".to_string(),
+            payment_address: "address".to_string(),
+            amount: 100_000_000,
+            owner: Principal::anonymous(),
+            expires_at: 3_600,
+            status: StatusV1::Pending,
+            token_id: None,
+            deposit_subaccount: Subaccount([7; 32]),
+        };
+        let bytes = Encode!(&stored).unwrap();
+
+        let session = PaymentSession::from_bytes(Cow::Owned(bytes));
+        assert_eq!(session.session_id, "session_1_1");
+        assert_eq!(session.status, PaymentStatus::Created);
+        assert_eq!(session.deposit_subaccount, Subaccount([7; 32]));
+        assert_eq!(session.mint_attempts(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+use candid::{CandidType, Deserialize};
+use std::fmt;
+
+use super::pricing_config::PricingConfig;
+
+/// 100% expressed in basis points.
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Number of outgoing transfers a mint payment is distributed with
+/// (R&D, platform, compute). Each one costs a ledger fee.
+pub const DISTRIBUTION_TRANSFERS: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct RevenueSplit {
+    pub rd_amount: u64,
+    pub platform_amount: u64,
+    pub compute_amount: u64,
+    pub ledger_fees: u64, // Fees consumed by the distribution transfers
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SplitError {
+    InvalidBasisPoints { bps: u16 },
+    InsufficientTotal { total: u64, required: u64 },
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitError::InvalidBasisPoints { bps } => {
+                write!(f, "Basis points {} exceed {}", bps, BPS_DENOMINATOR)
+            }
+            SplitError::InsufficientTotal { total, required } => {
+                write!(f, "Total {} cannot cover required {} for fees and fixed splits", total, required)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SplitError {}
+
+/// `amount * bps / 10_000`, rounded down, without overflow.
+pub fn apply_bps(amount: u64, bps: u16) -> u64 {
+    (amount as u128 * bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+/// Splits a mint payment into R&D, platform and compute portions.
+///
+/// Ledger fees for the distribution transfers come off the top. R&D takes
+/// `rd_bps` of what is left, compute takes its fixed amount, and platform
+/// receives the remainder, so every rounding remainder lands with platform
+/// and the parts always add up exactly to `total`.
+pub fn split_revenue(
+    total: u64,
+    rd_bps: u16,
+    compute_fixed: u64,
+    ledger_fee: u64,
+) -> Result<RevenueSplit, SplitError> {
+    if rd_bps > BPS_DENOMINATOR {
+        return Err(SplitError::InvalidBasisPoints { bps: rd_bps });
+    }
+
+    let ledger_fees = ledger_fee.saturating_mul(DISTRIBUTION_TRANSFERS);
+    let required = ledger_fees.saturating_add(compute_fixed);
+    let distributable = total
+        .checked_sub(ledger_fees)
+        .ok_or(SplitError::InsufficientTotal { total, required })?;
+
+    let rd_amount = apply_bps(distributable, rd_bps);
+    let platform_amount = distributable
+        .checked_sub(rd_amount)
+        .and_then(|rest| rest.checked_sub(compute_fixed))
+        .ok_or(SplitError::InsufficientTotal {
+            total,
+            required: required.saturating_add(rd_amount),
+        })?;
+
+    Ok(RevenueSplit {
+        rd_amount,
+        platform_amount,
+        compute_amount: compute_fixed,
+        ledger_fees,
+    })
+}
+
+/// Rejects pricing configurations whose cheapest possible mint could not
+/// cover the fixed compute split plus the distribution fees.
+///
+/// Every mint is charged at least the fixed service fees and at least the
+/// minimum payment. Platform's share only grows with the total, so if the
+/// smallest of those splits, every larger total does too.
+pub fn validate_config(config: &PricingConfig, ledger_fee: u64) -> Result<(), SplitError> {
+    let fees = &config.fees;
+    for bps in [
+        fees.rd_fee_bps,
+        config.royalties.creator_royalty_bps,
+        config.royalties.platform_fee_bps,
+        config.royalties.quantum_research_fund_bps,
+    ] {
+        if bps > BPS_DENOMINATOR {
+            return Err(SplitError::InvalidBasisPoints { bps });
+        }
+    }
+
+    let compute_fixed = fees.quantum_compute_fee.saturating_add(fees.consciousness_init_fee);
+    let fixed_fees = compute_fixed
+        .saturating_add(fees.maintenance_fee)
+        .saturating_add(fees.evolution_potential_fee);
+    let smallest_total = fixed_fees.max(config.payment_settings.minimum_payment);
+    split_revenue(smallest_total, fees.rd_fee_bps, compute_fixed, ledger_fee)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::pricing_config::{PaymentSettings, PricingTiers, RoyaltyConfig, ServiceFees};
+
+    #[test]
+    fn test_split_parts_sum_to_total() {
+        for total in [1_000_000_000u64, 1_000_000_007, 123_456_789_013] {
+            let split = split_revenue(total, 500, 150_000_000, 10_000).unwrap();
+            assert_eq!(
+                split.rd_amount + split.platform_amount + split.compute_amount + split.ledger_fees,
+                total
+            );
+        }
+    }
+
+    #[test]
+    fn test_rounding_remainder_goes_to_platform() {
+        // 999 * 3333 / 10000 = 332.9667 -> R&D gets 332, platform the rest
+        let split = split_revenue(999, 3_333, 0, 0).unwrap();
+        assert_eq!(split.rd_amount, 332);
+        assert_eq!(split.platform_amount, 667);
+    }
+
+    #[test]
+    fn test_fixed_fees_above_total_are_rejected() {
+        assert!(matches!(
+            split_revenue(100_000_000, 500, 150_000_000, 10_000),
+            Err(SplitError::InsufficientTotal { .. })
+        ));
+        assert!(matches!(
+            split_revenue(20_000, 0, 0, 10_000),
+            Err(SplitError::InsufficientTotal { .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_basis_points_are_rejected() {
+        assert_eq!(
+            split_revenue(1_000, 10_001, 0, 0),
+            Err(SplitError::InvalidBasisPoints { bps: 10_001 })
+        );
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let mut config = PricingConfig {
+            tiers: PricingTiers::default(),
+            fees: ServiceFees::default(),
+            royalties: RoyaltyConfig::default(),
+            payment_settings: PaymentSettings::default(),
+        };
+        assert_eq!(validate_config(&config, 10_000), Ok(()));
+
+        config.fees.maintenance_fee = 0;
+        config.fees.evolution_potential_fee = 0;
+        config.fees.consciousness_init_fee = 200_000_000;
+        assert!(matches!(
+            validate_config(&config, 10_000),
+            Err(SplitError::InsufficientTotal { .. })
+        ));
+
+        config.fees = ServiceFees { rd_fee_bps: 10_001, ..ServiceFees::default() };
+        assert_eq!(
+            validate_config(&config, 10_000),
+            Err(SplitError::InvalidBasisPoints { bps: 10_001 })
+        );
+    }
+
+    #[test]
+    fn test_large_amounts_do_not_overflow() {
+        assert_eq!(apply_bps(u64::MAX, BPS_DENOMINATOR), u64::MAX);
+    }
+}
@@ -1,9 +1,120 @@
-use candid::Principal;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_cdk::api::management_canister::provisional::CanisterId;
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use crate::error::Error;
 use crate::quantum::QuantumState;
 use crate::types::Result;
-use super::pricing_config::{PricingConfig, TokenType, calculate_total_cost};
-use super::ledger::{TransferArgs, Memo, BlockIndex, Tokens};
+use super::checkouts::{self, Checkout, CheckoutKey};
+use super::icrc2::{self, Account, BlockIndex, TransferArg, TransferFailure, TransferFromArgs};
+use super::mint_phases::{self, Admission, AppliedDiscount};
+use super::price_feed;
+use super::receipts;
+use super::referrals::{self, ReferralCredit};
+use super::costs::calculate_total_cost;
+use super::pricing_config::{AcceptedToken, PricingConfig, TokenType};
+use super::splitting::{split_revenue, validate_config, RevenueSplit};
+
+const MEMO_CHECKOUT: &[u8] = &[0]; // Pull from the payer
+const MEMO_RD: u8 = 1;             // R&D fee memo
+const MEMO_PLATFORM: u8 = 2;       // Platform fee memo
+const MEMO_COMPUTE: u8 = 3;        // Compute cost memo
+
+/// Optional inputs a buyer can supply at checkout.
+#[derive(Debug, Clone, Default, CandidType, Deserialize)]
+pub struct CheckoutOptions {
+    pub merkle_proof: Option<Vec<Vec<u8>>>,  // For phases with a Merkle-root allowlist
+    pub discount_code: Option<String>,
+    pub referrer: Option<Principal>,  // Earns a share of the platform portion
+}
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const CHECKOUT_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(16);
+
+/// How `checkout` prices mints and where it sends the splits.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct CheckoutConfig {
+    pub pricing_config: PricingConfig,
+    pub ledger_canister: CanisterId,  // ICP ledger
+    pub research_wallet: Principal,
+    pub platform_wallet: Principal,
+    pub base_price: u64,  // ICP e8s, unless the active phase overrides it
+}
+
+/// The stored checkout config; checkout is refused until one is set.
+struct StoredCheckoutConfig(Option<CheckoutConfig>);
+
+impl Storable for StoredCheckoutConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).expect("failed to encode checkout config"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Decode!(bytes.as_ref(), Option<CheckoutConfig>).expect("failed to decode checkout config"))
+    }
+}
+
+thread_local! {
+    static CHECKOUT_CONFIG: RefCell<StableCell<StoredCheckoutConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            crate::MEMORY_MANAGER.with(|m| m.borrow().get(CHECKOUT_CONFIG_MEMORY_ID)),
+            StoredCheckoutConfig(None),
+        ).expect("failed to initialize checkout config")
+    );
+}
+
+pub fn checkout_config() -> Option<CheckoutConfig> {
+    CHECKOUT_CONFIG.with(|config| config.borrow().get().0.clone())
+}
+
+pub fn set_checkout_config(config: CheckoutConfig) -> std::result::Result<(), Error> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(Error::NotAuthorized("Only controllers can configure checkout".to_string()));
+    }
+    TransactionProcessor::new(
+        config.pricing_config.clone(),
+        config.ledger_canister,
+        config.research_wallet,
+        config.platform_wallet,
+    )
+    .map_err(|e| Error::Payment(e.to_string()))?;
+
+    CHECKOUT_CONFIG.with(|cell| cell.borrow_mut().set(StoredCheckoutConfig(Some(config))))
+        .map_err(|e| Error::System(format!("Failed to store checkout config: {:?}", e)))?;
+    Ok(())
+}
+
+/// Checks out a mint for the caller under the configured pricing. See
+/// `TransactionProcessor::process_mint_payment` for the payment flow and
+/// how retries with the same `created_at_time` resume it.
+pub async fn checkout(token_ledger: CanisterId, created_at_time: u64, options: CheckoutOptions) -> Result<PaymentReceipt> {
+    let payer = ic_cdk::caller();
+    if payer == Principal::anonymous() {
+        return Err(anyhow::anyhow!("Anonymous principals cannot check out"));
+    }
+    let config = checkout_config().ok_or_else(|| anyhow::anyhow!("Checkout is not configured"))?;
+    let processor = TransactionProcessor::new(
+        config.pricing_config,
+        config.ledger_canister,
+        config.research_wallet,
+        config.platform_wallet,
+    )?;
+
+    // The Anima is created after payment, so it is priced at the base state
+    processor
+        .process_mint_payment(
+            Account::of(payer),
+            config.base_price,
+            &QuantumState::default(),
+            token_ledger,
+            created_at_time,
+            options,
+        )
+        .await
+}
 
 pub struct TransactionProcessor {
     pricing_config: PricingConfig,
@@ -13,253 +124,304 @@ pub struct TransactionProcessor {
 }
 
 impl TransactionProcessor {
+    /// Fails if the cheapest mint under `pricing_config` could not be split
+    /// after the ICP ledger's transfer fees.
     pub fn new(
         pricing_config: PricingConfig,
         ledger_canister: CanisterId,
         research_wallet: Principal,
         platform_wallet: Principal,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        let ledger_fee = pricing_config.payment_settings.accepted_tokens
+            .iter()
+            .find(|t| t.token_type == TokenType::ICP)
+            .map_or(0, |t| t.transfer_fee);
+        validate_config(&pricing_config, ledger_fee)
+            .map_err(|e| anyhow::anyhow!("Invalid pricing configuration: {}", e))?;
+
+        Ok(Self {
             pricing_config,
             ledger_canister,
             research_wallet,
             platform_wallet,
-        }
+        })
     }
 
-    /// Process minting payment with R&D fees
+    /// Process minting payment with R&D fees.
+    ///
+    /// Prices are configured in ICP e8s and converted into the accepted
+    /// token identified by `token_ledger` through its price source. The
+    /// payer must first `icrc2_approve` this canister for the converted
+    /// total plus the ledger fee. The total is pulled into this canister's
+    /// account with `icrc2_transfer_from` and then distributed to the R&D,
+    /// platform and compute destinations.
+    ///
+    /// The payer and `created_at_time` identify the checkout. It is stored
+    /// as priced before anything is charged, so retrying with the same
+    /// `created_at_time` re-sends identical ledger calls: a retry after the
+    /// pull only finishes the distribution, and a retry after that returns
+    /// the original receipt.
+    ///
+    /// The active mint phase decides who may buy, how many, and may
+    /// override `base_price`; a discount code from `options` is applied on
+    /// top. Both are recorded on the receipt.
+    ///
+    /// A referrer from `options` is credited a share of the platform
+    /// portion. That share stays in this canister's account until the
+    /// referrer claims it.
     pub async fn process_mint_payment(
         &self,
-        from: Principal,
+        from: Account,
         base_price: u64,
         quantum_state: &QuantumState,
-        token_type: TokenType,
+        token_ledger: CanisterId,
+        created_at_time: u64,
+        options: CheckoutOptions,
     ) -> Result<PaymentReceipt> {
+        let key = CheckoutKey { payer: from.owner, created_at_time };
+        if let Some(checkout) = checkouts::get(&key) {
+            return self.complete(&from, key, checkout).await;
+        }
+
+        if let Some(referrer) = &options.referrer {
+            referrals::check_referrer(referrer, &from.owner, &[self.research_wallet, self.platform_wallet])
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+        }
+
+        let admission = mint_phases::admit(
+            key,
+            ic_cdk::api::time(),
+            options.merkle_proof,
+            options.discount_code,
+        )
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+        // Hand back the phase slot and discount use if pricing fails
+        let checkout = match self.price(base_price, quantum_state, token_ledger, admission, options.referrer).await {
+            Ok(checkout) => checkout,
+            Err(e) => {
+                mint_phases::release(&key);
+                return Err(e);
+            }
+        };
+
+        let checkout = checkouts::get_or_insert(key, checkout);
+        self.complete(&from, key, checkout).await
+    }
+
+    /// Prices a checkout in the payment token and splits it.
+    async fn price(
+        &self,
+        base_price: u64,
+        quantum_state: &QuantumState,
+        token_ledger: CanisterId,
+        admission: Admission,
+        referrer: Option<Principal>,
+    ) -> Result<Checkout> {
         // Calculate total cost including R&D fees
-        let total_cost = calculate_total_cost(
-            base_price,
+        let price_e8s = calculate_total_cost(
+            admission.price_override.unwrap_or(base_price),
             quantum_state.get_complexity()?,
             &self.pricing_config,
+            admission.discount.as_ref().map(|applied| &applied.discount),
         );
 
-        // Verify payment token is accepted
-        self.verify_token_acceptance(token_type, total_cost)?;
+        let token = self.get_token_config(token_ledger)?;
+        self.ledger_for(token)?;
+
+        // Quote the total and the fixed compute portion in the payment token
+        let fees = &self.pricing_config.fees;
+        let compute_e8s = fees.quantum_compute_fee.saturating_add(fees.consciousness_init_fee);
+        let converted = price_feed::price_in_token(token, &[price_e8s, compute_e8s])
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot price payment in token: {}", e))?;
+        let (total_cost, compute_amount) = (converted[0], converted[1]);
+
+        // Verify payment meets the token's minimum
+        self.verify_token_acceptance(token, total_cost)?;
 
         // Split payment into components
-        let (rd_amount, platform_amount, compute_amount) = self.split_payment(total_cost)?;
-
-        // Process main payment
-        let block_height = match token_type {
-            TokenType::ICP => {
-                self.process_icp_transfers(
-                    from,
-                    rd_amount,
-                    platform_amount,
-                    compute_amount,
-                ).await?
-            },
-            TokenType::ICRC1 => {
-                self.process_icrc1_transfers(
-                    from,
-                    rd_amount,
-                    platform_amount,
-                    compute_amount,
-                ).await?
-            },
-            TokenType::ICRC2 => {
-                self.process_icrc2_transfers(
-                    from,
-                    rd_amount,
-                    platform_amount,
-                    compute_amount,
-                ).await?
-            }
-        };
+        let split = self.split_payment(total_cost, compute_amount, token.transfer_fee)?;
+
+        // The referrer's share is carved out of the platform portion and kept here
+        let referral = referrer
+            .map(|referrer| ReferralCredit { referrer, amount: referrals::config().cut(split.platform_amount) })
+            .filter(|credit| credit.amount > 0);
+        let platform_amount = split.platform_amount - referral.as_ref().map_or(0, |credit| credit.amount);
 
-        Ok(PaymentReceipt {
-            payer: from,
+        Ok(Checkout {
+            token_ledger,
             total_amount: total_cost,
-            rd_amount,
+            price_e8s,
+            split,
             platform_amount,
-            compute_amount,
-            token_type,
-            block_height,
-            timestamp: ic_cdk::api::time(),
+            referral,
+            admission,
+            pull_block: None,
+            receipt: None,
         })
     }
 
-    /// Process ICP transfers
-    async fn process_icp_transfers(
-        &self,
-        from: Principal,
-        rd_amount: u64,
-        platform_amount: u64,
-        compute_amount: u64,
-    ) -> Result<BlockIndex> {
-        // Transfer R&D fee
-        let rd_block = self.transfer_icp(
-            from,
-            self.research_wallet,
-            rd_amount,
-            Memo(1), // R&D fee memo
-        ).await?;
+    /// Takes a stored checkout as far as it will go: pulls the payment if
+    /// that hasn't happened, distributes the splits and records the receipt.
+    async fn complete(&self, from: &Account, key: CheckoutKey, checkout: Checkout) -> Result<PaymentReceipt> {
+        if let Some(receipt) = checkout.receipt {
+            return Ok(receipt);
+        }
 
-        // Transfer platform fee
-        let platform_block = self.transfer_icp(
-            from,
-            self.platform_wallet,
-            platform_amount,
-            Memo(2), // Platform fee memo
-        ).await?;
+        let token = self.get_token_config(checkout.token_ledger)?;
+        let ledger = self.ledger_for(token)?;
+
+        let block_height = match checkout.pull_block {
+            Some(block_height) => block_height,
+            None => match self.pull_payment(ledger, from, checkout.total_amount, key.created_at_time).await {
+                Ok(block_height) => {
+                    checkouts::update(&key, |checkout| checkout.pull_block = Some(block_height));
+                    block_height
+                }
+                Err(TransferFailure::Rejected(e)) => {
+                    // The ledger refused the pull, so nothing was charged and
+                    // the checkout can start over
+                    checkouts::remove(&key);
+                    mint_phases::release(&key);
+                    return Err(anyhow::anyhow!("Payment pull failed: {}", e));
+                }
+                Err(e @ TransferFailure::Unknown(_)) => {
+                    // The payer may have been charged. Keep the checkout so a
+                    // retry resends the same pull and the ledger deduplicates it.
+                    return Err(anyhow::anyhow!("Payment pull failed: {}", e));
+                }
+            },
+        };
 
-        // Transfer compute costs
-        let compute_block = self.transfer_icp(
-            from,
-            self.platform_wallet, // Compute costs go to platform
-            compute_amount,
-            Memo(3), // Compute cost memo
+        // Distribute the splits from this canister's own account. A failure
+        // leaves the checkout pulled; retrying resends the same transfers.
+        let distribution_blocks = self.distribute(
+            ledger,
+            &[
+                (self.research_wallet, checkout.split.rd_amount, MEMO_RD),
+                (self.platform_wallet, checkout.platform_amount, MEMO_PLATFORM),
+                (self.platform_wallet, checkout.split.compute_amount, MEMO_COMPUTE), // Compute costs go to platform
+            ],
+            token.transfer_fee,
+            key.created_at_time,
+            block_height,
         ).await?;
 
-        // Return the last block height
-        Ok(compute_block)
-    }
+        // A concurrent retry may have finished the checkout meanwhile
+        if let Some(receipt) = checkouts::get(&key).and_then(|checkout| checkout.receipt) {
+            return Ok(receipt);
+        }
 
-    /// Transfer ICP tokens
-    async fn transfer_icp(
-        &self,
-        from: Principal,
-        to: Principal,
-        amount: u64,
-        memo: Memo,
-    ) -> Result<BlockIndex> {
-        let args = TransferArgs {
-            memo,
-            amount: Tokens::from_e8s(amount),
-            fee: Tokens::from_e8s(10_000), // 0.0001 ICP fee
-            from_subaccount: None,
-            to,
-            created_at_time: None,
+        if let Some(credit) = &checkout.referral {
+            referrals::credit(credit.referrer, checkout.token_ledger, credit.amount, block_height);
+        }
+
+        let receipt = PaymentReceipt {
+            payer: from.owner,
+            total_amount: checkout.total_amount,
+            price_e8s: checkout.price_e8s,
+            rd_amount: checkout.split.rd_amount,
+            platform_amount: checkout.platform_amount,
+            compute_amount: checkout.split.compute_amount,
+            ledger_fees: checkout.split.ledger_fees,
+            token_type: token.token_type.clone(),
+            token_ledger: checkout.token_ledger,
+            block_height,
+            distribution_blocks,
+            research_wallet: self.research_wallet,
+            platform_wallet: self.platform_wallet,
+            phase: checkout.admission.phase.clone(),
+            discount: checkout.admission.discount.clone(),
+            referral: checkout.referral.clone(),
+            timestamp: ic_cdk::api::time(),
         };
+        receipts::record(receipt.clone());
+        checkouts::update(&key, |checkout| checkout.receipt = Some(receipt.clone()));
+        mint_phases::settle(&key);
 
-        let block_height = ic_cdk::call(self.ledger_canister, "transfer", (args,))
-            .await
-            .map_err(|e| anyhow::anyhow!("Transfer failed: {:?}", e))?;
+        Ok(receipt)
+    }
 
-        Ok(block_height)
+    /// Resolve the ledger for a token. ICP is served through the ICP
+    /// ledger's ICRC-2 interface; plain ICRC-1 tokens cannot be pulled.
+    fn ledger_for(&self, token: &AcceptedToken) -> Result<CanisterId> {
+        match token.token_type {
+            TokenType::ICP => Ok(self.ledger_canister),
+            TokenType::ICRC2 => Ok(token.canister_id),
+            TokenType::ICRC1 => Err(anyhow::anyhow!(
+                "ICRC-1 tokens without ICRC-2 support cannot be used for checkout"
+            )),
+        }
     }
 
-    /// Process ICRC1 transfers
-    async fn process_icrc1_transfers(
+    /// Pull the payment from the payer's approved allowance
+    async fn pull_payment(
         &self,
-        from: Principal,
-        rd_amount: u64,
-        platform_amount: u64,
-        compute_amount: u64,
-    ) -> Result<BlockIndex> {
-        // Implement ICRC1 token transfers
-        let token_config = self.get_token_config(TokenType::ICRC1)?;
-        
-        let rd_transfer = ic_cdk::call(
-            token_config.canister_id,
-            "icrc1_transfer",
-            (ICRCTransferArgs {
-                from: from,
-                to: self.research_wallet,
-                amount: rd_amount,
-                fee: None,
-                memo: Some(vec![1]), // R&D fee memo
-                created_at_time: None,
-            },),
-        ).await?;
-
-        let platform_transfer = ic_cdk::call(
-            token_config.canister_id,
-            "icrc1_transfer",
-            (ICRCTransferArgs {
-                from: from,
-                to: self.platform_wallet,
-                amount: platform_amount,
-                fee: None,
-                memo: Some(vec![2]), // Platform fee memo
-                created_at_time: None,
-            },),
-        ).await?;
-
-        let compute_transfer = ic_cdk::call(
-            token_config.canister_id,
-            "icrc1_transfer",
-            (ICRCTransferArgs {
-                from: from,
-                to: self.platform_wallet,
-                amount: compute_amount,
-                fee: None,
-                memo: Some(vec![3]), // Compute cost memo
-                created_at_time: None,
-            },),
-        ).await?;
+        ledger: CanisterId,
+        from: &Account,
+        amount: u64,
+        created_at_time: u64,
+    ) -> std::result::Result<BlockIndex, TransferFailure> {
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from: from.clone(),
+            to: Account::of(ic_cdk::id()),
+            amount: Nat::from(amount),
+            fee: None,
+            memo: Some(MEMO_CHECKOUT.to_vec()),
+            created_at_time: Some(created_at_time),
+        };
 
-        Ok(compute_transfer.block_index)
+        icrc2::icrc2_transfer_from(ledger, args).await
     }
 
-    /// Process ICRC2 transfers (supports atomic operations)
-    async fn process_icrc2_transfers(
+    /// Send each split from this canister's account. Each memo is the
+    /// split's tag followed by the pull block, so transfers are distinct
+    /// across checkouts and within one, while a retried checkout resends
+    /// identical transfers that the ledger deduplicates.
+    async fn distribute(
         &self,
-        from: Principal,
-        rd_amount: u64,
-        platform_amount: u64,
-        compute_amount: u64,
-    ) -> Result<BlockIndex> {
-        let token_config = self.get_token_config(TokenType::ICRC2)?;
-        
-        // ICRC2 supports batch operations
-        let batch_transfer = ic_cdk::call(
-            token_config.canister_id,
-            "icrc2_transfer_batch",
-            (vec![
-                ICRCTransferArgs {
-                    from: from,
-                    to: self.research_wallet,
-                    amount: rd_amount,
-                    fee: None,
-                    memo: Some(vec![1]),
-                    created_at_time: None,
-                },
-                ICRCTransferArgs {
-                    from: from,
-                    to: self.platform_wallet,
-                    amount: platform_amount,
-                    fee: None,
-                    memo: Some(vec![2]),
-                    created_at_time: None,
-                },
-                ICRCTransferArgs {
-                    from: from,
-                    to: self.platform_wallet,
-                    amount: compute_amount,
-                    fee: None,
-                    memo: Some(vec![3]),
-                    created_at_time: None,
-                },
-            ],),
-        ).await?;
+        ledger: CanisterId,
+        splits: &[(Principal, u64, u8)],
+        ledger_fee: u64,
+        created_at_time: u64,
+        pull_block: BlockIndex,
+    ) -> Result<Vec<BlockIndex>> {
+        let mut blocks = Vec::with_capacity(splits.len());
+
+        for (to, amount, memo) in splits {
+            if *amount == 0 {
+                continue;
+            }
 
-        Ok(batch_transfer.last_block_index)
-    }
+            let arg = TransferArg {
+                from_subaccount: None,
+                to: Account::of(*to),
+                amount: Nat::from(*amount),
+                fee: Some(Nat::from(ledger_fee)),
+                memo: Some(split_memo(*memo, pull_block)),
+                created_at_time: Some(created_at_time),
+            };
+
+            let block = icrc2::icrc1_transfer(ledger, arg)
+                .await
+                .map_err(|e| anyhow::anyhow!("Distribution transfer failed: {}", e))?;
+            blocks.push(block);
+        }
 
-    /// Split payment into components
-    fn split_payment(&self, total: u64) -> Result<(u64, u64, u64)> {
-        let rd_amount = (total as f64 * self.pricing_config.fees.rd_fee_percentage) as u64;
-        let compute_amount = self.pricing_config.fees.quantum_compute_fee +
-            self.pricing_config.fees.consciousness_init_fee;
-        let platform_amount = total - rd_amount - compute_amount;
+        Ok(blocks)
+    }
 
-        Ok((rd_amount, platform_amount, compute_amount))
+    /// Split payment into components, net of the distribution ledger fees.
+    /// All amounts are in the payment token's units.
+    fn split_payment(&self, total: u64, compute_amount: u64, ledger_fee: u64) -> Result<RevenueSplit> {
+        split_revenue(total, self.pricing_config.fees.rd_fee_bps, compute_amount, ledger_fee)
+            .map_err(|e| anyhow::anyhow!("Cannot split payment: {}", e))
     }
 
     /// Verify token is accepted and meets minimum amount
-    fn verify_token_acceptance(&self, token_type: TokenType, amount: u64) -> Result<()> {
-        let token_config = self.get_token_config(token_type)?;
-
+    fn verify_token_acceptance(&self, token_config: &AcceptedToken, amount: u64) -> Result<()> {
         if amount < token_config.minimum_amount {
             return Err(anyhow::anyhow!(format!(
                 "Amount below minimum required: {} < {}",
@@ -271,35 +433,57 @@ impl TransactionProcessor {
         Ok(())
     }
 
-    /// Get token configuration
-    fn get_token_config(&self, token_type: TokenType) -> Result<&AcceptedToken> {
+    /// Get token configuration by ledger canister
+    fn get_token_config(&self, token_ledger: CanisterId) -> Result<&AcceptedToken> {
         self.pricing_config.payment_settings.accepted_tokens
             .iter()
-            .find(|t| t.token_type == token_type)
+            .find(|t| t.canister_id == token_ledger)
             .ok_or_else(|| anyhow::anyhow!("Token type not accepted"))
     }
 }
 
-#[derive(Debug, Clone)]
+/// Memo of a distribution transfer: the split's tag, then the pull block.
+fn split_memo(tag: u8, pull_block: BlockIndex) -> Vec<u8> {
+    let mut memo = Vec::with_capacity(9);
+    memo.push(tag);
+    memo.extend_from_slice(&pull_block.to_be_bytes());
+    memo
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct PaymentReceipt {
     pub payer: Principal,
-    pub total_amount: u64,
+    pub total_amount: u64,  // In the payment token's units
+    pub price_e8s: u64,     // The same price in ICP e8s, before conversion
     pub rd_amount: u64,
     pub platform_amount: u64,
     pub compute_amount: u64,
+    pub ledger_fees: u64,  // Fees paid by the canister for the distribution transfers
     pub token_type: TokenType,
-    pub block_height: BlockIndex,
+    pub token_ledger: CanisterId,
+    pub block_height: BlockIndex,              // Block of the pull from the payer
+    pub distribution_blocks: Vec<BlockIndex>,  // Blocks of the R&D, platform and compute transfers
+    pub research_wallet: Principal,
+    pub platform_wallet: Principal,
+    pub phase: Option<String>,               // Mint phase the checkout ran in
+    pub discount: Option<AppliedDiscount>,   // Discount code applied to the price
+    pub referral: Option<ReferralCredit>,    // Share of the platform portion kept for a referrer
     pub timestamp: u64,
 }
 
-#[derive(Debug, Clone, candid::CandidType)]
-struct ICRCTransferArgs {
-    from: Principal,
-    to: Principal,
-    amount: u64,
-    fee: Option<u64>,
-    memo: Option<Vec<u8>>,
-    created_at_time: Option<u64>,
+impl PaymentReceipt {
+    /// The distribution transfers this receipt claims, in the order they were
+    /// sent. Zero amounts are never sent, so they are left out.
+    pub fn expected_distributions(&self) -> Vec<(&'static str, Principal, u64)> {
+        [
+            ("rd", self.research_wallet, self.rd_amount),
+            ("platform", self.platform_wallet, self.platform_amount),
+            ("compute", self.platform_wallet, self.compute_amount),
+        ]
+        .into_iter()
+        .filter(|(_, _, amount)| *amount > 0)
+        .collect()
+    }
 }
 
 #[cfg(test)]
@@ -314,7 +498,7 @@ mod tests {
             CanisterId::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
             Principal::anonymous(), // Research wallet
             Principal::anonymous(), // Platform wallet
-        );
+        ).unwrap();
 
         let quantum_state = QuantumState {
             coherence: 0.9,
@@ -323,8 +507,18 @@ mod tests {
             // ... other fields
         };
 
-        let split = processor.split_payment(1_000_000_000).unwrap(); // 10 ICP
-        assert_eq!(split.0 + split.1 + split.2, 1_000_000_000);
+        let split = processor.split_payment(1_000_000_000, 150_000_000, 10_000).unwrap(); // 10 ICP
+        assert_eq!(
+            split.rd_amount + split.platform_amount + split.compute_amount + split.ledger_fees,
+            1_000_000_000
+        );
+    }
+
+    #[test]
+    fn test_split_memos_are_distinct_per_checkout() {
+        assert_ne!(split_memo(MEMO_RD, 7), split_memo(MEMO_RD, 8));
+        assert_ne!(split_memo(MEMO_RD, 7), split_memo(MEMO_PLATFORM, 7));
+        assert!(split_memo(MEMO_COMPUTE, u64::MAX).len() <= 32); // ICRC-1 memo limit
     }
 
     #[test]
@@ -335,18 +529,22 @@ mod tests {
             CanisterId::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
             Principal::anonymous(),
             Principal::anonymous(),
-        );
+        ).unwrap();
+
+        let icp = processor
+            .get_token_config(CanisterId::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap())
+            .unwrap();
 
         // Test minimum amount verification
         let result = processor.verify_token_acceptance(
-            TokenType::ICP,
+            icp,
             50_000_000, // 0.5 ICP - below minimum
         );
         assert!(result.is_err());
 
         // Test accepted token verification
         let result = processor.verify_token_acceptance(
-            TokenType::ICP,
+            icp,
             200_000_000, // 2 ICP - above minimum
         );
         assert!(result.is_ok());
@@ -1,21 +1,53 @@
 use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::CallResult;
 use ic_cdk::api::time;
 use ic_cdk_macros::*;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use ic_ledger_types::{AccountIdentifier, Tokens, MAINNET_LEDGER_CANISTER_ID};
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, Subaccount, Timestamp, Tokens, TransferError, TransferResult,
+    DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+};
+use sha2::{Digest, Sha256};
+
+mod admins;
+mod block_verification;
+mod expiry_sweeper;
+mod ledger_blocks;
+mod mint_handoff;
+mod payment_request;
+mod pricing_config;
+mod quotes;
+mod refunds;
+mod session_state;
+mod session_store;
+mod splitting;
+
+use block_verification::ExpectedTransfer;
+use expiry_sweeper::SweepReport;
+use mint_handoff::DeadLetter;
+use payment_request::PaymentRequest;
+use pricing_config::{MintTier, PaymentSettings, PricingConfig, PricingTiers, RoyaltyConfig, ServiceFees, TierSupply};
+use quotes::{PricingQuote, TierPricing};
+use refunds::{DepositTransfer, RefundKind, RefundPlan, UnrefundedSession};
 
 type SessionId = String;
 type PaymentAddress = String;
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum PaymentStatus {
-    Pending,
-    Confirmed,
-    Expired,
-    Failed,
+    Created,    // Waiting for the ledger payment
+    Funded,     // Payment verified against a ledger block
+    Minting,    // Sweep to treasury in flight
+    Settled,    // Funds swept and token bound to the session
+    Expired,    // Not paid before `expires_at`
+    Failed,     // Minting failed after payment
+    Refunding,  // Refund transfer in flight
+    Refunded,   // Funds returned to the payer
 }
 
+/// Sessions live in stable memory. New fields must be `Option` so sessions
+/// stored by an earlier version still decode; any other change needs a
+/// legacy layout in `session_store`.
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct PaymentSession {
     session_id: SessionId,
@@ -25,172 +57,669 @@ pub struct PaymentSession {
     expires_at: u64,
     status: PaymentStatus,
     token_id: Option<String>,
+    deposit_subaccount: Subaccount, // Subaccount of this canister that receives the payment
+    memo: u64,                      // Memo the payer must attach to the ledger transfer
+    block_index: Option<u64>,       // Ledger block that funded this session
+    sweep_created_at: Option<u64>,  // created_at_time of the treasury sweep, reused on retry
+    refund_created_at: Option<u64>, // created_at_time of the refund, reused on retry
+    settlement_block: Option<u64>,  // Ledger block of the sweep or refund
+    payer_account: Option<AccountIdentifier>, // Sender of the verified payment; refunds go here
+    paid_amount: Option<u64>,       // Amount actually received in the funding block
+    funded_at: Option<u64>,
+    updated_at: Option<u64>,        // Time of the last status change
+    overpayment_refund_created_at: Option<u64>,
+    overpayment_refund_block: Option<u64>,
+    anima_name: Option<String>,     // Name for the Anima minted once paid
+    mint_attempts: Option<u32>,
+    last_mint_error: Option<String>,
+    quote: Option<PricingQuote>,    // Quote the session price was taken from
+    deposit_balance: Option<u64>,   // Deposit subaccount balance at the last expiry sweep check
+    deposit_checked_at: Option<u64>,
+    reserved_tier: Option<MintTier>, // Tier supply held until the session settles, expires or is refunded
+}
+
+impl PaymentSession {
+    /// Time of the last status change. Sessions stored before it was
+    /// recorded fall back to their expiry.
+    fn last_updated(&self) -> u64 {
+        self.updated_at.unwrap_or(self.expires_at)
+    }
+
+    fn mint_attempts(&self) -> u32 {
+        self.mint_attempts.unwrap_or(0)
+    }
 }
 
 thread_local! {
-    static SESSIONS: RefCell<HashMap<SessionId, PaymentSession>> = RefCell::new(HashMap::new());
+    static SESSION_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+    static PAYMENT_SETTINGS: RefCell<PaymentSettings> = RefCell::new(PaymentSettings::default());
     static TREASURY_ACCOUNT: RefCell<AccountIdentifier> = RefCell::new(
-        AccountIdentifier::new(&ic_cdk::api::id(), None)
+        AccountIdentifier::new(&ic_cdk::api::id(), &DEFAULT_SUBACCOUNT)
     );
 }
 
-const PAYMENT_EXPIRY_NANOS: u64 = 3600_000_000_000; // 1 hour
-const MINTING_COST_E8S: u64 = 100_000_000; // 1 ICP
+const PAYMENT_EXPIRY_NANOS: u64 = 3_600_000_000_000; // 1 hour
+const CLEANUP_GRACE_NANOS: u64 = 24 * 3_600_000_000_000; // Deposits in flight at expiry land well before this
+const LEDGER_FEE_E8S: u64 = 10_000; // 0.0001 ICP
+const DEPOSIT_DOMAIN: &[u8] = b"anima-payment-session";
+const MAX_PAGE_SIZE: u64 = 100;
+const MIN_ANIMA_NAME_LEN: usize = 3;  // The anima canister's own name limits
+const MAX_ANIMA_NAME_LEN: usize = 32;
+
+#[init]
+fn init() {
+    // The installing controller becomes the first admin
+    admins::seed(ic_cdk::caller());
+    expiry_sweeper::start_expiry_timer();
+}
+
+/// Prices a mint at the tier currently on sale. The returned quote must be
+/// passed to `create_payment_session` before it expires.
+#[update]
+async fn get_pricing_quote(owner: Principal, complexity: f64) -> Result<PricingQuote, String> {
+    quotes::issue_quote(owner, complexity).await
+}
 
 #[update]
-async fn create_payment_session(owner: Principal) -> Result<PaymentSession, String> {
-    let session_id = ic_cdk::api::call::arg_data::<String>();
-    let payment_address = generate_payment_address(&owner, &session_id);
+async fn create_payment_session(
+    owner: Principal,
+    anima_name: String,
+    quote: PricingQuote,
+) -> Result<PaymentSession, String> {
+    validate_anima_name(&anima_name)?;
+    quotes::verify_quote(&quote, owner, time())?;
+    quotes::reserve(&quote.tier)?;
+    
+    let (session_id, memo) = next_session_id();
+    let deposit_subaccount = derive_deposit_subaccount(&session_id, &owner);
+    let payment_address = generate_payment_address(&deposit_subaccount);
     
     let session = PaymentSession {
         session_id: session_id.clone(),
         payment_address,
-        amount: MINTING_COST_E8S,
+        amount: quote.total(),
         owner,
         expires_at: time() + PAYMENT_EXPIRY_NANOS,
-        status: PaymentStatus::Pending,
+        status: PaymentStatus::Created,
         token_id: None,
+        deposit_subaccount,
+        memo,
+        block_index: None,
+        sweep_created_at: None,
+        refund_created_at: None,
+        settlement_block: None,
+        payer_account: None,
+        paid_amount: None,
+        funded_at: None,
+        updated_at: Some(time()),
+        overpayment_refund_created_at: None,
+        overpayment_refund_block: None,
+        anima_name: Some(anima_name),
+        mint_attempts: Some(0),
+        last_mint_error: None,
+        quote: Some(quote.clone()),
+        deposit_balance: None,
+        deposit_checked_at: None,
+        reserved_tier: Some(quote.tier.clone()),
     };
     
-    SESSIONS.with(|sessions| {
-        sessions.borrow_mut().insert(session_id, session.clone());
-    });
+    quotes::redeem(&quote.quote_id, &session_id);
+    session_store::insert(session.clone());
     
     Ok(session)
 }
 
 #[query]
 fn verify_payment(session_id: String) -> bool {
-    SESSIONS.with(|sessions| {
-        if let Some(session) = sessions.borrow().get(&session_id) {
-            matches!(session.status, PaymentStatus::Confirmed)
-        } else {
-            false
-        }
-    })
+    session_store::get(&session_id)
+        .map(|session| session.status.is_paid())
+        .unwrap_or(false)
 }
 
 #[update]
-async fn check_payment_status(session_id: String) -> Result<PaymentStatus, String> {
-    let session = SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id).cloned()
-    }).ok_or("Session not found")?;
+async fn check_payment_status(session_id: String, block_index: u64) -> Result<PaymentStatus, String> {
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
     
-    if time() > session.expires_at {
-        return Ok(PaymentStatus::Expired);
+    if session.block_index.is_some() {
+        return Ok(session.status);
     }
     
-    // Query ledger for payment
-    let balance = ic_ledger_types::account_balance(
-        MAINNET_LEDGER_CANISTER_ID,
-        &AccountIdentifier::from_hex(&session.payment_address).unwrap()
-    ).await.map_err(|e| format!("Failed to check balance: {:?}", e))?;
+    if let Some(other) = session_store::block_owner(block_index) {
+        return Err(format!("Block {} was already used for session {}", block_index, other));
+    }
     
-    let status = if balance.e8s >= session.amount {
-        PaymentStatus::Confirmed
-    } else {
-        PaymentStatus::Pending
+    let block = ledger_blocks::fetch_block(MAINNET_LEDGER_CANISTER_ID, block_index).await?;
+    let expected = ExpectedTransfer {
+        to: AccountIdentifier::new(&ic_cdk::api::id(), &session.deposit_subaccount),
+        amount: session.amount,
+        memo: session.memo,
+        not_after: session.expires_at,
     };
+    let verified = block_verification::match_transfer(&block, &expected)?;
     
-    // Update session status
-    SESSIONS.with(|sessions| {
-        if let Some(mut session) = sessions.borrow_mut().get_mut(&session_id) {
-            session.status = status.clone();
-        }
-    });
+    // Re-check after the await: another call may have claimed the block or
+    // funded the session in the meantime.
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
+    if session.block_index.is_some() {
+        return Ok(session.status);
+    }
+    session_store::claim_block(block_index, &session_id)?;
+    
+    let now = time();
+    let transition = session_state::transition(&session_id, PaymentStatus::Funded, |session| {
+        session.block_index = Some(block_index);
+        session.payer_account = Some(verified.from);
+        session.paid_amount = Some(verified.amount);
+        session.funded_at = Some(now);
+    })?;
+    
+    if !transition.applied {
+        return Ok(transition.session.status);
+    }
+    
+    // Hand the paid session over to the anima canister. Failures are kept on
+    // the session and retried by the timer, so they are not surfaced here.
+    let status = match mint_handoff::mint_session(&session_id).await {
+        Ok(session) => session.status,
+        Err(_) => session_store::get(&session_id).map(|s| s.status).unwrap_or(PaymentStatus::Failed),
+    };
     
     Ok(status)
 }
 
 #[update]
-async fn complete_minting(session_id: String, token_id: String) -> Result<PaymentSession, String> {
-    let mut session = SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id).cloned()
-    }).ok_or("Session not found")?;
+async fn refund_session(session_id: String) -> Result<PaymentSession, String> {
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
     
-    // Verify payment is confirmed
-    if !matches!(session.status, PaymentStatus::Confirmed) {
-        return Err("Payment not confirmed".to_string());
+    if session.owner != ic_cdk::caller() && !admins::is_admin(&ic_cdk::caller()) {
+        return Err("Only the session owner can request a refund".to_string());
     }
     
-    // Move funds to treasury
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: 0,
-        amount: Tokens::from_e8s(session.amount),
-        fee: Tokens::from_e8s(10_000),
-        from_subaccount: None,
-        to: TREASURY_ACCOUNT.with(|acc| acc.borrow().clone()),
-        created_at_time: None,
-    };
+    if session.status == PaymentStatus::Refunded {
+        return Ok(session);
+    }
     
-    ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, &transfer_args)
-        .await
-        .map_err(|e| format!("Transfer failed: {:?}", e))?;
+    // A mint whose reply was lost leaves the session Failed without a token.
+    // Only the anima canister's own record tells whether it really failed.
+    if session.status == PaymentStatus::Failed && session.token_id.is_none() {
+        if let Some(receipt) = mint_handoff::recorded_mint(&session_id).await? {
+            session_store::update(&session_id, |session| {
+                session.token_id.get_or_insert(receipt.token_id);
+            });
+            return Err("An Anima was already minted for this session; retry the mint to settle it".to_string());
+        }
+    }
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
     
-    // Update session
-    session.token_id = Some(token_id);
-    SESSIONS.with(|sessions| {
-        sessions.borrow_mut().insert(session_id.clone(), session.clone());
-    });
+    let now = time();
+    let plan = PAYMENT_SETTINGS.with(|settings| {
+        refunds::plan_refund(&session, &settings.borrow(), now, LEDGER_FEE_E8S)
+    })?;
     
-    Ok(session)
+    // Funded | Expired | Failed -> Refunding. A session already in Refunding
+    // re-sends the identical transfer, which the ledger deduplicates.
+    let session = session_state::transition(&session_id, PaymentStatus::Refunding, |session| {
+        session.refund_created_at.get_or_insert(now);
+    })?.session;
+    
+    finish_refund(&session, &plan).await
 }
 
+/// Returns a deposit the expiry sweep found on an expired session's
+/// subaccount. Late deposits never pass payment verification, so the sender
+/// is read from `block_index`, which must be a transfer into the deposit
+/// subaccount. The subaccount's whole balance goes back, minus the fee.
+/// Once the session is `Refunding`, retry with `refund_session`.
 #[update]
-async fn refund_session(session_id: String) -> Result<PaymentSession, String> {
-    let session = SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id).cloned()
+async fn refund_stray_deposit(session_id: String, block_index: u64) -> Result<PaymentSession, String> {
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
+    
+    if session.owner != ic_cdk::caller() && !admins::is_admin(&ic_cdk::caller()) {
+        return Err("Only the session owner can request a refund".to_string());
+    }
+    
+    if !session_store::is_refund_queued(&session_id) {
+        return Err("Session has no stray deposit queued for refund".to_string());
+    }
+    
+    let block = ledger_blocks::fetch_block(MAINNET_LEDGER_CANISTER_ID, block_index).await?;
+    let deposit = AccountIdentifier::new(&ic_cdk::api::id(), &session.deposit_subaccount);
+    let sender = block_verification::transfer_sender(&block, deposit)?;
+    let balance = deposit_balance(&session).await?;
+    
+    // Re-plan against the stored session: it may have been verified or
+    // refunded while we awaited the ledger.
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
+    let plan = refunds::plan_stray_refund(&session, sender, balance, LEDGER_FEE_E8S)?;
+    
+    // Record the sender and amount so a retry through `refund_session`
+    // re-sends the identical transfer.
+    let now = time();
+    let session = session_state::transition(&session_id, PaymentStatus::Refunding, |session| {
+        session.payer_account = Some(plan.to);
+        session.paid_amount = Some(balance);
+        session.refund_created_at.get_or_insert(now);
+    })?.session;
+    
+    finish_refund(&session, &plan).await
+}
+
+/// Sends the refund of a session in `Refunding` and marks it refunded.
+async fn finish_refund(session: &PaymentSession, plan: &RefundPlan) -> Result<PaymentSession, String> {
+    let block = send_refund(session, plan).await?;
+    
+    let transition = session_state::transition(&session.session_id, PaymentStatus::Refunded, |session| {
+        session.settlement_block = Some(block);
+    })?;
+    session_store::dequeue_refund(&session.session_id);
+    session_store::remove_dead_letter(&session.session_id);
+    
+    Ok(transition.session)
+}
+
+/// Returns whatever was paid above the session price to the payer.
+#[update]
+async fn refund_overpayment(session_id: String) -> Result<PaymentSession, String> {
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
+    
+    if session.owner != ic_cdk::caller() && !admins::is_admin(&ic_cdk::caller()) {
+        return Err("Only the session owner can request a refund".to_string());
+    }
+    
+    if session.overpayment_refund_block.is_some() {
+        return Ok(session);
+    }
+    
+    let plan = refunds::plan_overpayment_refund(&session, LEDGER_FEE_E8S)?;
+    
+    // Pin the transfer timestamp before the await so concurrent or retried
+    // calls send an identical, ledger-deduplicated transfer.
+    let now = time();
+    let session = session_store::update(&session_id, |session| {
+        session.overpayment_refund_created_at.get_or_insert(now);
+        session.clone()
     }).ok_or("Session not found")?;
     
-    // Transfer back to user's payment address
+    let block = send_refund(&session, &plan).await?;
+    
+    session_store::update(&session_id, |session| {
+        session.overpayment_refund_block = Some(block);
+        session.clone()
+    }).ok_or_else(|| "Session not found".to_string())
+}
+
+/// Sends a planned refund from the session's deposit subaccount. Each kind
+/// of refund reuses its own pinned `created_at_time`, so retries are
+/// deduplicated by the ledger without colliding with each other.
+async fn send_refund(session: &PaymentSession, plan: &RefundPlan) -> Result<u64, String> {
+    let (transfer, created_at) = match plan.kind {
+        RefundKind::Full | RefundKind::Stray => (DepositTransfer::Refund, session.refund_created_at),
+        RefundKind::Overpayment => (DepositTransfer::OverpaymentRefund, session.overpayment_refund_created_at),
+    };
     let transfer_args = ic_ledger_types::TransferArgs {
-        memo: 0,
-        amount: Tokens::from_e8s(session.amount),
-        fee: Tokens::from_e8s(10_000),
-        from_subaccount: None,
-        to: AccountIdentifier::from_hex(&session.payment_address).unwrap(),
-        created_at_time: None,
+        memo: ic_ledger_types::Memo(session.memo),
+        amount: Tokens::from_e8s(plan.amount),
+        fee: Tokens::from_e8s(LEDGER_FEE_E8S),
+        from_subaccount: Some(session.deposit_subaccount),
+        to: plan.to,
+        created_at_time: created_at.map(|timestamp_nanos| Timestamp { timestamp_nanos }),
     };
     
-    ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, &transfer_args)
+    pinned_transfer(&session.session_id, transfer, transfer_args).await
+}
+
+/// Current balance of a session's deposit subaccount.
+async fn deposit_balance(session: &PaymentSession) -> Result<u64, String> {
+    let account = AccountIdentifier::new(&ic_cdk::api::id(), &session.deposit_subaccount);
+    ic_ledger_types::account_balance(MAINNET_LEDGER_CANISTER_ID, AccountBalanceArgs { account })
         .await
-        .map_err(|e| format!("Refund failed: {:?}", e))?;
+        .map(|balance| balance.e8s())
+        .map_err(|(code, msg)| format!("Balance check failed: {:?} - {}", code, msg))
+}
+
+/// Sends a ledger transfer, treating a deduplicated retry as success.
+async fn ledger_transfer(args: ic_ledger_types::TransferArgs) -> Result<u64, String> {
+    transfer_result(ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, args).await)
+}
+
+/// Sends a deposit transfer pinned to the session's `created_at_time`.
+///
+/// Once the pin ages out of the ledger's deduplication window the ledger
+/// answers `TxTooOld` and the earlier attempt can no longer land. If the
+/// deposit still holds what it held before that attempt, the attempt never
+/// went through, so the transfer is re-pinned to now and sent again.
+async fn pinned_transfer(
+    session_id: &str,
+    transfer: DepositTransfer,
+    mut args: ic_ledger_types::TransferArgs,
+) -> Result<u64, String> {
+    let pinned = args.created_at_time.map(|timestamp| timestamp.timestamp_nanos);
+    match ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, args.clone()).await {
+        Ok(Err(TransferError::TxTooOld { .. })) => {}
+        result => return transfer_result(result),
+    }
     
-    // Update session status
-    SESSIONS.with(|sessions| {
-        if let Some(mut session) = sessions.borrow_mut().get_mut(&session_id) {
-            session.status = PaymentStatus::Failed;
+    let session = session_store::get(session_id).ok_or("Session not found")?;
+    let balance = deposit_balance(&session).await?;
+    if balance < refunds::balance_before(&session, transfer) {
+        return Err("An earlier transfer for this session may have landed; reconcile it before retrying".to_string());
+    }
+    
+    // Concurrent retries re-pin once and share the new timestamp
+    let now = time();
+    let created_at = session_store::update(session_id, |session| {
+        let created_at = transfer.created_at(session);
+        if *created_at == pinned {
+            *created_at = Some(now);
         }
-    });
+        *created_at
+    }).ok_or("Session not found")?;
+    args.created_at_time = created_at.map(|timestamp_nanos| Timestamp { timestamp_nanos });
     
-    Ok(session)
+    ledger_transfer(args).await
+}
+
+fn transfer_result(result: CallResult<TransferResult>) -> Result<u64, String> {
+    match result {
+        Ok(Ok(block)) => Ok(block),
+        Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+        Ok(Err(e)) => Err(format!("Transfer failed: {:?}", e)),
+        Err((code, msg)) => Err(format!("Ledger call failed: {:?} - {}", code, msg)),
+    }
 }
 
 #[query]
 fn get_session(session_id: String) -> Option<PaymentSession> {
-    SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id).cloned()
-    })
+    session_store::get(&session_id)
+}
+
+/// Wallet-readable payment request strings for a session's deposit.
+#[query]
+fn get_payment_request(session_id: String) -> Result<PaymentRequest, String> {
+    let session = session_store::get(&session_id).ok_or("Session not found")?;
+    if session.status != PaymentStatus::Created {
+        return Err("Session is no longer awaiting payment".to_string());
+    }
+    Ok(payment_request::for_session(&session, MAINNET_LEDGER_CANISTER_ID, ic_cdk::api::id()))
 }
 
+#[query]
+fn get_sessions_by_owner(owner: Principal) -> Result<Vec<PaymentSession>, String> {
+    if owner != ic_cdk::caller() {
+        is_admin()?;
+    }
+    Ok(session_store::by_owner(owner))
+}
+
+#[query(guard = "is_admin")]
+fn get_all_sessions() -> Vec<PaymentSession> {
+    session_store::all()
+}
+
+#[update(guard = "is_admin")]
+fn update_payment_settings(settings: PaymentSettings) -> Result<(), String> {
+    let pricing = quotes::pricing();
+    validate_split_config(pricing.tiers, pricing.fees, settings.clone())?;
+    PAYMENT_SETTINGS.with(|current| *current.borrow_mut() = settings);
+    Ok(())
+}
+
+#[query(guard = "is_admin")]
+fn get_payment_settings() -> PaymentSettings {
+    PAYMENT_SETTINGS.with(|settings| settings.borrow().clone())
+}
+
+#[query]
+fn get_tier_pricing() -> TierPricing {
+    quotes::pricing()
+}
+
+#[update(guard = "is_admin")]
+fn update_tier_pricing(tiers: PricingTiers, fees: ServiceFees) -> Result<(), String> {
+    let settings = PAYMENT_SETTINGS.with(|settings| settings.borrow().clone());
+    validate_split_config(tiers.clone(), fees.clone(), settings)?;
+    quotes::set_pricing(tiers, fees);
+    Ok(())
+}
+
+/// Rejects fees and settings under which the cheapest mint could not be
+/// split after ledger fees.
+fn validate_split_config(
+    tiers: PricingTiers,
+    fees: ServiceFees,
+    payment_settings: PaymentSettings,
+) -> Result<(), String> {
+    let config = PricingConfig {
+        tiers,
+        fees,
+        royalties: RoyaltyConfig::default(),
+        payment_settings,
+    };
+    splitting::validate_config(&config, LEDGER_FEE_E8S).map_err(|e| e.to_string())
+}
+
+#[update(guard = "is_admin")]
+fn set_tier_supply(minted: TierSupply) {
+    quotes::set_minted(minted);
+}
+
+// Admin management: adding an admin is a two-step propose/accept flow
+#[update(guard = "is_admin")]
+fn propose_admin(candidate: Principal) -> Result<(), String> {
+    admins::propose(candidate)
+}
+
+#[update]
+fn accept_admin() -> Result<(), String> {
+    admins::accept(ic_cdk::caller())
+}
+
+#[update(guard = "is_admin")]
+fn cancel_admin_proposal(candidate: Principal) -> Result<(), String> {
+    admins::cancel(candidate)
+}
+
+#[update(guard = "is_admin")]
+fn remove_admin(admin: Principal) -> Result<(), String> {
+    admins::remove(admin)
+}
+
+#[query(guard = "is_admin")]
+fn get_admins() -> Vec<Principal> {
+    admins::list()
+}
+
+#[query(guard = "is_admin")]
+fn get_pending_admins() -> Vec<(Principal, u64)> {
+    admins::pending()
+}
+
+/// Removes expired sessions whose deposit subaccount the expiry sweep found
+/// empty after the cleanup grace period. Anything unchecked, or holding even
+/// dust, is kept.
 #[update(guard = "is_admin")]
 fn cleanup_expired_sessions() {
-    let current_time = time();
-    SESSIONS.with(|sessions| {
-        sessions.borrow_mut().retain(|_, session| {
-            session.expires_at > current_time || 
-            matches!(session.status, PaymentStatus::Confirmed)
-        });
+    let expired: Vec<SessionId> = session_store::by_status(&PaymentStatus::Expired)
+        .into_iter()
+        .filter(|session| refunds::confirmed_empty(session, CLEANUP_GRACE_NANOS))
+        .filter(|session| !session_store::is_refund_queued(&session.session_id))
+        .map(|session| session.session_id)
+        .collect();
+
+    for session_id in expired {
+        session_store::remove(&session_id);
+    }
+}
+
+#[update(guard = "is_admin")]
+async fn run_expiry_sweep() -> SweepReport {
+    expiry_sweeper::run_sweep().await
+}
+
+#[query]
+fn get_last_sweep_report() -> Option<SweepReport> {
+    expiry_sweeper::last_report()
+}
+
+#[update(guard = "is_admin")]
+async fn retry_mint(session_id: String) -> Result<PaymentSession, String> {
+    mint_handoff::mint_session(&session_id).await
+}
+
+#[query(guard = "is_admin")]
+fn get_dead_letters() -> Vec<DeadLetter> {
+    session_store::dead_letters()
+}
+
+#[update(guard = "is_admin")]
+fn set_anima_canister(canister: Principal) {
+    mint_handoff::set_anima_canister(canister);
+}
+
+#[query(guard = "is_admin")]
+fn get_refund_queue() -> Vec<(SessionId, u64)> {
+    session_store::refund_queue()
+}
+
+/// Sessions still holding funds that were neither minted for nor refunded,
+/// for reconciliation against the payment receipts. The anima canister
+/// reads these into its reconciliation report.
+#[query(guard = "is_admin_or_anima")]
+fn get_unrefunded_sessions(offset: u64, limit: u64) -> Vec<UnrefundedSession> {
+    [PaymentStatus::Expired, PaymentStatus::Failed, PaymentStatus::Refunding]
+        .iter()
+        .flat_map(session_store::by_status)
+        .filter_map(|session| {
+            let queued = session_store::queued_refund(&session.session_id);
+            refunds::unrefunded(&session, queued)
+        })
+        .skip(offset as usize)
+        .take(limit.min(MAX_PAGE_SIZE) as usize)
+        .collect()
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Sessions and their indexes already live in stable memory; only the
+    // heap counter needs to be carried over.
+    let session_counter = SESSION_COUNTER.with(|counter| *counter.borrow());
+    let payment_settings = PAYMENT_SETTINGS.with(|settings| settings.borrow().clone());
+    let pricing = quotes::pricing();
+    session_store::save_upgrade_state(session_store::UpgradeState {
+        session_counter,
+        payment_settings: Some(payment_settings),
+        anima_canister: mint_handoff::anima_canister(),
+        pricing_tiers: Some(pricing.tiers),
+        service_fees: Some(pricing.fees),
+        tier_minted: Some(pricing.minted),
+        tier_reserved: Some(pricing.reserved),
+        quote_signing_key: quotes::signing_key(),
     });
 }
 
-fn is_admin() -> bool {
-    let caller = ic_cdk::caller();
-    // Add your admin principals here
-    true // TODO: Implement proper admin check
+#[post_upgrade]
+fn post_upgrade() {
+    let state = session_store::load_upgrade_state();
+    SESSION_COUNTER.with(|counter| *counter.borrow_mut() = state.session_counter);
+    if let Some(payment_settings) = state.payment_settings {
+        PAYMENT_SETTINGS.with(|settings| *settings.borrow_mut() = payment_settings);
+    }
+    if let Some(anima_canister) = state.anima_canister {
+        mint_handoff::set_anima_canister(anima_canister);
+    }
+    if let (Some(tiers), Some(fees)) = (state.pricing_tiers, state.service_fees) {
+        quotes::set_pricing(tiers, fees);
+    }
+    if let Some(minted) = state.tier_minted {
+        quotes::set_minted(minted);
+    }
+    if let Some(reserved) = state.tier_reserved {
+        quotes::set_reserved(reserved);
+    }
+    if let Some(key) = state.quote_signing_key {
+        quotes::restore_signing_key(key);
+    }
+    expiry_sweeper::start_expiry_timer();
+}
+
+fn is_admin() -> Result<(), String> {
+    admins::require_admin()
 }
 
-fn generate_payment_address(owner: &Principal, session_id: &str) -> PaymentAddress {
-    let subaccount = ic_ledger_types::Subaccount([0; 32]);
-    AccountIdentifier::new(owner, Some(subaccount)).to_hex()
+fn is_admin_or_anima() -> Result<(), String> {
+    if mint_handoff::anima_canister() == Some(ic_cdk::caller()) {
+        return Ok(());
+    }
+    admins::require_admin()
+}
+
+/// Applies the anima canister's name rules up front, so a paid session
+/// cannot carry a name that would fail every mint attempt.
+fn validate_anima_name(name: &str) -> Result<(), String> {
+    if name.len() < MIN_ANIMA_NAME_LEN || name.len() > MAX_ANIMA_NAME_LEN {
+        return Err(format!("Anima name must be {}-{} bytes", MIN_ANIMA_NAME_LEN, MAX_ANIMA_NAME_LEN));
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+        return Err("Anima name can only contain alphanumeric characters, underscores, and hyphens".to_string());
+    }
+    Ok(())
+}
+
+fn next_session_id() -> (SessionId, u64) {
+    let counter = SESSION_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        *counter
+    });
+    (format!("session_{}_{}", time(), counter), counter)
+}
+
+/// Derives the deposit subaccount for a session. Deterministic in
+/// (session id, owner), so the address can be recomputed at any time.
+fn derive_deposit_subaccount(session_id: &str, owner: &Principal) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update([DEPOSIT_DOMAIN.len() as u8]);
+    hasher.update(DEPOSIT_DOMAIN);
+    hasher.update((session_id.len() as u64).to_be_bytes());
+    hasher.update(session_id.as_bytes());
+    hasher.update(owner.as_slice());
+    Subaccount(hasher.finalize().into())
+}
+
+fn generate_payment_address(subaccount: &Subaccount) -> PaymentAddress {
+    AccountIdentifier::new(&ic_cdk::api::id(), subaccount).to_hex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_subaccount_is_deterministic() {
+        let owner = Principal::from_text("2vxsx-fae").unwrap();
+        let first = derive_deposit_subaccount("session_1_1", &owner);
+        let second = derive_deposit_subaccount("session_1_1", &owner);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_deposit_subaccount_is_unique_per_session_and_owner() {
+        let owner = Principal::from_text("2vxsx-fae").unwrap();
+        let other = Principal::management_canister();
+        let base = derive_deposit_subaccount("session_1_1", &owner);
+
+        assert_ne!(base, derive_deposit_subaccount("session_1_2", &owner));
+        assert_ne!(base, derive_deposit_subaccount("session_1_1", &other));
+        assert_ne!(base, DEFAULT_SUBACCOUNT);
+    }
+
+    #[test]
+    fn test_anima_names_follow_the_anima_canister_rules() {
+        assert!(validate_anima_name("Nova").is_ok());
+        assert!(validate_anima_name("nova_2-b").is_ok());
+
+        for name in ["a", "ab", "my anima", "nova!", &"n".repeat(33)] {
+            assert!(validate_anima_name(name).is_err(), "{} was accepted", name);
+        }
+    }
 }
\ No newline at end of file